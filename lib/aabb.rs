@@ -0,0 +1,110 @@
+use std::f32;
+
+use super::{Ray, Transform, Vec3};
+
+/// Axis-aligned bounding box, stored as its minimum and maximum corners
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    /// A box containing nothing, suitable as the identity for `surrounding`
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Vec3::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    /// Smallest box that contains both `self` and `other`
+    pub fn surrounding(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        }
+    }
+
+    /// Smallest box that contains both `self` and the point `p`
+    pub fn grow(&self, p: &Vec3) -> Aabb {
+        Aabb {
+            min: self.min.min(p),
+            max: self.max.max(p),
+        }
+    }
+
+    /// Smallest box that contains `self` after it is moved by `transform`
+    pub fn transform(&self, transform: &Transform) -> Aabb {
+        let mut bounds = Aabb::empty();
+        for corner in 0..8 {
+            let pick = |axis: usize| {
+                if corner & (1 << axis) == 0 {
+                    self.min[axis]
+                } else {
+                    self.max[axis]
+                }
+            };
+            bounds = bounds.grow(&transform.point(&Vec3::new(pick(0), pick(1), pick(2))));
+        }
+        bounds
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (&self.min + &self.max)
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        &self.max - &self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.extent();
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Index of the axis along which the box is longest
+    pub fn longest_axis(&self) -> usize {
+        let d = self.extent();
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let inv_dir = Vec3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z);
+        self.hit_inv(&origin, &inv_dir, t_min, t_max)
+    }
+
+    /// Slab test against a ray given by its origin and reciprocal direction, so
+    /// the reciprocal can be computed once per ray rather than once per box
+    #[inline(always)]
+    pub fn hit_inv(&self, origin: &Vec3, inv_dir: &Vec3, mut t_min: f32, mut t_max: f32) -> bool {
+        for axis in 0..3 {
+            let mut t0 = (self.min[axis] - origin[axis]) * inv_dir[axis];
+            let mut t1 = (self.max[axis] - origin[axis]) * inv_dir[axis];
+            if inv_dir[axis] < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max < t_min {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,312 @@
+use std::f32;
+
+use super::aabb::Aabb;
+use super::{Hit, HitRecord, Ray, Vec3};
+
+/// Number of buckets centroids are binned into when evaluating split candidates
+const SAH_BUCKETS: usize = 12;
+/// Cost of visiting an interior node relative to intersecting one primitive
+const TRAVERSAL_COST: f32 = 0.125;
+/// Nodes holding more primitives than this are always split
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Debug, Clone)]
+enum NodeKind {
+    Leaf {
+        start: usize,
+        count: usize,
+    },
+    /// The first child always directly follows its parent in `nodes`
+    Interior {
+        second_child: usize,
+        axis: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    bounds: Aabb,
+    kind: NodeKind,
+}
+
+struct BuildPrim {
+    index: usize,
+    bounds: Aabb,
+    centroid: Vec3,
+}
+
+/// Bounding volume hierarchy over a list of objects, split using the surface
+/// area heuristic. Objects without a bounding box (such as infinite planes) are
+/// kept outside the tree and tested on every ray.
+pub struct Bvh<T: Hit> {
+    objects: Vec<T>,
+    bounded: usize,
+    nodes: Vec<Node>,
+}
+
+impl<T: Hit> Bvh<T> {
+    pub fn new(objects: Vec<T>) -> Bvh<T> {
+        let mut prims = Vec::with_capacity(objects.len());
+        let mut unbounded = Vec::new();
+        for (index, object) in objects.iter().enumerate() {
+            match object.bounding_box() {
+                Some(bounds) => prims.push(BuildPrim {
+                    index,
+                    centroid: bounds.centroid(),
+                    bounds,
+                }),
+                None => unbounded.push(index),
+            }
+        }
+
+        let mut nodes = Vec::new();
+        if !prims.is_empty() {
+            nodes.reserve(2 * prims.len());
+            build(&mut prims, 0, &mut nodes);
+        }
+
+        let bounded = prims.len();
+        let mut slots: Vec<Option<T>> = objects.into_iter().map(Some).collect();
+        let objects = prims
+            .iter()
+            .map(|prim| prim.index)
+            .chain(unbounded)
+            .map(|index| slots[index].take().unwrap())
+            .collect();
+
+        Bvh {
+            objects,
+            bounded,
+            nodes,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+}
+
+fn build(prims: &mut [BuildPrim], offset: usize, nodes: &mut Vec<Node>) -> usize {
+    let bounds = prims
+        .iter()
+        .fold(Aabb::empty(), |acc, prim| acc.surrounding(&prim.bounds));
+    let node_index = nodes.len();
+    nodes.push(Node {
+        bounds: bounds.clone(),
+        kind: NodeKind::Leaf {
+            start: offset,
+            count: prims.len(),
+        },
+    });
+    if prims.len() == 1 {
+        return node_index;
+    }
+
+    let centroid_bounds = prims
+        .iter()
+        .fold(Aabb::empty(), |acc, prim| acc.grow(&prim.centroid));
+    let axis = centroid_bounds.longest_axis();
+    let lo = centroid_bounds.min[axis];
+    let hi = centroid_bounds.max[axis];
+    if hi <= lo {
+        // Every centroid coincides, so no split can separate them
+        return node_index;
+    }
+
+    let bucket_of = |prim: &BuildPrim| {
+        let b = (SAH_BUCKETS as f32 * (prim.centroid[axis] - lo) / (hi - lo)) as usize;
+        b.min(SAH_BUCKETS - 1)
+    };
+
+    let mut counts = [0usize; SAH_BUCKETS];
+    let mut bucket_bounds: Vec<Aabb> = vec![Aabb::empty(); SAH_BUCKETS];
+    for prim in prims.iter() {
+        let b = bucket_of(prim);
+        counts[b] += 1;
+        bucket_bounds[b] = bucket_bounds[b].surrounding(&prim.bounds);
+    }
+
+    // Sweep from both sides so each split candidate is costed in linear time
+    let mut below_area = [0.0f32; SAH_BUCKETS - 1];
+    let mut below_count = [0usize; SAH_BUCKETS - 1];
+    let mut acc = Aabb::empty();
+    let mut count = 0;
+    for i in 0..SAH_BUCKETS - 1 {
+        acc = acc.surrounding(&bucket_bounds[i]);
+        count += counts[i];
+        below_area[i] = acc.surface_area();
+        below_count[i] = count;
+    }
+    let mut best_split = 0;
+    let mut best_cost = f32::MAX;
+    let mut acc = Aabb::empty();
+    let mut count = 0;
+    for i in (0..SAH_BUCKETS - 1).rev() {
+        acc = acc.surrounding(&bucket_bounds[i + 1]);
+        count += counts[i + 1];
+        if below_count[i] == 0 || count == 0 {
+            continue;
+        }
+        let cost = below_count[i] as f32 * below_area[i] + count as f32 * acc.surface_area();
+        if cost < best_cost {
+            best_cost = cost;
+            best_split = i;
+        }
+    }
+
+    let area = bounds.surface_area();
+    let split_cost = if area > 0.0 {
+        TRAVERSAL_COST + best_cost / area
+    } else {
+        TRAVERSAL_COST + prims.len() as f32
+    };
+    if prims.len() <= MAX_LEAF_SIZE && split_cost >= prims.len() as f32 {
+        return node_index;
+    }
+
+    let mut mid = partition(prims, |prim| bucket_of(prim) <= best_split);
+    if mid == 0 || mid == prims.len() {
+        mid = prims.len() / 2;
+        // Malformed objects can have NaN centroids, which must not panic
+        prims.sort_by(|a, b| a.centroid[axis].total_cmp(&b.centroid[axis]));
+    }
+
+    let (below, above) = prims.split_at_mut(mid);
+    build(below, offset, nodes);
+    let second_child = build(above, offset + mid, nodes);
+    nodes[node_index].kind = NodeKind::Interior { second_child, axis };
+    node_index
+}
+
+/// Moves every element matching `pred` to the front, returning how many matched
+fn partition<F: Fn(&BuildPrim) -> bool>(prims: &mut [BuildPrim], pred: F) -> usize {
+    let mut mid = 0;
+    for i in 0..prims.len() {
+        if pred(&prims[i]) {
+            prims.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+impl<T: Hit> Hit for Bvh<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let mut hit_anything = false;
+        let mut closest = t_max;
+        for object in self.objects[self.bounded..].iter() {
+            if object.hit(ray, t_min, closest, hit_record) {
+                hit_anything = true;
+                closest = hit_record.t;
+            }
+        }
+        if self.nodes.is_empty() {
+            return hit_anything;
+        }
+
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let inv_dir = Vec3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z);
+        let mut stack = Vec::with_capacity(32);
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.bounds.hit_inv(&origin, &inv_dir, t_min, closest) {
+                match node.kind {
+                    NodeKind::Leaf { start, count } => {
+                        for object in self.objects[start..start + count].iter() {
+                            if object.hit(ray, t_min, closest, hit_record) {
+                                hit_anything = true;
+                                closest = hit_record.t;
+                            }
+                        }
+                    }
+                    NodeKind::Interior { second_child, axis } => {
+                        // Visit the nearer child first so the far one can be culled
+                        if inv_dir[axis] < 0.0 {
+                            stack.push(current + 1);
+                            current = second_child;
+                        } else {
+                            stack.push(second_child);
+                            current += 1;
+                        }
+                        continue;
+                    }
+                }
+            }
+            match stack.pop() {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+        hit_anything
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.bounded < self.objects.len() {
+            return None;
+        }
+        self.nodes.first().map(|root| root.bounds.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::materials::Lambertian;
+    use super::super::{HitList, Sphere};
+    use super::*;
+
+    #[test]
+    fn bvh_matches_hit_list() {
+        let mut spheres = Vec::new();
+        let mut list = HitList::new(0);
+        for i in 0..20 {
+            for j in 0..20 {
+                let center =
+                    Vec3::new(i as f32 - 10.0, ((i * j) % 7) as f32 * 0.3, j as f32 - 10.0);
+                let radius = 0.2 + ((i + j) % 3) as f32 * 0.1;
+                let mat = Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+                spheres.push(Sphere::new(center.clone(), radius, mat.clone()));
+                list.list.push(Sphere::new(center, radius, mat));
+            }
+        }
+        let bvh = Bvh::new(spheres);
+        assert_eq!(bvh.len(), 400);
+
+        for k in 0..500 {
+            let angle = k as f32 * 0.0125;
+            let ray = Ray::new(
+                Vec3::new(13.0, 2.0 + (k % 5) as f32, 3.0),
+                Vec3::new(-angle.cos() * 13.0, -2.0, -angle.sin() * 13.0 + 3.0),
+            );
+            let mut expected = HitRecord::new();
+            let mut actual = HitRecord::new();
+            let hit_list = list.hit(&ray, 0.001, f32::MAX, &mut expected);
+            let hit_bvh = bvh.hit(&ray, 0.001, f32::MAX, &mut actual);
+            assert_eq!(hit_list, hit_bvh);
+            if hit_list {
+                assert_eq!(expected.t, actual.t);
+                assert_eq!(expected.p, actual.p);
+            }
+        }
+    }
+
+    #[test]
+    fn infinite_bounds_do_not_panic() {
+        let mat = Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        // Bounds from minus to plus infinity have a NaN centroid, and one
+        // centroid at infinity leaves no split for the others
+        let mut spheres = vec![Sphere::new(Vec3::origin(), f32::INFINITY, mat.clone()); 3];
+        let far = Vec3::new(f32::INFINITY, 0.0, 0.0);
+        spheres.push(Sphere::new(far, 0.5, mat.clone()));
+        for i in 0..8 {
+            spheres.push(Sphere::new(Vec3::new(i as f32, 0.0, 0.0), 0.5, mat.clone()));
+        }
+        let bvh = Bvh::new(spheres);
+        assert_eq!(bvh.len(), 12);
+    }
+}
@@ -1,10 +1,8 @@
-extern crate rand;
 extern crate std;
 
 use std::f32;
-use super::{cross, dot, unit_vector, Ray, Vec3, RAND_END};
-
-use rand::{thread_rng, Rng};
+use super::sampler::Sampler;
+use super::{cross, unit_vector, Ray, Vec3};
 
 #[derive(Debug, Clone)]
 pub struct Camera {
@@ -16,6 +14,9 @@ pub struct Camera {
     v: Vec3,
     w: Vec3,
     lens_radius: f32,
+    /// Times at which the shutter opens and closes
+    time0: f32,
+    time1: f32,
 }
 
 impl Default for Camera {
@@ -29,25 +30,69 @@ impl Default for Camera {
             v: Vec3::origin(),
             w: Vec3::origin(),
             lens_radius: 0.0,
+            time0: 0.0,
+            time1: 0.0,
         }
     }
 }
 
-fn random_unit_in_disk() -> Vec3 {
-    let mut rng = thread_rng();
-    let mut p;
-    loop {
-        p = 2.0
-            * Vec3::new(
-                rng.gen_range::<f32>(0.0, RAND_END),
-                rng.gen_range::<f32>(0.0, RAND_END),
-                0.0,
-            ) - Vec3::new(1.0, 1.0, 0.0);
-        if !(dot(&p, &p) >= 1.0) {
-            break;
+/// Maps a 2D sample to the unit disk with Shirley's concentric mapping, which
+/// keeps stratified samples stratified
+fn concentric_disk(u: (f32, f32)) -> Vec3 {
+    let (x, y) = (2.0 * u.0 - 1.0, 2.0 * u.1 - 1.0);
+    if x == 0.0 && y == 0.0 {
+        return Vec3::origin();
+    }
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, f32::consts::FRAC_PI_4 * (y / x))
+    } else {
+        (y, f32::consts::FRAC_PI_2 - f32::consts::FRAC_PI_4 * (x / y))
+    };
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+/// Placement and lens of a camera, independent of the image it renders to
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraSettings {
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    pub vup: Vec3,
+    /// Vertical field of view in degrees
+    pub vfov: f32,
+    pub aperture: f32,
+    pub focus: f32,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> CameraSettings {
+        CameraSettings {
+            look_from: Vec3::new(0.0, 0.0, 0.0),
+            look_at: Vec3::new(0.0, 0.0, -1.0),
+            vup: Vec3::new(0.0, 1.0, 0.0),
+            vfov: 90.0,
+            aperture: 0.0,
+            focus: 1.0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         }
     }
-    p
+}
+
+impl CameraSettings {
+    pub fn camera(&self, aspect: f32) -> Camera {
+        Camera::new(
+            self.look_from.clone(),
+            self.look_at.clone(),
+            self.vup.clone(),
+            self.vfov,
+            aspect,
+            self.aperture,
+            self.focus,
+        )
+        .with_shutter(self.shutter_open, self.shutter_close)
+    }
 }
 
 impl Camera {
@@ -77,13 +122,23 @@ impl Camera {
         camera
     }
 
-    pub fn ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius * random_unit_in_disk();
+    /// Keeps the shutter open from `open` to `close`, giving every ray a
+    /// uniformly distributed time in between
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Camera {
+        self.time0 = open;
+        self.time1 = close;
+        self
+    }
+
+    pub fn ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = self.lens_radius * concentric_disk(sampler.get_2d());
         let offset = &self.u * rd.x() + &self.v * rd.y();
-        Ray::new(
+        let time = self.time0 + (self.time1 - self.time0) * sampler.get_1d();
+        Ray::with_time(
             &self.origin + &offset,
             &self.lower_left + &(s * &self.horizontal) + (t * &self.vertical) - self.origin.clone()
                 - offset,
+            time,
         )
     }
 }
@@ -0,0 +1,170 @@
+//! Film: accumulates filter-weighted samples into pixels.
+//!
+//! Samples are splatted into every pixel within the filter radius, so a
+//! sample also contributes to the pixels next to the one it was taken for.
+//! Workers splat into `FilmTile`s that cover their tile plus the filter
+//! radius, and the film adds tiles up in a fixed order so the sums, and with
+//! them the image, do not depend on which thread finished first.
+
+use super::filter::Filter;
+use super::render::Framebuffer;
+use super::Vec3;
+
+/// Total filter weight below which a pixel counts as empty. Filters with
+/// negative lobes can leave pixels with weights that nearly cancel, and
+/// dividing by those would blow the noise in their sums up.
+const MIN_WEIGHT: f32 = 1e-3;
+
+/// Weighted sums for a rectangle of pixels, `x0..x1` by `y0..y1`
+#[derive(Debug, Clone)]
+pub struct FilmTile {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+    filter: Filter,
+    sums: Vec<Vec3>,
+    weights: Vec<f32>,
+}
+
+impl FilmTile {
+    /// Adds a sample at continuous image position `(x, y)`, with rows counted
+    /// from the top and pixel centres at half-integer coordinates
+    pub fn add_sample(&mut self, x: f32, y: f32, radiance: &Vec3) {
+        let radius = self.filter.radius();
+        let first = |p: f32, lo: usize| ((p - 0.5 - radius).ceil().max(lo as f32)) as usize;
+        let last =
+            |p: f32, hi: usize| ((p - 0.5 + radius).floor() + 1.0).clamp(0.0, hi as f32) as usize;
+        let width = self.x1 - self.x0;
+        for py in first(y, self.y0)..last(y, self.y1) {
+            for px in first(x, self.x0)..last(x, self.x1) {
+                let weight = self
+                    .filter
+                    .evaluate(x - (px as f32 + 0.5), y - (py as f32 + 0.5));
+                if weight != 0.0 {
+                    let i = (py - self.y0) * width + (px - self.x0);
+                    self.sums[i] += weight * radiance;
+                    self.weights[i] += weight;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub filter: Filter,
+    sums: Vec<Vec3>,
+    weights: Vec<f32>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Film {
+        Film {
+            width,
+            height,
+            filter,
+            sums: vec![Vec3::origin(); width * height],
+            weights: vec![0.0; width * height],
+        }
+    }
+
+    /// An empty tile for samples taken in the pixels `x0..x1` by `y0..y1`,
+    /// grown by the filter radius so it also holds their splats into the
+    /// surrounding pixels
+    pub fn tile(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> FilmTile {
+        let pad = (self.filter.radius() - 0.5).ceil().max(0.0) as usize;
+        let (x0, y0) = (x0.saturating_sub(pad), y0.saturating_sub(pad));
+        let (x1, y1) = ((x1 + pad).min(self.width), (y1 + pad).min(self.height));
+        let size = (x1 - x0) * (y1 - y0);
+        FilmTile {
+            x0,
+            y0,
+            x1,
+            y1,
+            filter: self.filter,
+            sums: vec![Vec3::origin(); size],
+            weights: vec![0.0; size],
+        }
+    }
+
+    pub fn merge(&mut self, tile: &FilmTile) {
+        let width = tile.x1 - tile.x0;
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let i = (y - tile.y0) * width + (x - tile.x0);
+                self.sums[y * self.width + x] += tile.sums[i].clone();
+                self.weights[y * self.width + x] += tile.weights[i];
+            }
+        }
+    }
+
+    /// The weighted average of the samples around each pixel. Pixels with a
+    /// total weight below `MIN_WEIGHT`, including negative ones, are black.
+    pub fn framebuffer(&self) -> Framebuffer {
+        let mut image = Framebuffer::new(self.width, self.height);
+        for (pixel, (sum, &weight)) in image
+            .pixels
+            .iter_mut()
+            .zip(self.sums.iter().zip(self.weights.iter()))
+        {
+            if weight > MIN_WEIGHT {
+                *pixel = sum / weight;
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn box_filter_averages_within_the_pixel() {
+        let mut film = Film::new(3, 2, Filter::default());
+        let mut tile = film.tile(0, 0, 3, 2);
+        tile.add_sample(1.25, 0.5, &Vec3::new(1.0, 0.0, 0.0));
+        tile.add_sample(1.75, 0.25, &Vec3::new(0.0, 1.0, 0.0));
+        tile.add_sample(0.5, 1.5, &Vec3::new(0.0, 0.0, 4.0));
+        film.merge(&tile);
+        let image = film.framebuffer();
+        assert_eq!(*image.get(1, 0), Vec3::new(0.5, 0.5, 0.0));
+        assert_eq!(*image.get(0, 1), Vec3::new(0.0, 0.0, 4.0));
+        assert_eq!(*image.get(2, 1), Vec3::origin());
+    }
+
+    #[test]
+    fn wide_filters_splat_into_neighbouring_tiles() {
+        let mut film = Film::new(4, 1, Filter::Triangle { radius: 1.5 });
+        // A single sample in the centre of pixel 1, rendered by the left tile
+        let mut left = film.tile(0, 0, 2, 1);
+        left.add_sample(1.5, 0.5, &Vec3::new(2.0, 2.0, 2.0));
+        film.merge(&left);
+        film.merge(&film.tile(2, 0, 4, 1));
+        let image = film.framebuffer();
+        for x in 0..3 {
+            assert_eq!(*image.get(x, 0), Vec3::new(2.0, 2.0, 2.0), "pixel {}", x);
+        }
+        // Two pixel centres away is outside the radius
+        assert_eq!(*image.get(3, 0), Vec3::origin());
+        assert_eq!(film.weights[2], 0.5 * 1.5);
+    }
+
+    #[test]
+    fn cancelling_weights_leave_pixels_empty() {
+        let mut film = Film::new(3, 1, Filter::default());
+        film.sums[0] = Vec3::new(0.01, 0.01, 0.01);
+        film.weights[0] = 1e-6;
+        film.sums[1] = Vec3::new(0.5, 0.5, 0.5);
+        film.weights[1] = -0.25;
+        film.sums[2] = Vec3::new(0.5, 0.5, 0.5);
+        film.weights[2] = 0.25;
+        let image = film.framebuffer();
+        assert_eq!(*image.get(0, 0), Vec3::origin());
+        assert_eq!(*image.get(1, 0), Vec3::origin());
+        assert_eq!(*image.get(2, 0), Vec3::new(2.0, 2.0, 2.0));
+    }
+}
@@ -0,0 +1,188 @@
+//! Pixel reconstruction filters, weighting a sample's contribution to every
+//! pixel whose centre lies within the filter radius.
+
+use std::f32;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    /// Equal weight everywhere inside the square of half-width `radius`; a
+    /// radius of 0.5 averages the samples inside each pixel
+    Box { radius: f32 },
+    /// Weight falling off linearly to zero at `radius`
+    Triangle { radius: f32 },
+    /// Gaussian with standard deviation `sigma`, shifted down so it reaches
+    /// zero at `radius`
+    Gaussian { radius: f32, sigma: f32 },
+    /// Mitchell–Netravali cubic with parameters `b` and `c`, stretched over
+    /// `radius`
+    Mitchell { radius: f32, b: f32, c: f32 },
+    /// `sinc(x)` windowed by `sinc(x / radius)`
+    Lanczos { radius: f32 },
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+#[inline(always)]
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        let x = f32::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+fn gaussian(x: f32, sigma: f32) -> f32 {
+    (-x * x / (2.0 * sigma * sigma)).exp()
+}
+
+/// The cubic on `[-2, 2]`
+fn mitchell(x: f32, b: f32, c: f32) -> f32 {
+    let x = x.abs();
+    if x <= 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+            + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x <= 2.0 {
+        ((-b - 6.0 * c) * x * x * x
+            + (6.0 * b + 30.0 * c) * x * x
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+impl Filter {
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Triangle { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius } => radius,
+        }
+    }
+
+    /// Weight of a sample at offset `x` along one axis from a pixel centre
+    fn evaluate_1d(&self, x: f32) -> f32 {
+        let radius = self.radius();
+        if x.abs() >= radius {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Triangle { .. } => radius - x.abs(),
+            Filter::Gaussian { sigma, .. } => {
+                (gaussian(x, sigma) - gaussian(radius, sigma)).max(0.0)
+            }
+            Filter::Mitchell { b, c, .. } => mitchell(2.0 * x / radius, b, c),
+            Filter::Lanczos { .. } => sinc(x) * sinc(x / radius),
+        }
+    }
+
+    /// Weight of a sample at offset `(x, y)` from a pixel centre. Mitchell and
+    /// Lanczos have negative lobes, which sharpen edges.
+    pub fn evaluate(&self, x: f32, y: f32) -> f32 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Filter::Box { .. } => "box",
+            Filter::Triangle { .. } => "triangle",
+            Filter::Gaussian { .. } => "gaussian",
+            Filter::Mitchell { .. } => "mitchell",
+            Filter::Lanczos { .. } => "lanczos",
+        };
+        write!(f, "{}:{}", name, self.radius())
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    /// Parses a filter name, optionally followed by `:radius`, e.g.
+    /// `gaussian:2`. Without a radius each filter gets its usual one.
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let (name, radius) = match s.find(':') {
+            Some(colon) => {
+                let radius = &s[colon + 1..];
+                match radius.parse::<f32>() {
+                    Ok(radius) if radius > 0.0 => (&s[..colon], Some(radius)),
+                    _ => return Err(format!("invalid filter radius '{}'", radius)),
+                }
+            }
+            None => (s, None),
+        };
+        match name {
+            "box" => Ok(Filter::Box {
+                radius: radius.unwrap_or(0.5),
+            }),
+            "triangle" => Ok(Filter::Triangle {
+                radius: radius.unwrap_or(1.0),
+            }),
+            "gaussian" => {
+                let radius = radius.unwrap_or(1.5);
+                Ok(Filter::Gaussian {
+                    radius,
+                    sigma: radius / 3.0,
+                })
+            }
+            "mitchell" => Ok(Filter::Mitchell {
+                radius: radius.unwrap_or(2.0),
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            }),
+            "lanczos" => Ok(Filter::Lanczos {
+                radius: radius.unwrap_or(3.0),
+            }),
+            _ => Err(format!("unknown filter '{}'", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_peak_at_the_centre_and_vanish_at_the_radius() {
+        for name in ["box", "triangle", "gaussian", "mitchell", "lanczos:2"].iter() {
+            let filter = name.parse::<Filter>().unwrap();
+            let radius = filter.radius();
+            let centre = filter.evaluate(0.0, 0.0);
+            assert!(centre > 0.0, "{}", name);
+            assert!(filter.evaluate(0.3 * radius, 0.1) <= centre, "{}", name);
+            assert_eq!(filter.evaluate(radius * 1.01, 0.0), 0.0, "{}", name);
+            assert_eq!(filter.evaluate(0.0, -radius * 1.01), 0.0, "{}", name);
+            assert!(filter.evaluate(radius, 0.0).abs() < 1e-4, "{}", name);
+        }
+        // Mitchell–Netravali has a negative lobe in the outer half
+        let mitchell = "mitchell".parse::<Filter>().unwrap();
+        assert!(mitchell.evaluate(1.5, 0.0) < 0.0);
+    }
+
+    #[test]
+    fn parse_filters() {
+        assert_eq!("box".parse::<Filter>(), Ok(Filter::default()));
+        assert_eq!(
+            "triangle:2".parse::<Filter>(),
+            Ok(Filter::Triangle { radius: 2.0 })
+        );
+        let lanczos = Filter::Lanczos { radius: 2.5 };
+        assert_eq!(lanczos.to_string().parse::<Filter>(), Ok(lanczos));
+        assert!("gaussian:0".parse::<Filter>().is_err());
+        assert!("sinc".parse::<Filter>().is_err());
+    }
+}
@@ -0,0 +1,390 @@
+//! Floating point image formats: Radiance RGBE (`.hdr`) and Portable Float
+//! Map (`.pfm`). Both store linear radiance, so no transfer curve is applied.
+
+use std::io::{self, Read, Write};
+
+use super::image::ImageError;
+use super::render::Framebuffer;
+use super::Vec3;
+
+/// Shared-exponent encoding of a linear colour
+fn to_rgbe(col: &Vec3) -> [u8; 4] {
+    let v = col.r().max(col.g()).max(col.b());
+    // NaN and infinity have no exponent to share, so pixels with either come
+    // out black
+    let finite = col.r().is_finite() && col.g().is_finite() && col.b().is_finite();
+    if !finite || v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // frexp: v = mantissa * 2^exponent with mantissa in [0.5, 1). The
+    // largest exponent RGBE can store is 127, above which the channels
+    // saturate instead.
+    let exponent = (((v.to_bits() >> 23) & 0xff) as i32 - 126).min(127);
+    let scale = 256.0 / 2f32.powi(exponent);
+    let channel = |c: f32| (c.max(0.0) * scale).min(255.0) as u8;
+    [
+        channel(col.r()),
+        channel(col.g()),
+        channel(col.b()),
+        (exponent + 128) as u8,
+    ]
+}
+
+fn from_rgbe(rgbe: &[u8]) -> Vec3 {
+    if rgbe[3] == 0 {
+        return Vec3::origin();
+    }
+    let f = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    Vec3::new(
+        (rgbe[0] as f32 + 0.5) * f,
+        (rgbe[1] as f32 + 0.5) * f,
+        (rgbe[2] as f32 + 0.5) * f,
+    )
+}
+
+/// Run-length encodes one channel of a scanline: runs of four or more equal
+/// bytes become a count above 128 and the byte, everything else is copied
+/// in literal chunks of up to 128 bytes
+fn write_rle_channel(out: &mut Vec<u8>, data: &[u8]) {
+    const MIN_RUN: usize = 4;
+    let mut i = 0;
+    while i < data.len() {
+        // Find the next run long enough to be worth encoding
+        let mut run_start = i;
+        let mut run_length = 0;
+        while run_start < data.len() {
+            run_length = data[run_start..]
+                .iter()
+                .take(127)
+                .take_while(|&&b| b == data[run_start])
+                .count();
+            if run_length >= MIN_RUN {
+                break;
+            }
+            run_start += run_length;
+        }
+        if run_length < MIN_RUN {
+            run_start = data.len();
+        }
+        while i < run_start {
+            let count = (run_start - i).min(128);
+            out.push(count as u8);
+            out.extend_from_slice(&data[i..i + count]);
+            i += count;
+        }
+        if run_start < data.len() {
+            out.push(128 + run_length as u8);
+            out.push(data[run_start]);
+            i = run_start + run_length;
+        }
+    }
+}
+
+/// Writes a Radiance RGBE image with run-length encoded scanlines
+pub fn write_hdr<W: Write>(mut writer: W, image: &Framebuffer) -> io::Result<()> {
+    write!(
+        writer,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        image.height, image.width
+    )?;
+    let width = image.width;
+    let mut channels: Vec<Vec<u8>> = (0..4).map(|_| Vec::with_capacity(width)).collect();
+    let mut line = Vec::new();
+    for row in image.pixels.chunks(width.max(1)) {
+        line.clear();
+        if (8..=0x7fff).contains(&width) {
+            for channel in channels.iter_mut() {
+                channel.clear();
+            }
+            for col in row {
+                for (channel, byte) in channels.iter_mut().zip(to_rgbe(col).iter()) {
+                    channel.push(*byte);
+                }
+            }
+            line.extend_from_slice(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+            for channel in channels.iter() {
+                write_rle_channel(&mut line, channel);
+            }
+        } else {
+            // Scanlines this narrow or wide cannot be run-length encoded
+            for col in row {
+                line.extend_from_slice(&to_rgbe(col));
+            }
+        }
+        writer.write_all(&line)?;
+    }
+    writer.flush()
+}
+
+fn format_error<T, S: Into<String>>(message: S) -> Result<T, ImageError> {
+    Err(ImageError::Format(message.into()))
+}
+
+/// Reads a Radiance RGBE image in the standard `-Y height +X width`
+/// orientation, with flat or run-length encoded scanlines
+pub fn read_hdr<R: Read>(mut reader: R) -> Result<Framebuffer, ImageError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let mut pos = 0;
+    let mut next_line = |data: &[u8]| -> Option<String> {
+        let start = pos;
+        let end = start + data.get(start..)?.iter().position(|&b| b == b'\n')?;
+        pos = end + 1;
+        Some(String::from_utf8_lossy(&data[start..end]).into_owned())
+    };
+    match next_line(&data) {
+        Some(ref magic) if magic.starts_with("#?") => {}
+        _ => return format_error("not a Radiance HDR file"),
+    }
+    loop {
+        match next_line(&data) {
+            None => return format_error("truncated HDR header"),
+            Some(ref line) if line.is_empty() => break,
+            Some(ref line) if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" => {
+                return format_error(format!("unsupported HDR {}", line))
+            }
+            Some(_) => {}
+        }
+    }
+    let resolution = match next_line(&data) {
+        Some(line) => line,
+        None => return format_error("missing HDR resolution"),
+    };
+    let fields: Vec<&str> = resolution.split_whitespace().collect();
+    let (height, width) = match fields[..] {
+        ["-Y", h, "+X", w] => match (h.parse::<usize>(), w.parse::<usize>()) {
+            (Ok(h), Ok(w)) => (h, w),
+            _ => return format_error(format!("invalid HDR resolution '{}'", resolution)),
+        },
+        _ => return format_error(format!("unsupported HDR orientation '{}'", resolution)),
+    };
+    let body = &data[pos..];
+
+    let mut image = Framebuffer::new(width, height);
+    let mut offset = 0;
+    let mut scanline = vec![[0u8; 4]; width];
+    for y in 0..height {
+        let rle = (8..=0x7fff).contains(&width)
+            && body.len() >= offset + 4
+            && body[offset] == 2
+            && body[offset + 1] == 2
+            && body[offset + 2] & 0x80 == 0;
+        if !rle {
+            let end = offset + width * 4;
+            if body.len() < end {
+                return format_error("truncated HDR data");
+            }
+            for (x, rgbe) in body[offset..end].chunks(4).enumerate() {
+                image.set(x, y, from_rgbe(rgbe));
+            }
+            offset = end;
+            continue;
+        }
+
+        if ((body[offset + 2] as usize) << 8 | body[offset + 3] as usize) != width {
+            return format_error("HDR scanline width mismatch");
+        }
+        offset += 4;
+        // Each channel is run-length encoded separately
+        for channel in 0..4 {
+            let mut x = 0;
+            while x < width {
+                let count = match body.get(offset) {
+                    Some(&count) => count as usize,
+                    None => return format_error("truncated HDR data"),
+                };
+                offset += 1;
+                if count > 128 {
+                    let count = count - 128;
+                    let value = match body.get(offset) {
+                        Some(&value) => value,
+                        None => return format_error("truncated HDR data"),
+                    };
+                    if x + count > width {
+                        return format_error("HDR run overflows scanline");
+                    }
+                    for rgbe in scanline[x..x + count].iter_mut() {
+                        rgbe[channel] = value;
+                    }
+                    offset += 1;
+                    x += count;
+                } else {
+                    if count == 0 || x + count > width || body.len() < offset + count {
+                        return format_error("bad HDR literal run");
+                    }
+                    for (rgbe, &value) in scanline[x..x + count]
+                        .iter_mut()
+                        .zip(body[offset..offset + count].iter())
+                    {
+                        rgbe[channel] = value;
+                    }
+                    offset += count;
+                    x += count;
+                }
+            }
+        }
+        for (x, rgbe) in scanline.iter().enumerate() {
+            image.set(x, y, from_rgbe(rgbe));
+        }
+    }
+    Ok(image)
+}
+
+/// Writes a little-endian colour Portable Float Map
+pub fn write_pfm<W: Write>(mut writer: W, image: &Framebuffer) -> io::Result<()> {
+    // A negative scale marks little-endian data
+    write!(writer, "PF\n{} {}\n-1.0\n", image.width, image.height)?;
+    let mut bytes = Vec::with_capacity(image.width * 12);
+    // PFM rows run from the bottom of the image to the top
+    for row in image.pixels.chunks(image.width.max(1)).rev() {
+        bytes.clear();
+        for col in row {
+            for value in [col.r(), col.g(), col.b()].iter() {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        writer.write_all(&bytes)?;
+    }
+    writer.flush()
+}
+
+/// Reads a colour (`PF`) or greyscale (`Pf`) Portable Float Map of either
+/// byte order
+pub fn read_pfm<R: Read>(mut reader: R) -> Result<Framebuffer, ImageError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let mut fields = Vec::with_capacity(4);
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return format_error("truncated PFM header");
+        }
+        fields.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+    }
+    // Exactly one whitespace byte separates the header from the samples
+    pos += 1;
+
+    let channels = match fields[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        magic => return format_error(format!("unsupported PFM type '{}'", magic)),
+    };
+    let (width, height, scale) = match (
+        fields[1].parse::<usize>(),
+        fields[2].parse::<usize>(),
+        fields[3].parse::<f32>(),
+    ) {
+        (Ok(w), Ok(h), Ok(s)) if s != 0.0 => (w, h, s),
+        _ => return format_error("invalid PFM header"),
+    };
+    let little_endian = scale < 0.0;
+    let (row_bytes, image_bytes) = match width
+        .checked_mul(channels * 4)
+        .and_then(|row_bytes| Some((row_bytes, row_bytes.checked_mul(height)?)))
+    {
+        Some(sizes) => sizes,
+        None => return format_error("invalid PFM header"),
+    };
+    let body = data.get(pos..).unwrap_or(&[]);
+    if body.len() < image_bytes {
+        return format_error("truncated PFM data");
+    }
+
+    let mut image = Framebuffer::new(width, height);
+    for (i, row) in body.chunks(row_bytes.max(1)).take(height).enumerate() {
+        let y = height - 1 - i;
+        let values: Vec<f32> = row
+            .chunks(4)
+            .map(|b| {
+                let bytes = [b[0], b[1], b[2], b[3]];
+                if little_endian {
+                    f32::from_le_bytes(bytes)
+                } else {
+                    f32::from_be_bytes(bytes)
+                }
+            })
+            .collect();
+        for (x, pixel) in values.chunks(channels).enumerate() {
+            let col = match *pixel {
+                [r, g, b] => Vec3::new(r, g, b),
+                [v] => Vec3::new(v, v, v),
+                _ => unreachable!(),
+            };
+            image.set(x, y, col);
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient() -> Framebuffer {
+        let mut image = Framebuffer::new(40, 3);
+        for y in 0..3 {
+            for x in 0..40 {
+                // Runs of equal pixels exercise the run-length encoder
+                let level = (x / 7) as f32 * 0.37 + y as f32 * 10.0;
+                image.set(x, y, Vec3::new(level, 0.5 * level, 1e-3));
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn hdr_round_trip() {
+        let image = gradient();
+        let mut data = Vec::new();
+        write_hdr(&mut data, &image).unwrap();
+        let decoded = read_hdr(&data[..]).unwrap();
+        assert_eq!((decoded.width, decoded.height), (40, 3));
+        for (a, b) in image.pixels.iter().zip(decoded.pixels.iter()) {
+            // Every channel shares the largest channel's 8-bit exponent
+            let tolerance = a.r().max(a.g()).max(a.b()) / 128.0;
+            assert!((a - b).length() <= tolerance, "{:?} vs {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn pfm_round_trip() {
+        let image = gradient();
+        let mut data = Vec::new();
+        write_pfm(&mut data, &image).unwrap();
+        assert_eq!(read_pfm(&data[..]).unwrap().pixels, image.pixels);
+
+        // Big-endian greyscale, bottom row first
+        let mut grey = b"Pf\n1 2\n1.0\n".to_vec();
+        grey.extend_from_slice(&2.0f32.to_be_bytes());
+        grey.extend_from_slice(&0.5f32.to_be_bytes());
+        let decoded = read_pfm(&grey[..]).unwrap();
+        assert_eq!(*decoded.get(0, 0), Vec3::new(0.5, 0.5, 0.5));
+        assert_eq!(*decoded.get(0, 1), Vec3::new(2.0, 2.0, 2.0));
+    }
+
+    #[test]
+    fn rgbe_saturates_instead_of_wrapping() {
+        assert_eq!(to_rgbe(&Vec3::new(f32::INFINITY, 1.0, 1.0)), [0, 0, 0, 0]);
+        assert_eq!(to_rgbe(&Vec3::new(f32::NAN, 1.0, 1.0)), [0, 0, 0, 0]);
+        let huge = to_rgbe(&Vec3::new(f32::MAX, 0.0, 0.0));
+        assert_eq!(huge, [255, 0, 0, 255]);
+        assert!(from_rgbe(&huge).r() > 1e38);
+    }
+
+    #[test]
+    fn pfm_rejects_overflowing_sizes() {
+        let header = format!("PF\n{} {}\n-1.0\n", usize::MAX / 4, 2);
+        assert!(read_pfm(header.as_bytes()).is_err());
+        let header = format!("PF\n{} {}\n-1.0\n", 1 << 20, usize::MAX / 8);
+        assert!(read_pfm(header.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::hdr::{read_hdr, read_pfm, write_hdr, write_pfm};
+use super::png::write_png_rgb8;
+use super::render::Framebuffer;
+use super::tonemap::ToneMap;
+use super::Vec3;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    /// The data is not a well-formed image of the expected kind
+    Format(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageError::Io(ref err) => write!(f, "{}", err),
+            ImageError::Format(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ImageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ImageError::Io(ref err) => Some(err),
+            ImageError::Format(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> ImageError {
+        ImageError::Io(err)
+    }
+}
+
+/// The sRGB transfer function, converting a display value in `[0, 1]` to
+/// linear light
+#[inline(always)]
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// The inverse sRGB transfer function, converting linear light in `[0, 1]` to
+/// a display value
+#[inline(always)]
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Quantizes a linear colour to 8-bit sRGB samples, clamping to the
+/// displayable range. Tone map first to keep highlights from clipping.
+#[inline(always)]
+pub fn to_rgb8(col: &Vec3) -> [u8; 3] {
+    let channel = |value: f32| (255.0 * linear_to_srgb(value.clamp(0.0, 1.0)) + 0.5) as u8;
+    [channel(col.r()), channel(col.g()), channel(col.b())]
+}
+
+/// Splits the whitespace separated header fields of a PNM file, skipping
+/// comments, and returns the offset just past the single whitespace byte
+/// that ends the last field
+fn pnm_header(data: &[u8], fields: usize) -> Result<(Vec<String>, usize), ImageError> {
+    let mut tokens = Vec::with_capacity(fields);
+    let mut pos = 0;
+    while tokens.len() < fields {
+        match data.get(pos) {
+            None => return Err(ImageError::Format("truncated PNM header".to_string())),
+            Some(b'#') => {
+                while pos < data.len() && data[pos] != b'\n' {
+                    pos += 1;
+                }
+            }
+            Some(c) if c.is_ascii_whitespace() => pos += 1,
+            Some(_) => {
+                let start = pos;
+                while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                    pos += 1;
+                }
+                tokens.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+            }
+        }
+    }
+    Ok((tokens, pos + 1))
+}
+
+fn parse_dimension(token: &str, what: &str) -> Result<usize, ImageError> {
+    token
+        .parse::<usize>()
+        .map_err(|_| ImageError::Format(format!("invalid PPM {} '{}'", what, token)))
+}
+
+/// Reads an ASCII (P3) or binary (P6) PPM image into linear RGB
+pub fn read_ppm<R: Read>(mut reader: R) -> Result<Framebuffer, ImageError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let (header, offset) = pnm_header(&data, 4)?;
+    let width = parse_dimension(&header[1], "width")?;
+    let height = parse_dimension(&header[2], "height")?;
+    let max = parse_dimension(&header[3], "maximum value")?;
+    if max == 0 || max > 65535 {
+        return Err(ImageError::Format(format!(
+            "PPM maximum value {} out of range",
+            max
+        )));
+    }
+    let count = match width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(3))
+    {
+        Some(count) => count,
+        None => return Err(ImageError::Format("invalid PPM size".to_string())),
+    };
+    let samples: Vec<usize> = match header[0].as_str() {
+        "P3" => {
+            let text = String::from_utf8_lossy(&data[offset.min(data.len())..]);
+            let samples = text
+                .split_whitespace()
+                .take(count)
+                .map(|token| parse_dimension(token, "sample"))
+                .collect::<Result<Vec<_>, _>>()?;
+            samples
+        }
+        "P6" => {
+            let bytes = if max < 256 { 1 } else { 2 };
+            let body = data.get(offset..).unwrap_or(&[]);
+            body.chunks(bytes)
+                .take(count)
+                .map(|chunk| match *chunk {
+                    [b] => b as usize,
+                    [hi, lo] => ((hi as usize) << 8) | lo as usize,
+                    _ => 0,
+                })
+                .collect()
+        }
+        magic => {
+            return Err(ImageError::Format(format!(
+                "unsupported PPM type '{}'",
+                magic
+            )))
+        }
+    };
+    if samples.len() < count {
+        return Err(ImageError::Format(format!(
+            "PPM has {} samples, expected {}",
+            samples.len(),
+            count
+        )));
+    }
+
+    let scale = 1.0 / max as f32;
+    let mut image = Framebuffer::new(width, height);
+    for (pixel, rgb) in image.pixels.iter_mut().zip(samples.chunks(3)) {
+        *pixel = Vec3::new(
+            srgb_to_linear(rgb[0].min(max) as f32 * scale),
+            srgb_to_linear(rgb[1].min(max) as f32 * scale),
+            srgb_to_linear(rgb[2].min(max) as f32 * scale),
+        );
+    }
+    Ok(image)
+}
+
+pub fn load_ppm<P: AsRef<Path>>(path: P) -> Result<Framebuffer, ImageError> {
+    read_ppm(BufReader::new(File::open(path)?))
+}
+
+/// Every pixel quantized to 8 bits, as a flat RGB array
+fn rgb8(image: &Framebuffer) -> Vec<u8> {
+    image.pixels.iter().flat_map(to_rgb8).collect()
+}
+
+/// Writes an ASCII (P3) PPM image
+pub fn write_ppm_ascii<W: Write>(mut writer: W, image: &Framebuffer) -> io::Result<()> {
+    writeln!(writer, "P3\n{} {}\n255", image.width, image.height)?;
+    for col in image.pixels.iter() {
+        let [r, g, b] = to_rgb8(col);
+        writeln!(writer, "{} {} {}", r, g, b)?;
+    }
+    writer.flush()
+}
+
+/// Writes a binary (P6) PPM image
+pub fn write_ppm<W: Write>(mut writer: W, image: &Framebuffer) -> io::Result<()> {
+    write!(writer, "P6\n{} {}\n255\n", image.width, image.height)?;
+    writer.write_all(&rgb8(image))?;
+    writer.flush()
+}
+
+pub fn write_png<W: Write>(writer: W, image: &Framebuffer) -> io::Result<()> {
+    write_png_rgb8(writer, image.width, image.height, &rgb8(image))
+}
+
+/// File formats images can be saved in or loaded from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    /// Binary PPM
+    Ppm,
+    Png,
+    /// Radiance RGBE
+    Hdr,
+    /// Portable Float Map
+    Pfm,
+}
+
+impl ImageFormat {
+    /// Whether the format stores floating point radiance rather than 8-bit
+    /// display values
+    pub fn is_hdr(self) -> bool {
+        match self {
+            ImageFormat::Hdr | ImageFormat::Pfm => true,
+            ImageFormat::Ppm | ImageFormat::Png => false,
+        }
+    }
+
+    /// Picks the format from the extension of `path`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<ImageFormat, ImageError> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("ppm") => Ok(ImageFormat::Ppm),
+            Some("png") => Ok(ImageFormat::Png),
+            Some("hdr") => Ok(ImageFormat::Hdr),
+            Some("pfm") => Ok(ImageFormat::Pfm),
+            Some(ext) => Err(ImageError::Format(format!(
+                "unsupported image format '.{}'",
+                ext
+            ))),
+            None => Err(ImageError::Format(format!(
+                "'{}' has no extension to pick an image format from",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// Writes a linear image to `path`, choosing the format from its extension.
+/// 8-bit formats are tone mapped with `tone`; float formats get the radiance
+/// unchanged.
+pub fn save<P: AsRef<Path>>(path: P, image: &Framebuffer, tone: &ToneMap) -> Result<(), ImageError> {
+    let path = path.as_ref();
+    let format = ImageFormat::from_path(path)?;
+    let writer = BufWriter::new(File::create(path)?);
+    match format {
+        ImageFormat::Ppm => write_ppm(writer, &tone.apply(image))?,
+        ImageFormat::Png => write_png(writer, &tone.apply(image))?,
+        ImageFormat::Hdr => write_hdr(writer, image)?,
+        ImageFormat::Pfm => write_pfm(writer, image)?,
+    }
+    Ok(())
+}
+
+/// Reads an image from `path` into linear RGB, choosing the format from its
+/// extension
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Framebuffer, ImageError> {
+    let path = path.as_ref();
+    let format = ImageFormat::from_path(path)?;
+    let reader = BufReader::new(File::open(path)?);
+    match format {
+        ImageFormat::Ppm => read_ppm(reader),
+        ImageFormat::Hdr => read_hdr(reader),
+        ImageFormat::Pfm => read_pfm(reader),
+        ImageFormat::Png => Err(ImageError::Format(
+            "reading PNG images is not supported".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_and_binary_ppm_agree() {
+        let ascii = read_ppm(&b"P3\n# comment\n2 1\n255\n255 0 0  0 0 255\n"[..]).unwrap();
+        let binary = read_ppm(&b"P6 2 1 255\n\xff\x00\x00\x00\x00\xff"[..]).unwrap();
+        assert_eq!(ascii.pixels, binary.pixels);
+        assert_eq!(*ascii.get(0, 0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(*ascii.get(1, 0), Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn ppm_rejects_overflowing_sizes() {
+        let huge = format!("P6 {} {} 255\n", usize::MAX / 2, 3);
+        match read_ppm(huge.as_bytes()) {
+            Err(ImageError::Format(message)) => assert_eq!(message, "invalid PPM size"),
+            _ => panic!("expected a format error"),
+        }
+    }
+
+    #[test]
+    fn srgb_transfer() {
+        for i in 0..=255 {
+            let value = i as f32 / 255.0;
+            assert!((linear_to_srgb(srgb_to_linear(value)) - value).abs() < 1e-5);
+        }
+        // Mid grey in sRGB is about a fifth of the linear intensity
+        assert!((srgb_to_linear(0.5) - 0.214).abs() < 1e-3);
+        assert_eq!(to_rgb8(&Vec3::new(2.0, 1.0, -1.0)), [255, 255, 0]);
+    }
+
+    #[test]
+    fn ppm_round_trip() {
+        let mut image = Framebuffer::new(3, 2);
+        image.set(0, 0, Vec3::new(1.0, 0.25, 0.0));
+        image.set(2, 1, Vec3::new(0.5, 4.0, -1.0));
+        let mut binary = Vec::new();
+        write_ppm(&mut binary, &image).unwrap();
+        let mut ascii = Vec::new();
+        write_ppm_ascii(&mut ascii, &image).unwrap();
+
+        let from_binary = read_ppm(&binary[..]).unwrap();
+        assert_eq!(from_binary.pixels, read_ppm(&ascii[..]).unwrap().pixels);
+        assert_eq!(rgb8(&from_binary), rgb8(&image));
+    }
+}
@@ -1,11 +1,34 @@
-extern crate rand;
 pub mod vec;
 pub mod materials;
 pub mod camera;
+pub mod aabb;
+pub mod bvh;
+pub mod rng;
+pub mod render;
+pub mod mesh;
+pub mod obj;
+pub mod scene;
+pub mod texture;
+pub mod image;
+pub mod parser;
+pub mod png;
+pub mod hdr;
+pub mod tonemap;
+pub mod sampler;
+pub mod filter;
+pub mod film;
+pub mod shapes;
+pub mod medium;
+pub mod light;
+pub mod microfacet;
+pub mod spectrum;
 
 use vec::*;
 use materials::*;
-use rand::{thread_rng, Rng};
+use aabb::Aabb;
+use sampler::Sampler;
+use std::f32;
+use std::sync::Arc;
 
 pub const RAND_END: f32 = 0.99999;
 
@@ -40,44 +63,79 @@ pub fn cross(lhs: &Vec3, rhs: &Vec3) -> Vec3 {
     }
 }
 
-pub fn rand_in_unit_sphere() -> Vec3 {
-    let mut rng = thread_rng();
-    let mut p;
-    loop {
-        p = 2.0
-            * Vec3::new(
-                rng.gen_range::<f32>(0.0, RAND_END),
-                rng.gen_range::<f32>(0.0, RAND_END),
-                rng.gen_range::<f32>(0.0, RAND_END),
-            ) - Vec3::new(1.0, 1.0, 1.0);
-        if !(p.squared_length() >= 1.0) {
-            break;
-        }
-    }
-    p
+/// Uniformly distributed unit vector from a 2D sample
+pub fn unit_sphere_direction(u: (f32, f32)) -> Vec3 {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * f32::consts::PI * u.1;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
 }
 
-#[inline(always)]
-pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
-    return v - &(2.0 * dot(v, n) * n);
+/// Two unit vectors that together with the unit vector `n` form a
+/// right-handed orthonormal basis (Duff et al. 2017)
+pub fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    let sign = 1f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (
+        Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        Vec3::new(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
+/// Uniformly distributed point inside the unit sphere, drawing a 2D sample
+/// for the direction and a 1D one for the distance from the centre
+pub fn rand_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+    let direction = unit_sphere_direction(sampler.get_2d());
+    sampler.get_1d().cbrt() * direction
 }
 
 #[inline(always)]
-pub fn shlick(cos: f32, idx: f32) -> f32 {
-    let mut r0 = (1.0 - idx) / (1.0 + idx);
-    r0 = r0 * r0;
-    return r0 + (1.0 - r0) * (1.0 - cos).powf(5.0);
+pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
+    v - &(2.0 * dot(v, n) * n)
 }
 
 #[derive(Debug, Clone)]
 pub struct Ray {
     a: Vec3,
     b: Vec3,
+    time: f32,
+    wavelength: Option<f32>,
+    medium_sample: Option<f32>,
 }
 
 impl Ray {
     pub fn new(a: Vec3, b: Vec3) -> Ray {
-        Ray { a: a, b: b }
+        Ray::with_time(a, b, 0.0)
+    }
+
+    /// A ray that samples the scene at `time`, for moving objects
+    pub fn with_time(a: Vec3, b: Vec3, time: f32) -> Ray {
+        Ray {
+            a,
+            b,
+            time,
+            wavelength: None,
+            medium_sample: None,
+        }
+    }
+
+    /// The same ray carrying light of a single `wavelength` in nanometres,
+    /// for materials whose effect depends on it
+    pub fn with_wavelength(self, wavelength: f32) -> Ray {
+        Ray {
+            wavelength: Some(wavelength),
+            ..self
+        }
+    }
+
+    /// The same ray carrying a uniform sample in `[0, 1)`, which decides how
+    /// far it travels through participating media before scattering
+    pub fn with_medium_sample(self, u: f32) -> Ray {
+        Ray {
+            medium_sample: Some(u),
+            ..self
+        }
     }
 
     #[inline(always)]
@@ -89,6 +147,21 @@ impl Ray {
         self.b.clone()
     }
     #[inline(always)]
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+    /// Wavelength of the light the ray carries, or `None` for RGB light
+    #[inline(always)]
+    pub fn wavelength(&self) -> Option<f32> {
+        self.wavelength
+    }
+    /// Sample for free-flight distances in media, or `None` if the ray was
+    /// not given one
+    #[inline(always)]
+    pub fn medium_sample(&self) -> Option<f32> {
+        self.medium_sample
+    }
+    #[inline(always)]
     pub fn point_at_param(&self, t: f32) -> Vec3 {
         &self.a + &(t * &self.b)
     }
@@ -98,7 +171,16 @@ pub struct HitRecord {
     pub t: f32,
     pub p: Vec3,
     pub normal: Vec3,
-    pub mat: Option<Box<Material>>,
+    /// Surface coordinates of the hit, for texturing
+    pub u: f32,
+    pub v: f32,
+    pub mat: Option<Box<dyn Material>>,
+}
+
+impl Default for HitRecord {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HitRecord {
@@ -107,57 +189,238 @@ impl HitRecord {
             t: 0.0,
             p: Vec3::new(0.0, 0.0, 0.0),
             normal: Vec3::new(0.0, 0.0, 0.0),
+            u: 0.0,
+            v: 0.0,
             mat: None,
         }
     }
+
+    /// Whether `ray` arrives on the side of the surface the normal faces
+    pub fn front_face(&self, ray: &Ray) -> bool {
+        dot(&ray.direction(), &self.normal) < 0.0
+    }
+
+    /// The normal turned towards where `ray` comes from, for materials that
+    /// look the same from both sides of a surface
+    pub fn facing_normal(&self, ray: &Ray) -> Vec3 {
+        if self.front_face(ray) {
+            self.normal.clone()
+        } else {
+            -&self.normal
+        }
+    }
 }
 
-pub trait Hit {
+pub trait Hit: Send + Sync {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool;
+    /// Box enclosing the object, or `None` if it is unbounded
+    fn bounding_box(&self) -> Option<Aabb>;
+}
+
+impl<T: Hit + ?Sized> Hit for Box<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        (**self).hit(ray, t_min, t_max, hit_record)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        (**self).bounding_box()
+    }
+}
+
+impl<T: Hit + ?Sized> Hit for Arc<T> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        (**self).hit(ray, t_min, t_max, hit_record)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        (**self).bounding_box()
+    }
 }
 
+#[derive(Clone)]
 pub struct Sphere {
     center: Vec3,
     radius: f32,
-    material: Box<Material>,
+    material: Box<dyn Material>,
 }
 
 impl Sphere {
-    pub fn new(center: Vec3, radius: f32, mat: Box<Material>) -> Sphere {
+    pub fn new(center: Vec3, radius: f32, mat: Box<dyn Material>) -> Sphere {
         Sphere {
-            center: center,
-            radius: radius,
+            center,
+            radius,
             material: mat,
         }
     }
+
+    pub fn material(&self) -> &dyn Material {
+        &*self.material
+    }
+}
+
+/// Nearest intersection of `ray` with a sphere inside `(t_min, t_max)`
+fn intersect_sphere(center: &Vec3, radius: f32, ray: &Ray, t_min: f32, t_max: f32) -> Option<f32> {
+    let oc = &ray.origin() - center;
+    let a = dot(&ray.direction(), &ray.direction());
+    let b = dot(&oc, &ray.direction());
+    let c = dot(&oc, &oc) - (radius * radius);
+    let discriminant = (b * b) - (a * c);
+    if discriminant > 0.0 {
+        let temp = (-b - discriminant.sqrt()) / a;
+        if temp < t_max && temp > t_min {
+            return Some(temp);
+        }
+        let temp = (-b + discriminant.sqrt()) / a;
+        if temp < t_max && temp > t_min {
+            return Some(temp);
+        }
+    }
+    None
+}
+
+fn record_sphere_hit(
+    center: &Vec3,
+    radius: f32,
+    material: &dyn Material,
+    ray: &Ray,
+    t: f32,
+    hit_record: &mut HitRecord,
+) {
+    hit_record.t = t;
+    hit_record.p = ray.point_at_param(t);
+    hit_record.normal = (&hit_record.p - center) / radius;
+    let (u, v) = sphere_uv(&hit_record.normal);
+    hit_record.u = u;
+    hit_record.v = v;
+    hit_record.mat = Some(material.clone_box());
+}
+
+/// Texture coordinates of a point on the unit sphere, with `u` running around
+/// the y axis from -x and `v` running from the bottom pole to the top
+pub fn sphere_uv(p: &Vec3) -> (f32, f32) {
+    let phi = p.z.atan2(p.x);
+    let theta = p.y.clamp(-1.0, 1.0).asin();
+    (
+        1.0 - (phi + f32::consts::PI) / (2.0 * f32::consts::PI),
+        (theta + f32::consts::FRAC_PI_2) / f32::consts::PI,
+    )
 }
 
 impl Hit for Sphere {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
-        let oc = &ray.origin() - &self.center;
-        let a = dot(&ray.direction(), &ray.direction());
-        let b = dot(&oc, &ray.direction());
-        let c = dot(&oc, &oc) - (&self.radius * &self.radius);
-        let discriminant = (b * b) - (a * c);
-        let mut temp = (-b - discriminant.sqrt()) / a;
-        if discriminant > 0.0 {
-            if temp < t_max && temp > t_min {
-                hit_record.t = temp;
-                hit_record.p = ray.point_at_param(hit_record.t);
-                hit_record.normal = (&hit_record.p - &self.center) / self.radius;
-                hit_record.mat = Some(self.material.clone_box());
-                return true;
+        match intersect_sphere(&self.center, self.radius, ray, t_min, t_max) {
+            Some(t) => {
+                record_sphere_hit(&self.center, self.radius, &*self.material, ray, t, hit_record);
+                true
             }
-            temp = (-b + discriminant.sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                hit_record.t = temp;
-                hit_record.p = ray.point_at_param(hit_record.t);
-                hit_record.normal = (&hit_record.p - &self.center) / self.radius;
-                hit_record.mat = Some(self.material.clone_box());
-                return true;
+            None => false,
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Some(Aabb::new(&self.center - &r, &self.center + &r))
+    }
+}
+
+/// Sphere whose centre moves in a straight line from `center0` at `time0` to
+/// `center1` at `time1`, resting at either end outside that interval
+pub struct MovingSphere {
+    pub center0: Vec3,
+    pub center1: Vec3,
+    pub time0: f32,
+    pub time1: f32,
+    pub radius: f32,
+    material: Box<dyn Material>,
+}
+
+impl MovingSphere {
+    pub fn new(
+        center0: Vec3,
+        center1: Vec3,
+        time0: f32,
+        time1: f32,
+        radius: f32,
+        mat: Box<dyn Material>,
+    ) -> MovingSphere {
+        MovingSphere {
+            center0,
+            center1,
+            time0,
+            time1,
+            radius,
+            material: mat,
+        }
+    }
+
+    pub fn center(&self, time: f32) -> Vec3 {
+        let span = self.time1 - self.time0;
+        if span <= 0.0 {
+            return self.center0.clone();
+        }
+        let s = ((time - self.time0) / span).clamp(0.0, 1.0);
+        &self.center0 + &(s * &(&self.center1 - &self.center0))
+    }
+}
+
+impl Hit for MovingSphere {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let center = self.center(ray.time());
+        match intersect_sphere(&center, self.radius, ray, t_min, t_max) {
+            Some(t) => {
+                record_sphere_hit(&center, self.radius, &*self.material, ray, t, hit_record);
+                true
             }
+            None => false,
         }
-        return false;
+    }
+
+    /// The centre only ever lies on the segment between the keyframes, so the
+    /// boxes at both ends enclose the sphere at every time
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        let start = Aabb::new(&self.center0 - &r, &self.center0 + &r);
+        let end = Aabb::new(&self.center1 - &r, &self.center1 + &r);
+        Some(start.surrounding(&end))
+    }
+}
+
+/// An object placed in the world by `transform`. Rays are moved into the
+/// object's own space, so wrapping one `Arc`'d mesh in several of these draws
+/// it in several places while storing it once.
+pub struct Transformed<H: Hit> {
+    pub object: H,
+    pub transform: Transform,
+}
+
+impl<H: Hit> Transformed<H> {
+    pub fn new(object: H, transform: Transform) -> Transformed<H> {
+        Transformed { object, transform }
+    }
+}
+
+impl<H: Hit> Hit for Transformed<H> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        // The direction is not renormalised, so distances along the ray mean
+        // the same in both spaces
+        let to_object = self.transform.inverse();
+        let local = Ray {
+            a: to_object.point(&ray.origin()),
+            b: to_object.vector(&ray.direction()),
+            ..*ray
+        };
+        if !self.object.hit(&local, t_min, t_max, hit_record) {
+            return false;
+        }
+        hit_record.p = self.transform.point(&hit_record.p);
+        hit_record.normal = unit_vector(&self.transform.normal(&hit_record.normal));
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.object
+            .bounding_box()
+            .map(|bounds| bounds.transform(&self.transform))
     }
 }
 
@@ -186,6 +449,15 @@ impl<T: Hit> Hit for HitList<T> {
         }
         hit_anything
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let mut items = self.list.iter();
+        let mut bounds = items.next()?.bounding_box()?;
+        for item in items {
+            bounds = bounds.surrounding(&item.bounding_box()?);
+        }
+        Some(bounds)
+    }
 }
 
 #[cfg(test)]
@@ -206,4 +478,73 @@ mod tests {
         let new = &rhs * &lhs;
         assert_eq!(new, Vec3::new(8f32, 1f32, 1f32));
     }
+
+    #[test]
+    fn moving_sphere_follows_its_keyframes() {
+        let sphere = MovingSphere::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            0.0,
+            1.0,
+            0.5,
+            Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+        );
+        let mut hit_record = HitRecord::new();
+        let down = |x: f32, time: f32| {
+            Ray::with_time(Vec3::new(x, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), time)
+        };
+        assert!(sphere.hit(&down(0.0, 0.0), 0.001, 100.0, &mut hit_record));
+        assert!(!sphere.hit(&down(0.0, 1.0), 0.001, 100.0, &mut hit_record));
+        assert!(sphere.hit(&down(1.0, 0.5), 0.001, 100.0, &mut hit_record));
+        assert_eq!(hit_record.normal, Vec3::new(0.0, 1.0, 0.0));
+        // Past the last keyframe the sphere stays put, inside its bounds
+        assert_eq!(sphere.center(3.0), Vec3::new(2.0, 0.0, 0.0));
+        let bounds = sphere.bounding_box().unwrap();
+        assert_eq!(bounds.min, Vec3::new(-0.5, -0.5, -0.5));
+        assert_eq!(bounds.max, Vec3::new(2.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn transformed_objects_share_one_instance() {
+        let sphere: Arc<dyn Hit> = Arc::new(Sphere::new(
+            Vec3::origin(),
+            1.0,
+            Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+        ));
+        // Squashed to an ellipsoid 4 wide, then moved up by 5
+        let ellipsoid = Transformed::new(
+            sphere.clone(),
+            Transform::translate(&Vec3::new(0.0, 5.0, 0.0))
+                * Transform::scale(&Vec3::new(4.0, 1.0, 1.0)),
+        );
+        let mut hit_record = HitRecord::new();
+        let ray = Ray::new(Vec3::new(-10.0, 5.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(ellipsoid.hit(&ray, 0.001, 100.0, &mut hit_record));
+        assert!((hit_record.t - 6.0).abs() < 1e-5);
+        assert!((&hit_record.p - &Vec3::new(-4.0, 5.0, 0.0)).length() < 1e-5);
+        assert!((&hit_record.normal - &Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-5);
+
+        // Off the centre line the normal tilts towards the flat side
+        let ray = Ray::new(Vec3::new(2.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(ellipsoid.hit(&ray, 0.001, 100.0, &mut hit_record));
+        assert!((hit_record.normal.length() - 1.0).abs() < 1e-5);
+        assert!(hit_record.normal.x > 0.0 && hit_record.normal.x < 0.3);
+
+        let bounds = ellipsoid.bounding_box().unwrap();
+        assert_eq!(bounds.min, Vec3::new(-4.0, 4.0, -1.0));
+        assert_eq!(bounds.max, Vec3::new(4.0, 6.0, 1.0));
+        // The original is untouched
+        assert!(!ellipsoid.hit(
+            &Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0)),
+            0.001,
+            100.0,
+            &mut hit_record
+        ));
+        assert!(sphere.hit(
+            &Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0)),
+            0.001,
+            100.0,
+            &mut hit_record
+        ));
+    }
 }
@@ -0,0 +1,245 @@
+//! Emitters that can be sampled directly, so that the integrator can aim
+//! shadow rays at them rather than wait for scattered rays to find them.
+
+use std::f32;
+
+use super::shapes::Quad;
+use super::{
+    dot, intersect_sphere, orthonormal_basis, unit_vector, Hit, HitRecord, Ray, Sphere, Vec3,
+};
+
+/// A point picked on a light as seen from some origin
+#[derive(Debug, Clone)]
+pub struct LightSample {
+    /// From the origin to the point on the light; not normalised
+    pub direction: Vec3,
+    /// Density of `direction` with respect to solid angle at the origin
+    pub pdf: f32,
+}
+
+pub trait Light: Send + Sync {
+    /// Picks a point on the light to send a shadow ray to from `origin`, or
+    /// `None` if the light cannot be seen from there
+    fn sample(&self, origin: &Vec3, u: (f32, f32)) -> Option<LightSample>;
+
+    /// Solid angle density with which `sample` picks `direction` from
+    /// `origin`, which is 0 if the ray misses the light before `t_max`, in
+    /// units of the length of `direction`
+    fn pdf(&self, origin: &Vec3, direction: &Vec3, t_max: f32) -> f32;
+}
+
+/// Converts an area density at a point `distance_squared` away, seen at an
+/// angle with cosine `cos` to its surface, to a solid angle density
+#[inline(always)]
+fn area_to_solid_angle(area_pdf: f32, distance_squared: f32, cos: f32) -> f32 {
+    if cos < 1e-6 {
+        0.0
+    } else {
+        area_pdf * distance_squared / cos
+    }
+}
+
+impl Light for Sphere {
+    /// Samples the cone of directions the sphere covers from outside it.
+    /// Spheres only light their outside, so nothing is sampled from inside.
+    fn sample(&self, origin: &Vec3, u: (f32, f32)) -> Option<LightSample> {
+        let to_center = &self.center - origin;
+        let distance_squared = to_center.squared_length();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return None;
+        }
+        let sin_squared_max = radius_squared / distance_squared;
+        let cos_max = (1.0 - sin_squared_max).max(0.0).sqrt();
+        // 1 - cos_max, without cancellation for small, distant spheres
+        let one_minus_cos_max = sin_squared_max / (1.0 + cos_max);
+        let cos_theta = 1.0 - u.0 * one_minus_cos_max;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * f32::consts::PI * u.1;
+        let axis = unit_vector(&to_center);
+        let (tangent, bitangent) = orthonormal_basis(&axis);
+        let direction =
+            sin_theta * phi.cos() * tangent + sin_theta * phi.sin() * bitangent + cos_theta * axis;
+        // Directions at the rim may graze past the sphere by rounding; aim at
+        // the closest point instead
+        let ray = Ray::new(origin.clone(), direction.clone());
+        let t = intersect_sphere(&self.center, self.radius, &ray, 0.0, f32::MAX)
+            .unwrap_or_else(|| dot(&to_center, &direction));
+        Some(LightSample {
+            direction: t * direction,
+            pdf: 1.0 / (2.0 * f32::consts::PI * one_minus_cos_max),
+        })
+    }
+
+    fn pdf(&self, origin: &Vec3, direction: &Vec3, t_max: f32) -> f32 {
+        let distance_squared = (&self.center - origin).squared_length();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return 0.0;
+        }
+        let ray = Ray::new(origin.clone(), direction.clone());
+        if intersect_sphere(&self.center, self.radius, &ray, 1e-4, t_max).is_none() {
+            return 0.0;
+        }
+        let sin_squared_max = radius_squared / distance_squared;
+        let cos_max = (1.0 - sin_squared_max).max(0.0).sqrt();
+        1.0 / (2.0 * f32::consts::PI * sin_squared_max / (1.0 + cos_max))
+    }
+}
+
+impl Light for Quad {
+    /// Samples the quad uniformly by area. Quads only light the side their
+    /// normal faces, so nothing is sampled from behind.
+    fn sample(&self, origin: &Vec3, u: (f32, f32)) -> Option<LightSample> {
+        let point = &(&self.q + &(u.0 * &self.u)) + &(u.1 * &self.v);
+        let direction = &point - origin;
+        let cos = -dot(&self.normal(), &unit_vector(&direction));
+        let pdf = area_to_solid_angle(1.0 / self.area(), direction.squared_length(), cos);
+        if pdf > 0.0 && pdf.is_finite() {
+            Some(LightSample { direction, pdf })
+        } else {
+            None
+        }
+    }
+
+    fn pdf(&self, origin: &Vec3, direction: &Vec3, t_max: f32) -> f32 {
+        let ray = Ray::new(origin.clone(), direction.clone());
+        let mut hit_record = HitRecord::new();
+        if !self.hit(&ray, 1e-4, t_max, &mut hit_record) {
+            return 0.0;
+        }
+        let to_point = &hit_record.p - origin;
+        let cos = -dot(&self.normal(), &unit_vector(&to_point));
+        area_to_solid_angle(1.0 / self.area(), to_point.squared_length(), cos)
+    }
+}
+
+/// The lights of a scene, picked between uniformly
+#[derive(Default)]
+pub struct LightList {
+    pub lights: Vec<Box<dyn Light>>,
+}
+
+impl LightList {
+    pub fn new() -> LightList {
+        LightList::default()
+    }
+
+    pub fn push<L: Light + 'static>(&mut self, light: L) {
+        self.lights.push(Box::new(light));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    /// Picks one light with `u_light` and a point on it with `u`
+    pub fn sample(&self, origin: &Vec3, u_light: f32, u: (f32, f32)) -> Option<LightSample> {
+        let count = self.lights.len();
+        if count == 0 {
+            return None;
+        }
+        let index = ((u_light * count as f32) as usize).min(count - 1);
+        self.lights[index]
+            .sample(origin, u)
+            .map(|sample| LightSample {
+                pdf: sample.pdf / count as f32,
+                ..sample
+            })
+    }
+
+    /// Density with which `sample` picks `direction` from `origin` and finds
+    /// a light before `t_max`. Lights further away count for nothing, since
+    /// shadow rays aimed at them are blocked by whatever is at `t_max`.
+    pub fn pdf(&self, origin: &Vec3, direction: &Vec3, t_max: f32) -> f32 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let total: f32 = self
+            .lights
+            .iter()
+            .map(|light| light.pdf(origin, direction, t_max))
+            .sum();
+        total / self.lights.len() as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::materials::DiffuseLight;
+    use super::super::sampler::{IndependentSampler, Sampler};
+    use super::*;
+
+    fn lamp() -> Box<DiffuseLight> {
+        Box::new(DiffuseLight::new(Vec3::new(1.0, 1.0, 1.0)))
+    }
+
+    /// Solid angle of `light` from `origin`, estimated from its own samples
+    fn solid_angle<L: Light>(light: &L, origin: &Vec3) -> f32 {
+        let mut sampler = IndependentSampler::new(3);
+        let n = 4000;
+        let mut total = 0.0;
+        for _ in 0..n {
+            let sample = light.sample(origin, sampler.get_2d()).unwrap();
+            let pdf = light.pdf(origin, &sample.direction, f32::MAX);
+            assert!(
+                (pdf - sample.pdf).abs() <= 1e-3 * sample.pdf,
+                "{} != {}",
+                pdf,
+                sample.pdf
+            );
+            total += 1.0 / sample.pdf;
+        }
+        total / n as f32
+    }
+
+    #[test]
+    fn sphere_samples_cover_its_solid_angle() {
+        let sphere = Sphere::new(Vec3::new(0.0, 3.0, 0.0), 1.0, lamp());
+        let expected = 2.0 * f32::consts::PI * (1.0 - (8.0f32 / 9.0).sqrt());
+        let estimate = solid_angle(&sphere, &Vec3::origin());
+        assert!((estimate - expected).abs() < 1e-3 * expected);
+        let right = Vec3::new(1.0, 0.0, 0.0);
+        assert_eq!(sphere.pdf(&Vec3::origin(), &right, f32::MAX), 0.0);
+        // Nor does it count when something nearer is in the way
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        assert!(sphere.pdf(&Vec3::origin(), &up, f32::MAX) > 0.0);
+        assert_eq!(sphere.pdf(&Vec3::origin(), &up, 1.5), 0.0);
+        // The inside is dark
+        let inside = Vec3::new(0.2, 3.5, 0.0);
+        assert!(sphere.sample(&inside, (0.3, 0.6)).is_none());
+        assert_eq!(sphere.pdf(&inside, &up, f32::MAX), 0.0);
+    }
+
+    #[test]
+    fn quad_samples_cover_its_solid_angle() {
+        let quad = Quad::new(
+            Vec3::new(-1.0, 2.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            lamp(),
+        );
+        // A square of side 2 seen from a distance of 2 along its axis
+        // subtends 4 asin(1 / 5)
+        let expected = 4.0 * (0.2f32).asin();
+        let estimate = solid_angle(&quad, &Vec3::origin());
+        assert!(
+            (estimate - expected).abs() < 0.02 * expected,
+            "{}",
+            estimate
+        );
+        // It faces down, away from points above it
+        let above = Vec3::new(0.0, 3.0, 0.0);
+        assert!(quad.sample(&above, (0.3, 0.6)).is_none());
+        assert_eq!(quad.pdf(&above, &Vec3::new(0.0, -1.0, 0.0), f32::MAX), 0.0);
+
+        let mut lights = LightList::new();
+        lights.push(quad);
+        lights.push(Sphere::new(Vec3::new(0.0, -3.0, 0.0), 1.0, lamp()));
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        assert_eq!(
+            lights.pdf(&Vec3::origin(), &up, f32::MAX),
+            lights.lights[0].pdf(&Vec3::origin(), &up, f32::MAX) / 2.0
+        );
+    }
+}
@@ -1,77 +1,159 @@
-extern crate rand;
+use std::f32;
 
-use super::{dot, rand_in_unit_sphere, reflect, refract, shlick, unit_vector, HitRecord,
-            Ray, Vec3};
-use rand::{thread_rng, Rng};
+use super::{dot, orthonormal_basis, rand_in_unit_sphere, reflect, refract,
+            unit_sphere_direction, unit_vector, HitRecord, Ray, Vec3};
+use super::microfacet::{reflect_about, refract_about, Frame, Ggx};
+use super::sampler::Sampler;
+use super::spectrum::REFERENCE_WAVELENGTH;
+use super::texture::{SolidColor, Texture};
 
-pub trait Material: MaterialClone {
+pub trait Material: MaterialClone + Send + Sync {
     fn scatter(
         &self,
         rays: &Ray,
         hit_record: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
     ) -> bool;
+
+    /// The BSDF times the cosine between `direction` and the normal: how much
+    /// of the light arriving from `direction` is scattered back along `ray`.
+    /// Black unless overridden.
+    fn eval(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: &Vec3) -> Vec3 {
+        Vec3::origin()
+    }
+
+    /// Solid angle density with which `scatter` picks `direction`
+    fn pdf(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: &Vec3) -> f32 {
+        0.0
+    }
+
+    /// Whether light only reaches the surface along the directions `scatter`
+    /// picks, as for mirrors and glass, so that sampling light sources
+    /// directly would find nothing. Materials that implement `eval` and `pdf`
+    /// return false.
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    /// Radiance given off by the surface at `p`, black unless overridden
+    fn emitted(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
+        Vec3::origin()
+    }
+
+    /// Whether `emitted` can be anything but black, so that objects made of
+    /// the material are worth sampling as lights
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    /// Whether the direction `scatter` picks depends on the wavelength of
+    /// the ray, so that a path can only carry one wavelength past the
+    /// surface
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+}
+
+/// Density of cosine weighted directions around the unit normal `normal`
+#[inline(always)]
+fn cosine_pdf(normal: &Vec3, direction: &Vec3) -> f32 {
+    dot(normal, &unit_vector(direction)).max(0.0) / f32::consts::PI
 }
 
 pub trait MaterialClone {
-    fn clone_box(&self) -> Box<Material>;
+    fn clone_box(&self) -> Box<dyn Material>;
 }
 
 impl<T> MaterialClone for T
 where
     T: 'static + Material + Clone,
 {
-    fn clone_box(&self) -> Box<Material> {
+    fn clone_box(&self) -> Box<dyn Material> {
         Box::new(self.clone())
     }
 }
 
-impl Clone for Box<Material> {
-    fn clone(&self) -> Box<Material> {
+impl Clone for Box<dyn Material> {
+    fn clone(&self) -> Box<dyn Material> {
         self.clone_box()
     }
 }
 
 #[derive(Clone)]
 pub struct Lambertian {
-    pub albedo: Vec3,
+    pub albedo: Box<dyn Texture>,
 }
 
 impl Lambertian {
     pub fn new(vec: Vec3) -> Lambertian {
-        Lambertian { albedo: vec }
+        Lambertian {
+            albedo: Box::new(SolidColor::new(vec)),
+        }
+    }
+
+    pub fn textured(albedo: Box<dyn Texture>) -> Lambertian {
+        Lambertian { albedo }
     }
 }
 
 impl Material for Lambertian {
     fn scatter(
         &self,
-        _ray: &Ray,
+        ray: &Ray,
         hit_record: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
     ) -> bool {
-        let target = &hit_record.p + &hit_record.normal + rand_in_unit_sphere();
-        *scattered = Ray::new(hit_record.p.clone(), target - hit_record.p.clone());
-        *attenuation = self.albedo.clone();
-        return true;
+        // A point on the unit sphere above the surface gives a cosine
+        // weighted direction
+        let normal = hit_record.facing_normal(ray);
+        let mut direction = &normal + &unit_sphere_direction(sampler.get_2d());
+        if direction.squared_length() < 1e-12 {
+            direction = normal;
+        }
+        *scattered = Ray::with_time(hit_record.p.clone(), direction, ray.time());
+        *attenuation = self
+            .albedo
+            .value(hit_record.u, hit_record.v, &hit_record.p);
+        true
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> Vec3 {
+        let albedo = self
+            .albedo
+            .value(hit_record.u, hit_record.v, &hit_record.p);
+        cosine_pdf(&hit_record.facing_normal(ray), direction) * albedo
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> f32 {
+        cosine_pdf(&hit_record.facing_normal(ray), direction)
+    }
+
+    fn is_specular(&self) -> bool {
+        false
     }
 }
 
 #[derive(Clone)]
 pub struct Metal {
-    pub albedo: Vec3,
+    pub albedo: Box<dyn Texture>,
     pub fuzz: f32,
 }
 
 impl Metal {
     pub fn new(vec: Vec3, fuzz: f32) -> Metal {
         Metal {
-            albedo: vec,
-            fuzz: fuzz,
+            albedo: Box::new(SolidColor::new(vec)),
+            fuzz,
         }
     }
+
+    pub fn textured(albedo: Box<dyn Texture>, fuzz: f32) -> Metal {
+        Metal { albedo, fuzz }
+    }
 }
 
 impl Material for Metal {
@@ -81,26 +163,271 @@ impl Material for Metal {
         hit_record: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
     ) -> bool {
         let reflect = reflect(&unit_vector(&ray.direction()), &hit_record.normal);
-        *scattered = Ray::new(
+        *scattered = Ray::with_time(
             hit_record.p.clone(),
-            reflect + self.fuzz * rand_in_unit_sphere(),
+            reflect + self.fuzz * rand_in_unit_sphere(sampler),
+            ray.time(),
         );
-        *attenuation = self.albedo.clone();
-        dot(&scattered.direction(), &hit_record.normal) > 0.0
+        *attenuation = self
+            .albedo
+            .value(hit_record.u, hit_record.v, &hit_record.p);
+        dot(&scattered.direction(), &hit_record.facing_normal(ray)) > 0.0
+    }
+
+    /// Directions below the surface are absorbed, so the BSDF is the albedo
+    /// times the density of the fuzzed reflections above it
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> Vec3 {
+        if dot(direction, &hit_record.facing_normal(ray)) <= 0.0 {
+            return Vec3::origin();
+        }
+        let albedo = self.albedo.value(hit_record.u, hit_record.v, &hit_record.p);
+        self.pdf(ray, hit_record, direction) * albedo
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> f32 {
+        let reflected = reflect(&unit_vector(&ray.direction()), &hit_record.normal);
+        fuzz_pdf(&reflected, self.fuzz, direction)
+    }
+
+    /// Too little fuzz makes the lobe's density too sharp to evaluate
+    fn is_specular(&self) -> bool {
+        self.fuzz < 1e-3
     }
 }
 
-#[derive(Clone)]
+/// Density of the directions of `reflected + fuzz * p` for the unit vector
+/// `reflected` and `p` uniform in the unit ball: the volume of the ball of
+/// radius `fuzz` around `reflected` that lies along `direction`, as a
+/// fraction of the whole ball
+fn fuzz_pdf(reflected: &Vec3, fuzz: f32, direction: &Vec3) -> f32 {
+    let cos = dot(reflected, &unit_vector(direction));
+    let sin_squared = 1.0 - cos * cos;
+    let fuzz_squared = fuzz * fuzz;
+    if sin_squared >= fuzz_squared {
+        return 0.0;
+    }
+    // Distances along `direction` at which it enters and leaves the ball
+    let half_chord = (fuzz_squared - sin_squared).sqrt();
+    let far = cos + half_chord;
+    if far <= 0.0 {
+        return 0.0;
+    }
+    let near = (cos - half_chord).max(0.0);
+    (far * far * far - near * near * near) / (4.0 * f32::consts::PI * fuzz_squared * fuzz)
+}
+
+/// Fraction of light reflected by a conductor with the complex index of
+/// refraction `eta + i k`, relative to the outside, for unpolarised light
+/// arriving at an angle with cosine `cos`
+pub fn fresnel_conductor(cos: f32, eta: f32, k: f32) -> f32 {
+    let cos = cos.clamp(0.0, 1.0);
+    let cos_squared = cos * cos;
+    let sin_squared = 1.0 - cos_squared;
+    let eta_squared = eta * eta;
+    let k_squared = k * k;
+    let t0 = eta_squared - k_squared - sin_squared;
+    let a_squared_plus_b_squared = (t0 * t0 + 4.0 * eta_squared * k_squared).sqrt();
+    let a = (0.5 * (a_squared_plus_b_squared + t0)).max(0.0).sqrt();
+    let t1 = a_squared_plus_b_squared + cos_squared;
+    let t2 = 2.0 * cos * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos_squared * a_squared_plus_b_squared + sin_squared * sin_squared;
+    let t4 = t2 * sin_squared;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rs + rp)
+}
+
+/// A metal whose surface is made of GGX microfacets, each a perfect mirror
+/// that reflects by the Fresnel equations for the metal's complex index of
+/// refraction. Only light reflected once by the microfacets is accounted
+/// for, so very rough metals come out a little dark.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conductor {
+    /// Real part of the index of refraction for red, green and blue
+    pub eta: Vec3,
+    /// Imaginary part of the index of refraction, the extinction coefficient
+    pub k: Vec3,
+    /// GGX width of the microfacet distribution. Below 0.001 the metal is a
+    /// perfect mirror.
+    pub roughness: f32,
+}
+
+impl Conductor {
+    pub fn new(eta: Vec3, k: Vec3, roughness: f32) -> Conductor {
+        Conductor { eta, k, roughness }
+    }
+
+    pub fn gold(roughness: f32) -> Conductor {
+        Conductor::new(
+            Vec3::new(0.143, 0.374, 1.442),
+            Vec3::new(3.983, 2.385, 1.603),
+            roughness,
+        )
+    }
+
+    pub fn copper(roughness: f32) -> Conductor {
+        Conductor::new(
+            Vec3::new(0.200, 0.924, 1.102),
+            Vec3::new(3.912, 2.452, 2.142),
+            roughness,
+        )
+    }
+
+    pub fn silver(roughness: f32) -> Conductor {
+        Conductor::new(
+            Vec3::new(0.155, 0.117, 0.138),
+            Vec3::new(4.828, 3.122, 2.147),
+            roughness,
+        )
+    }
+
+    pub fn aluminium(roughness: f32) -> Conductor {
+        Conductor::new(
+            Vec3::new(1.657, 0.880, 0.521),
+            Vec3::new(9.224, 6.270, 4.837),
+            roughness,
+        )
+    }
+
+    /// One of the named metals: gold, copper, silver or aluminium
+    pub fn preset(name: &str, roughness: f32) -> Result<Conductor, String> {
+        match name {
+            "gold" => Ok(Conductor::gold(roughness)),
+            "copper" => Ok(Conductor::copper(roughness)),
+            "silver" => Ok(Conductor::silver(roughness)),
+            "aluminium" | "aluminum" => Ok(Conductor::aluminium(roughness)),
+            _ => Err(format!("unknown metal '{}'", name)),
+        }
+    }
+
+    /// Reflectance for light meeting a microfacet at an angle with cosine
+    /// `cos`
+    pub fn fresnel(&self, cos: f32) -> Vec3 {
+        Vec3::new(
+            fresnel_conductor(cos, self.eta.x(), self.k.x()),
+            fresnel_conductor(cos, self.eta.y(), self.k.y()),
+            fresnel_conductor(cos, self.eta.z(), self.k.z()),
+        )
+    }
+
+    /// Frame around the normal on the side `ray` arrives from, so that
+    /// metals look the same from both sides, and the direction back along
+    /// `ray` in it
+    fn local(ray: &Ray, hit_record: &HitRecord) -> (Frame, Vec3) {
+        let frame = Frame::new(&hit_record.facing_normal(ray));
+        let wo = frame.to_local(&-&unit_vector(&ray.direction()));
+        (frame, wo)
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        attenuation: &mut Vec3,
+        scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        let (frame, wo) = Conductor::local(ray, hit_record);
+        if self.is_specular() {
+            let wi = Vec3::new(-wo.x(), -wo.y(), wo.z());
+            *attenuation = self.fresnel(wo.z());
+            *scattered = Ray::with_time(hit_record.p.clone(), frame.to_world(&wi), ray.time());
+            return true;
+        }
+        // Reflecting off a visible microfacet leaves only the Fresnel term
+        // and the chance that the reflection is not blocked on its way out
+        let ggx = Ggx::new(self.roughness);
+        let h = ggx.sample_visible(&wo, sampler.get_2d());
+        let wi = reflect_about(&wo, &h);
+        if wi.z() <= 0.0 {
+            return false;
+        }
+        *attenuation = ggx.g(&wo, &wi) / ggx.g1(&wo) * self.fresnel(dot(&wo, &h));
+        *scattered = Ray::with_time(hit_record.p.clone(), frame.to_world(&wi), ray.time());
+        true
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> Vec3 {
+        let (frame, wo) = Conductor::local(ray, hit_record);
+        let wi = frame.to_local(&unit_vector(direction));
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Vec3::origin();
+        }
+        let h = unit_vector(&(&wo + &wi));
+        let ggx = Ggx::new(self.roughness);
+        ggx.d(&h) * ggx.g(&wo, &wi) / (4.0 * wo.z()) * self.fresnel(dot(&wo, &h))
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> f32 {
+        let (frame, wo) = Conductor::local(ray, hit_record);
+        let wi = frame.to_local(&unit_vector(direction));
+        if wi.z() <= 0.0 {
+            return 0.0;
+        }
+        // Reflection turns the density of microfacet normals into one of
+        // directions, in which the same angle is twice as wide
+        let h = unit_vector(&(&wo + &wi));
+        Ggx::new(self.roughness).visible_pdf(&wo, &h) / (4.0 * dot(&wo, &h))
+    }
+
+    fn is_specular(&self) -> bool {
+        self.roughness < 1e-3
+    }
+}
+
+/// Smooth glass, water and the like, which reflect or refract light as the
+/// Fresnel equations say. Light crossing the inside keeps `transmittance` of
+/// itself for every `distance` it travels (the Beer–Lambert law), so thick
+/// glass is tinted more deeply than thin. The distance is measured from the
+/// origin of the ray that leaves the inside, which is only right when that
+/// ray started in the same glass: light reaching the inside through another
+/// object nested in it, or hitting the back of an open surface from outside,
+/// is tinted by the wrong amount.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Dielectric {
-    index: f32,
+    /// Index of refraction of the inside, relative to the outside, which the
+    /// normals point into
+    pub index: f32,
+    /// Colour of white light once it has travelled `distance` inside
+    pub transmittance: Vec3,
+    pub distance: f32,
 }
 
 impl Dielectric {
     pub fn new(idx: f32) -> Dielectric {
-        Dielectric { index: idx }
+        Dielectric {
+            index: idx,
+            transmittance: Vec3::new(1.0, 1.0, 1.0),
+            distance: 1.0,
+        }
     }
+
+    /// The same glass, absorbing light so that `transmittance` of it is left
+    /// after `distance`
+    pub fn tinted(self, transmittance: Vec3, distance: f32) -> Dielectric {
+        Dielectric {
+            transmittance,
+            distance,
+            ..self
+        }
+    }
+}
+
+/// Share of light left after travelling from the origin of `ray` to the hit
+/// at `hit_record` through a medium that keeps `transmittance` of it for
+/// every `distance`
+fn beer_lambert(transmittance: &Vec3, distance: f32, ray: &Ray, hit_record: &HitRecord) -> Vec3 {
+    let lengths = hit_record.t * ray.direction().length() / distance;
+    Vec3::new(
+        transmittance.x().powf(lengths),
+        transmittance.y().powf(lengths),
+        transmittance.z().powf(lengths),
+    )
 }
 
 impl Material for Dielectric {
@@ -110,35 +437,1047 @@ impl Material for Dielectric {
         hit_record: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
     ) -> bool {
-        let mut rng = thread_rng();
-        let outward_normal;
-        let reflected = reflect(&ray.direction(), &hit_record.normal);
-        let ni_nt;
-        *attenuation = Vec3::new(1.0, 1.0, 1.0);
+        let direction = unit_vector(&ray.direction());
+        let cos = dot(&direction, &hit_record.normal);
+        let (outward_normal, ni_nt, cos) = if cos > 0.0 {
+            // Leaving the inside, which the ray has crossed from its origin
+            *attenuation = beer_lambert(&self.transmittance, self.distance, ray, hit_record);
+            (-&hit_record.normal, self.index, cos)
+        } else {
+            *attenuation = Vec3::new(1.0, 1.0, 1.0);
+            (hit_record.normal.clone(), 1.0 / self.index, -cos)
+        };
+        // Rays that cannot refract are always reflected, since the Fresnel
+        // term is then 1
+        let reflect_prob = fresnel_dielectric(cos, 1.0 / ni_nt);
         let mut refracted = Vec3::default();
-        let reflect_prob;
-        let cos;
+        let direction = if sampler.get_1d() < reflect_prob
+            || !refract(&direction, &outward_normal, ni_nt, &mut refracted)
+        {
+            reflect(&direction, &hit_record.normal)
+        } else {
+            refracted
+        };
+        *scattered = Ray::with_time(hit_record.p.clone(), direction, ray.time());
+        true
+    }
+}
+
+/// Fraction of unpolarised light reflected where it meets, at an angle with
+/// cosine `cos`, a medium whose index of refraction is `eta` times that of
+/// the one it travels in
+pub fn fresnel_dielectric(cos: f32, eta: f32) -> f32 {
+    let cos = cos.clamp(0.0, 1.0);
+    let sin_squared_t = (1.0 - cos * cos) / (eta * eta);
+    if sin_squared_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_squared_t).sqrt();
+    let parallel = (eta * cos - cos_t) / (eta * cos + cos_t);
+    let perpendicular = (cos - eta * cos_t) / (cos + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+/// How the index of refraction of a transparent material changes with the
+/// wavelength of light, which is what splits white light into colours
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dispersion {
+    /// `a + b / λ²`, with λ in micrometres: simple, and good enough across
+    /// the visible range for most glasses
+    Cauchy { a: f32, b: f32 },
+    /// `sqrt(1 + Σ b λ² / (λ² - c))`, with λ in micrometres, the form glass
+    /// makers publish their data in
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    /// Schott N-BK7, the common optical crown glass
+    pub fn bk7() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [1.039_612, 0.231_792_3, 1.010_469_5],
+            c: [0.006_000_699, 0.020_017_914, 103.560_65],
+        }
+    }
+
+    pub fn fused_silica() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [0.696_166_3, 0.407_942_6, 0.897_479_4],
+            c: [0.004_679_148, 0.013_512_063, 97.934],
+        }
+    }
+
+    pub fn diamond() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030_625, 0.011_236, 0.0],
+        }
+    }
+
+    /// One of the named glasses: bk7, fused-silica or diamond
+    pub fn preset(name: &str) -> Result<Dispersion, String> {
+        match name {
+            "bk7" => Ok(Dispersion::bk7()),
+            "fused-silica" => Ok(Dispersion::fused_silica()),
+            "diamond" => Ok(Dispersion::diamond()),
+            _ => Err(format!("unknown glass '{}'", name)),
+        }
+    }
+
+    /// Index of refraction at `wavelength` nanometres
+    pub fn index(&self, wavelength: f32) -> f32 {
+        let micrometres = wavelength / 1000.0;
+        let squared = micrometres * micrometres;
+        match *self {
+            Dispersion::Cauchy { a, b } => a + b / squared,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f32 = (0..3).map(|i| b[i] * squared / (squared - c[i])).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+}
+
+/// Smooth glass whose index of refraction follows its `dispersion`, so that
+/// prisms and gems split white light into colours when rendered spectrally.
+/// Rays without a wavelength are refracted at `REFERENCE_WAVELENGTH`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DispersiveDielectric {
+    pub dispersion: Dispersion,
+    /// Colour of white light once it has travelled `distance` inside
+    pub transmittance: Vec3,
+    pub distance: f32,
+}
+
+impl DispersiveDielectric {
+    pub fn new(dispersion: Dispersion) -> DispersiveDielectric {
+        DispersiveDielectric {
+            dispersion,
+            transmittance: Vec3::new(1.0, 1.0, 1.0),
+            distance: 1.0,
+        }
+    }
+
+    /// The same glass, absorbing light so that `transmittance` of it is left
+    /// after `distance`
+    pub fn tinted(self, transmittance: Vec3, distance: f32) -> DispersiveDielectric {
+        DispersiveDielectric {
+            transmittance,
+            distance,
+            ..self
+        }
+    }
+
+    /// Glass with this one's index of refraction at `wavelength`
+    pub fn at(&self, wavelength: f32) -> Dielectric {
+        Dielectric::new(self.dispersion.index(wavelength))
+            .tinted(self.transmittance.clone(), self.distance)
+    }
+}
+
+impl Material for DispersiveDielectric {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        attenuation: &mut Vec3,
+        scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        let wavelength = ray.wavelength().unwrap_or(REFERENCE_WAVELENGTH);
+        self.at(wavelength)
+            .scatter(ray, hit_record, attenuation, scattered, sampler)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        true
+    }
+}
+
+/// Frosted glass: a dielectric whose surface is made of GGX microfacets that
+/// each reflect or refract like smooth glass (Walter et al. 2007). Like
+/// `Dielectric`, transmitted light is not scaled by the change in the
+/// squared index of refraction, which cancels out once it leaves again, and
+/// the inside absorbs light the same way, measuring the distance from the
+/// origin of the ray leaving it just as `Dielectric` does.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoughDielectric {
+    /// Index of refraction of the inside, relative to the outside, which the
+    /// normals point into
+    pub index: f32,
+    /// GGX width of the microfacet distribution. Below 0.001 the surface is
+    /// perfectly smooth.
+    pub roughness: f32,
+    /// Colour of white light once it has travelled `distance` inside
+    pub transmittance: Vec3,
+    pub distance: f32,
+}
+
+impl RoughDielectric {
+    pub fn new(index: f32, roughness: f32) -> RoughDielectric {
+        RoughDielectric {
+            index,
+            roughness,
+            transmittance: Vec3::new(1.0, 1.0, 1.0),
+            distance: 1.0,
+        }
+    }
+
+    /// The same glass, absorbing light so that `transmittance` of it is left
+    /// after `distance`
+    pub fn tinted(self, transmittance: Vec3, distance: f32) -> RoughDielectric {
+        RoughDielectric {
+            transmittance,
+            distance,
+            ..self
+        }
+    }
+
+    /// Share of the light arriving along `ray` left after crossing the
+    /// inside, if that is where it comes from
+    fn absorption(&self, ray: &Ray, hit_record: &HitRecord) -> Vec3 {
         if dot(&ray.direction(), &hit_record.normal) > 0.0 {
-            outward_normal = &hit_record.normal * -1.0;
-            ni_nt = self.index;
-            cos = self.index + dot(&ray.direction(), &hit_record.normal) / ray.direction().length();
+            beer_lambert(&self.transmittance, self.distance, ray, hit_record)
+        } else {
+            Vec3::new(1.0, 1.0, 1.0)
+        }
+    }
+
+    /// Frame around the normal on the side `ray` arrives from, the
+    /// direction back along `ray` in it, and the index of refraction on the
+    /// other side relative to that one
+    fn local(&self, ray: &Ray, hit_record: &HitRecord) -> (Frame, Vec3, f32) {
+        let wo = -&unit_vector(&ray.direction());
+        let (normal, eta) = if dot(&wo, &hit_record.normal) < 0.0 {
+            (-&hit_record.normal, 1.0 / self.index)
         } else {
-            outward_normal = hit_record.normal.clone();
-            ni_nt = 1.0 / self.index;
-            cos = -dot(&ray.direction(), &hit_record.normal) / ray.direction().length();
+            (hit_record.normal.clone(), self.index)
+        };
+        let frame = Frame::new(&normal);
+        let wo = frame.to_local(&wo);
+        (frame, wo, eta)
+    }
+
+    /// Microfacet normal that turns `wo` into `wi`, facing `wo`, along with
+    /// whether the turn is a reflection. `None` if no microfacet facing `wo`
+    /// can do it.
+    fn half_vector(wo: &Vec3, wi: &Vec3, eta: f32) -> Option<(Vec3, bool)> {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return None;
         }
-        if refract(&ray.direction(), &outward_normal, ni_nt, &mut refracted) {
-            reflect_prob = shlick(cos, self.index);
+        let reflection = wi.z() > 0.0;
+        let h = if reflection {
+            wo + wi
         } else {
-            *scattered = Ray::new(hit_record.p.clone(), reflected.clone());
-            reflect_prob = 1.0;
+            wo + &(eta * wi)
+        };
+        if h.squared_length() == 0.0 {
+            return None;
+        }
+        let mut h = unit_vector(&h);
+        if h.z() < 0.0 {
+            h = -h;
         }
-        if rng.gen_range::<f32>(0.0, 0.99999) < reflect_prob {
-            *scattered = Ray::new(hit_record.p.clone(), reflected);
+        // Refraction only passes through microfacets from their front
+        if dot(wo, &h) <= 0.0 || (!reflection && dot(wi, &h) >= 0.0) {
+            return None;
+        }
+        Some((h, reflection))
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        attenuation: &mut Vec3,
+        scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        let (frame, wo, eta) = self.local(ray, hit_record);
+        let h = if self.is_specular() {
+            Vec3::new(0.0, 0.0, 1.0)
+        } else {
+            Ggx::new(self.roughness).sample_visible(&wo, sampler.get_2d())
+        };
+        // Picking reflection or refraction by the Fresnel term cancels it
+        // out of the weight, leaving the chance of not being blocked
+        let fresnel = fresnel_dielectric(dot(&wo, &h), eta);
+        let wi = if sampler.get_1d() < fresnel {
+            let wi = reflect_about(&wo, &h);
+            if wi.z() <= 0.0 {
+                return false;
+            }
+            wi
+        } else {
+            match refract_about(&wo, &h, eta) {
+                Some(wi) if wi.z() < 0.0 => wi,
+                _ => return false,
+            }
+        };
+        let weight = if self.is_specular() {
+            1.0
+        } else {
+            let ggx = Ggx::new(self.roughness);
+            ggx.g(&wo, &wi) / ggx.g1(&wo)
+        };
+        *attenuation = weight * self.absorption(ray, hit_record);
+        *scattered = Ray::with_time(hit_record.p.clone(), frame.to_world(&wi), ray.time());
+        true
+    }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> Vec3 {
+        let (frame, wo, eta) = self.local(ray, hit_record);
+        let wi = frame.to_local(&unit_vector(direction));
+        let (h, reflection) = match RoughDielectric::half_vector(&wo, &wi, eta) {
+            Some(half) => half,
+            None => return Vec3::origin(),
+        };
+        let ggx = Ggx::new(self.roughness);
+        let fresnel = fresnel_dielectric(dot(&wo, &h), eta);
+        let f = if reflection {
+            ggx.d(&h) * ggx.g(&wo, &wi) * fresnel / (4.0 * wo.z())
+        } else {
+            let denominator = dot(&wi, &h) + dot(&wo, &h) / eta;
+            ggx.d(&h) * ggx.g(&wo, &wi) * (1.0 - fresnel) * (dot(&wi, &h) * dot(&wo, &h)).abs()
+                / (wo.z() * denominator * denominator)
+        };
+        f * self.absorption(ray, hit_record)
+    }
+
+    fn pdf(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> f32 {
+        let (frame, wo, eta) = self.local(ray, hit_record);
+        let wi = frame.to_local(&unit_vector(direction));
+        let (h, reflection) = match RoughDielectric::half_vector(&wo, &wi, eta) {
+            Some(half) => half,
+            None => return 0.0,
+        };
+        let normal_pdf = Ggx::new(self.roughness).visible_pdf(&wo, &h);
+        let fresnel = fresnel_dielectric(dot(&wo, &h), eta);
+        // Densities of microfacet normals become densities of directions
+        // through the Jacobians of reflection and refraction
+        if reflection {
+            fresnel * normal_pdf / (4.0 * dot(&wo, &h))
         } else {
-            *scattered = Ray::new(hit_record.p.clone(), refracted);
+            let denominator = dot(&wi, &h) + dot(&wo, &h) / eta;
+            (1.0 - fresnel) * normal_pdf * dot(&wi, &h).abs() / (denominator * denominator)
+        }
+    }
+
+    fn is_specular(&self) -> bool {
+        self.roughness < 1e-3
+    }
+}
+
+/// Light-emitting surface that does not scatter incoming light. It shines
+/// only from the side its normal faces: the outside of spheres and boxes, and
+/// the side of quads that `u × v` points to.
+#[derive(Clone)]
+pub struct DiffuseLight {
+    pub emit: Box<dyn Texture>,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Vec3) -> DiffuseLight {
+        DiffuseLight {
+            emit: Box::new(SolidColor::new(emit)),
+        }
+    }
+
+    pub fn textured(emit: Box<dyn Texture>) -> DiffuseLight {
+        DiffuseLight { emit }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(
+        &self,
+        _ray: &Ray,
+        _hit_record: &HitRecord,
+        _attenuation: &mut Vec3,
+        _scattered: &mut Ray,
+        _sampler: &mut dyn Sampler,
+    ) -> bool {
+        false
+    }
+
+    fn emitted(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        self.emit.value(u, v, p)
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+}
+
+/// Phase function of a medium that scatters equally in every direction
+#[derive(Clone)]
+pub struct Isotropic {
+    pub albedo: Box<dyn Texture>,
+}
+
+impl Isotropic {
+    pub fn new(albedo: Vec3) -> Isotropic {
+        Isotropic {
+            albedo: Box::new(SolidColor::new(albedo)),
+        }
+    }
+
+    pub fn textured(albedo: Box<dyn Texture>) -> Isotropic {
+        Isotropic { albedo }
+    }
+}
+
+impl Material for Isotropic {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        attenuation: &mut Vec3,
+        scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        let direction = unit_sphere_direction(sampler.get_2d());
+        *scattered = Ray::with_time(hit_record.p.clone(), direction, ray.time());
+        *attenuation = self
+            .albedo
+            .value(hit_record.u, hit_record.v, &hit_record.p);
+        true
+    }
+
+    fn eval(&self, _ray: &Ray, hit_record: &HitRecord, _direction: &Vec3) -> Vec3 {
+        let albedo = self
+            .albedo
+            .value(hit_record.u, hit_record.v, &hit_record.p);
+        albedo / (4.0 * f32::consts::PI)
+    }
+
+    fn pdf(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: &Vec3) -> f32 {
+        1.0 / (4.0 * f32::consts::PI)
+    }
+
+    fn is_specular(&self) -> bool {
+        false
+    }
+}
+
+/// Density of the Henyey–Greenstein phase function for light turned by an
+/// angle with cosine `cos_theta` from its direction of travel
+pub fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * f32::consts::PI * denominator * denominator.sqrt())
+}
+
+/// Samples a direction from the Henyey–Greenstein phase function around the
+/// unit direction of travel `forward`
+pub fn sample_henyey_greenstein(forward: &Vec3, g: f32, u: (f32, f32)) -> Vec3 {
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u.0
+    } else {
+        let s = (1.0 - g * g) / (1.0 + g - 2.0 * g * u.0);
+        ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * f32::consts::PI * u.1;
+    let (tangent, bitangent) = orthonormal_basis(forward);
+    sin_theta * phi.cos() * tangent + sin_theta * phi.sin() * bitangent + cos_theta * forward
+}
+
+/// Phase function of a medium that scatters mostly forwards (`g > 0`) or
+/// backwards (`g < 0`). A `g` of 0 is the same as `Isotropic`.
+#[derive(Clone)]
+pub struct HenyeyGreenstein {
+    pub albedo: Box<dyn Texture>,
+    /// Mean cosine of the scattering angle, in `(-1, 1)`
+    pub g: f32,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(albedo: Vec3, g: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein::textured(Box::new(SolidColor::new(albedo)), g)
+    }
+
+    pub fn textured(albedo: Box<dyn Texture>, g: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein {
+            albedo,
+            g: g.clamp(-0.999, 0.999),
         }
+    }
+}
+
+impl Material for HenyeyGreenstein {
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        attenuation: &mut Vec3,
+        scattered: &mut Ray,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        let forward = unit_vector(&ray.direction());
+        let direction = sample_henyey_greenstein(&forward, self.g, sampler.get_2d());
+        *scattered = Ray::with_time(hit_record.p.clone(), direction, ray.time());
+        *attenuation = self
+            .albedo
+            .value(hit_record.u, hit_record.v, &hit_record.p);
         true
     }
+
+    fn eval(&self, ray: &Ray, hit_record: &HitRecord, direction: &Vec3) -> Vec3 {
+        let albedo = self
+            .albedo
+            .value(hit_record.u, hit_record.v, &hit_record.p);
+        self.pdf(ray, hit_record, direction) * albedo
+    }
+
+    fn pdf(&self, ray: &Ray, _hit_record: &HitRecord, direction: &Vec3) -> f32 {
+        let cos_theta = dot(&unit_vector(&ray.direction()), &unit_vector(direction));
+        henyey_greenstein(cos_theta, self.g)
+    }
+
+    fn is_specular(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sampler::IndependentSampler;
+    use super::*;
+
+    #[test]
+    fn fuzz_pdf_matches_metal_sampling() {
+        let reflected = unit_vector(&Vec3::new(0.3, 1.0, 0.2));
+        let (tangent, _) = orthonormal_basis(&reflected);
+        let mut sampler = IndependentSampler::new(5);
+        for &fuzz in [0.05f32, 0.3, 0.7, 1.7].iter() {
+            // The density is symmetric about `reflected`, so integrate over
+            // the angle from it, up to the edge of the lobe
+            let theta_max = if fuzz < 1.0 {
+                fuzz.asin()
+            } else {
+                f32::consts::PI
+            };
+            let steps = 4000;
+            let step = theta_max / steps as f32;
+            let integral: f32 = (0..steps)
+                .map(|i| {
+                    let theta = (i as f32 + 0.5) * step;
+                    let direction = theta.sin() * &tangent + theta.cos() * &reflected;
+                    fuzz_pdf(&reflected, fuzz, &direction)
+                        * 2.0
+                        * f32::consts::PI
+                        * theta.sin()
+                        * step
+                })
+                .sum();
+            assert!((integral - 1.0).abs() < 0.01, "fuzz {}: {}", fuzz, integral);
+
+            // Samples drawn the way `Metal` draws them cover the lobe's
+            // solid angle
+            let n = 20000;
+            let coverage: f32 = (0..n)
+                .map(|_| {
+                    let direction = &reflected + &(fuzz * rand_in_unit_sphere(&mut sampler));
+                    1.0 / fuzz_pdf(&reflected, fuzz, &direction)
+                })
+                .sum::<f32>()
+                / n as f32;
+            let expected = 2.0 * f32::consts::PI * (1.0 - theta_max.cos());
+            assert!(
+                (coverage - expected).abs() < 0.05 * expected,
+                "fuzz {}: {}",
+                fuzz,
+                coverage,
+            );
+        }
+    }
+
+    /// A hit at the origin of the xz plane, which the ray from `from` reaches
+    /// at t = 1
+    fn hit_from(from: &Vec3) -> (Ray, HitRecord) {
+        let mut hit_record = HitRecord::new();
+        hit_record.t = 1.0;
+        hit_record.p = Vec3::origin();
+        hit_record.normal = Vec3::new(0.0, 1.0, 0.0);
+        (Ray::new(from.clone(), -from), hit_record)
+    }
+
+    /// Integral of `f` over the directions above the xz plane
+    fn integrate_hemisphere<F: Fn(&Vec3) -> f32>(f: F) -> f32 {
+        let steps = 400;
+        let step = 0.5 * f32::consts::PI / steps as f32;
+        let mut total = 0.0;
+        for i in 0..steps {
+            let theta = (i as f32 + 0.5) * step;
+            for j in 0..4 * steps {
+                let phi = (j as f32 + 0.5) * step;
+                let direction = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                total += f(&direction) * theta.sin() * step * step;
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn diffuse_and_metal_surfaces_work_from_behind() {
+        // Arriving from below the xz plane, whose normal faces up
+        let (ray, hit_record) = hit_from(&Vec3::new(0.3, -0.8, 0.2));
+        let below = Vec3::new(0.1, -1.0, 0.0);
+        let above = Vec3::new(0.1, 1.0, 0.0);
+        let mut sampler = IndependentSampler::new(5);
+        let mut scattered = Ray::new(Vec3::origin(), Vec3::origin());
+        let mut attenuation = Vec3::origin();
+
+        let diffuse = Lambertian::new(Vec3::new(0.5, 0.5, 0.5));
+        assert!(diffuse.eval(&ray, &hit_record, &below).g() > 0.0);
+        assert_eq!(diffuse.eval(&ray, &hit_record, &above), Vec3::origin());
+        assert_eq!(diffuse.pdf(&ray, &hit_record, &above), 0.0);
+        let metal = Metal::new(Vec3::new(0.9, 0.9, 0.9), 0.2);
+        let mirrored = Vec3::new(-0.3, -0.8, -0.2);
+        assert!(metal.eval(&ray, &hit_record, &mirrored).g() > 0.0);
+        assert_eq!(metal.eval(&ray, &hit_record, &above), Vec3::origin());
+        for material in [&diffuse as &dyn Material, &metal].iter() {
+            for _ in 0..100 {
+                if material.scatter(
+                    &ray,
+                    &hit_record,
+                    &mut attenuation,
+                    &mut scattered,
+                    &mut sampler,
+                ) {
+                    assert!(scattered.direction().y < 0.0);
+                }
+            }
+        }
+        // Metal must not absorb everything just for being hit from behind
+        assert!(metal.scatter(
+            &ray,
+            &hit_record,
+            &mut attenuation,
+            &mut scattered,
+            &mut sampler
+        ));
+    }
+
+    #[test]
+    fn conductor_fresnel_matches_known_reflectances() {
+        // At normal incidence ((eta - 1)^2 + k^2) / ((eta + 1)^2 + k^2)
+        let (eta, k) = (0.2f32, 3.9f32);
+        let expected = ((eta - 1.0).powi(2) + k * k) / ((eta + 1.0).powi(2) + k * k);
+        assert!((fresnel_conductor(1.0, eta, k) - expected).abs() < 1e-5);
+        // and everything is reflected at grazing angles
+        assert!((fresnel_conductor(0.0, eta, k) - 1.0).abs() < 1e-5);
+
+        let gold = Conductor::preset("gold", 0.0).unwrap().fresnel(1.0);
+        assert!(gold.r() > 0.9 && gold.r() > gold.g() && gold.g() > gold.b());
+        let silver = Conductor::silver(0.0).fresnel(1.0);
+        assert!(silver.r() > 0.9 && silver.g() > 0.9 && silver.b() > 0.9);
+        assert!(Conductor::preset("tin", 0.1).is_err());
+    }
+
+    #[test]
+    fn conductor_sampling_matches_its_bsdf() {
+        let (ray, hit_record) = hit_from(&Vec3::new(-0.8, 0.6, 0.1));
+        let mut sampler = IndependentSampler::new(13);
+        for &roughness in [0.05f32, 0.3, 0.8].iter() {
+            let gold = Conductor::gold(roughness);
+            let expected = integrate_hemisphere(|d| gold.eval(&ray, &hit_record, d).g());
+            let covered = integrate_hemisphere(|d| gold.pdf(&ray, &hit_record, d));
+
+            let n = 20000;
+            let (mut total, mut kept) = (0.0, 0);
+            let mut scattered = Ray::new(Vec3::origin(), Vec3::origin());
+            let mut attenuation = Vec3::origin();
+            for _ in 0..n {
+                if !gold.scatter(
+                    &ray,
+                    &hit_record,
+                    &mut attenuation,
+                    &mut scattered,
+                    &mut sampler,
+                ) {
+                    continue;
+                }
+                // The weight of a sample is the BSDF over its density
+                let direction = scattered.direction();
+                let f = gold.eval(&ray, &hit_record, &direction).g();
+                let pdf = gold.pdf(&ray, &hit_record, &direction);
+                assert!((attenuation.g() - f / pdf).abs() < 1e-3 * attenuation.g());
+                total += attenuation.g();
+                kept += 1;
+            }
+            let mean = total / n as f32;
+            assert!(
+                (mean - expected).abs() < 0.01 * expected,
+                "roughness {}: {} != {}",
+                roughness,
+                mean,
+                expected
+            );
+            let fraction = kept as f32 / n as f32;
+            assert!(
+                (fraction - covered).abs() < 0.01,
+                "roughness {}: {} != {}",
+                roughness,
+                fraction,
+                covered
+            );
+        }
+    }
+
+    /// Integral of `f` over all directions
+    fn integrate_sphere<F: Fn(&Vec3) -> f32>(f: F) -> f32 {
+        integrate_hemisphere(&f) + integrate_hemisphere(|d| f(&Vec3::new(d.x(), -d.y(), d.z())))
+    }
+
+    #[test]
+    fn rough_dielectric_sampling_matches_its_bsdf() {
+        let mut sampler = IndependentSampler::new(17);
+        let mut scattered = Ray::new(Vec3::origin(), Vec3::origin());
+        let mut attenuation = Vec3::origin();
+        // From outside, and from inside where some light is totally reflected
+        for from in [Vec3::new(-0.8, 0.6, 0.1), Vec3::new(0.7, -0.7, 0.0)].iter() {
+            let (ray, hit_record) = hit_from(from);
+            for &roughness in [0.1f32, 0.5].iter() {
+                let glass = RoughDielectric::new(1.5, roughness);
+                let expected = integrate_sphere(|d| glass.eval(&ray, &hit_record, d).g());
+                let covered = integrate_sphere(|d| glass.pdf(&ray, &hit_record, d));
+
+                let n = 20000;
+                let (mut total, mut kept) = (0.0, 0);
+                for _ in 0..n {
+                    if !glass.scatter(
+                        &ray,
+                        &hit_record,
+                        &mut attenuation,
+                        &mut scattered,
+                        &mut sampler,
+                    ) {
+                        continue;
+                    }
+                    let direction = scattered.direction();
+                    let f = glass.eval(&ray, &hit_record, &direction).g();
+                    let pdf = glass.pdf(&ray, &hit_record, &direction);
+                    assert!((attenuation.g() - f / pdf).abs() < 2e-3 * attenuation.g());
+                    total += attenuation.g();
+                    kept += 1;
+                }
+                let mean = total / n as f32;
+                assert!(
+                    (mean - expected).abs() < 0.01 * expected,
+                    "roughness {}: {} != {}",
+                    roughness,
+                    mean,
+                    expected
+                );
+                let fraction = kept as f32 / n as f32;
+                assert!(
+                    (fraction - covered).abs() < 0.01,
+                    "{} != {}",
+                    fraction,
+                    covered,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rough_dielectric_is_reciprocal() {
+        let glass = RoughDielectric::new(1.5, 0.3);
+        let mut sampler = IndependentSampler::new(19);
+        let mut checked = 0;
+        for _ in 0..2000 {
+            let a = unit_sphere_direction(sampler.get_2d());
+            let b = unit_sphere_direction(sampler.get_2d());
+            // The BSDF itself, without the cosine `eval` includes
+            let f = |from: &Vec3, to: &Vec3| {
+                let (ray, hit_record) = hit_from(from);
+                glass.eval(&ray, &hit_record, to).g() / to.y().abs()
+            };
+            let (forward, backward) = (f(&a, &b), f(&b, &a));
+            if forward < 1e-3 {
+                assert!(backward < 1e-2, "{} vs {}", forward, backward);
+                continue;
+            }
+            // Transmitted light is not rescaled by the indices of refraction,
+            // so the BSDF is only symmetric up to their squared ratio
+            let index = |d: &Vec3| if d.y() > 0.0 { 1.0 } else { glass.index };
+            let ratio = index(&a) * index(&a) / (index(&b) * index(&b));
+            assert!(
+                (forward * ratio - backward).abs() < 2e-3 * backward,
+                "{} * {} != {}",
+                forward,
+                ratio,
+                backward
+            );
+            checked += 1;
+        }
+        assert!(checked > 500);
+    }
+
+    #[test]
+    fn rough_dielectric_approaches_smooth_glass() {
+        let smooth = Dielectric::new(1.5);
+        let rough = RoughDielectric::new(1.5, 0.002);
+        let mut sampler = IndependentSampler::new(23);
+        let mut scattered = Ray::new(Vec3::origin(), Vec3::origin());
+        let mut attenuation = Vec3::origin();
+        for &angle in [0.0f32, 30.0, 60.0, 80.0].iter() {
+            let angle = angle.to_radians();
+            let from = Vec3::new(-angle.sin(), angle.cos(), 0.0);
+            let (ray, hit_record) = hit_from(&from);
+            let n = 20000;
+            let mut reflected = [0, 0];
+            let mut absorbed = [0, 0];
+            let mut directions = [Vec3::origin(), Vec3::origin()];
+            for (i, glass) in [&smooth as &dyn Material, &rough].iter().enumerate() {
+                for _ in 0..n {
+                    if !glass.scatter(
+                        &ray,
+                        &hit_record,
+                        &mut attenuation,
+                        &mut scattered,
+                        &mut sampler,
+                    ) {
+                        absorbed[i] += 1;
+                        continue;
+                    }
+                    assert!((attenuation.g() - 1.0).abs() < 1e-2);
+                    let direction = unit_vector(&scattered.direction());
+                    if direction.y() > 0.0 {
+                        reflected[i] += 1;
+                    } else if i == 0 {
+                        directions[0] = direction;
+                    } else {
+                        directions[1] += direction;
+                    }
+                }
+            }
+            // Only the rare reflection off a steep microfacet at a grazing
+            // angle is lost below the surface
+            assert_eq!(absorbed[0], 0);
+            assert!(absorbed[1] < n / 200, "{}", absorbed[1]);
+            // Both send light the same ways in the same proportions
+            let fractions = reflected.map(|count| count as f32 / n as f32);
+            let fresnel = fresnel_dielectric(angle.cos(), 1.5);
+            assert!((fractions[0] - fresnel).abs() < 0.01, "{:?}", fractions);
+            assert!((fractions[1] - fresnel).abs() < 0.01, "{:?}", fractions);
+            let mean = unit_vector(&directions[1]);
+            assert!(dot(&mean, &directions[0]) > 0.9999);
+
+            // Sent back the way it refracted, light leaves along the way it
+            // came in, in the same proportion
+            let (back, hit_record) = hit_from(&mean);
+            for glass in [&smooth as &dyn Material, &rough].iter() {
+                let mut transmitted = 0;
+                let mut direction = Vec3::origin();
+                for _ in 0..n {
+                    if glass.scatter(
+                        &back,
+                        &hit_record,
+                        &mut attenuation,
+                        &mut scattered,
+                        &mut sampler,
+                    ) && scattered.direction().y() > 0.0
+                    {
+                        direction += unit_vector(&scattered.direction());
+                        transmitted += 1;
+                    }
+                }
+                let fraction = transmitted as f32 / n as f32;
+                assert!(
+                    (fraction - (1.0 - fresnel)).abs() < 0.015,
+                    "{} != {}",
+                    fraction,
+                    1.0 - fresnel
+                );
+                assert!(dot(&unit_vector(&direction), &from) > 0.9999);
+            }
+        }
+    }
+
+    #[test]
+    fn dielectric_splits_light_without_losing_any() {
+        let glass = Dielectric::new(1.5);
+        let mut sampler = IndependentSampler::new(29);
+        let mut scattered = Ray::new(Vec3::origin(), Vec3::origin());
+        let mut attenuation = Vec3::origin();
+        let critical = (1.0f32 / 1.5).asin();
+        for &degrees in [0.0f32, 20.0, 40.0, 45.0, 70.0].iter() {
+            let angle = degrees.to_radians();
+            // Light arriving from outside, and from inside
+            for &side in [1.0f32, -1.0].iter() {
+                let from = Vec3::new(-angle.sin(), side * angle.cos(), 0.0);
+                let (ray, hit_record) = hit_from(&from);
+                let eta = if side > 0.0 { 1.5 } else { 1.0 / 1.5 };
+                let n = 20000;
+                let mut reflected = 0;
+                for _ in 0..n {
+                    assert!(glass.scatter(
+                        &ray,
+                        &hit_record,
+                        &mut attenuation,
+                        &mut scattered,
+                        &mut sampler
+                    ));
+                    // Nothing is absorbed, whichever way the light goes
+                    assert_eq!(attenuation, Vec3::new(1.0, 1.0, 1.0));
+                    let direction = unit_vector(&scattered.direction());
+                    if direction.y() * side > 0.0 {
+                        assert!((direction.x() - angle.sin()).abs() < 1e-4);
+                        reflected += 1;
+                    } else {
+                        // Snell's law
+                        assert!((eta * direction.x() - angle.sin()).abs() < 1e-4);
+                    }
+                }
+                let fraction = reflected as f32 / n as f32;
+                let expected = fresnel_dielectric(angle.cos(), eta);
+                assert!(
+                    (fraction - expected).abs() < 0.01,
+                    "{} degrees, side {}: {} != {}",
+                    degrees,
+                    side,
+                    fraction,
+                    expected
+                );
+                if side < 0.0 && angle > critical {
+                    assert_eq!(reflected, n);
+                }
+            }
+        }
+        // Light crossing the surface either way along the same line is
+        // reflected equally: here at 30 degrees outside
+        let cos_i = 0.75f32.sqrt();
+        let cos_t = (1.0 - (0.5f32 / 1.5).powi(2)).sqrt();
+        let outside = fresnel_dielectric(cos_i, 1.5);
+        let inside = fresnel_dielectric(cos_t, 1.0 / 1.5);
+        assert!((outside - inside).abs() < 1e-5, "{} != {}", outside, inside);
+    }
+
+    #[test]
+    fn dielectric_absorbs_light_crossing_its_inside() {
+        let glass = Dielectric::new(1.5).tinted(Vec3::new(0.5, 1.0, 0.25), 2.0);
+        let mut sampler = IndependentSampler::new(31);
+        let mut attenuation_from = |from: &Vec3| {
+            let (ray, hit_record) = hit_from(from);
+            let mut scattered = Ray::new(Vec3::origin(), Vec3::origin());
+            let mut attenuation = Vec3::origin();
+            glass.scatter(
+                &ray,
+                &hit_record,
+                &mut attenuation,
+                &mut scattered,
+                &mut sampler,
+            );
+            attenuation
+        };
+        // Light leaving after 3 units inside keeps 0.5^1.5 of its red,
+        // whether it is reflected back in or not
+        let expected = Vec3::new(0.5f32.powf(1.5), 1.0, 0.25f32.powf(1.5));
+        for _ in 0..100 {
+            let attenuation = attenuation_from(&Vec3::new(0.0, -3.0, 0.0));
+            assert!((&attenuation - &expected).length() < 1e-5);
+        }
+        // and light entering has crossed nothing yet
+        let attenuation = attenuation_from(&Vec3::new(0.0, 3.0, 0.0));
+        assert_eq!(attenuation, Vec3::new(1.0, 1.0, 1.0));
+
+        let rough = RoughDielectric::new(1.5, 0.3).tinted(glass.transmittance.clone(), 2.0);
+        let (ray, hit_record) = hit_from(&Vec3::new(0.0, -3.0, 0.0));
+        let direction = Vec3::new(0.1, 1.0, 0.0);
+        let f = rough.eval(&ray, &hit_record, &direction);
+        assert!((f.b() / f.g() - 0.25f32.powf(1.5)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn dispersion_matches_published_indices() {
+        // N-BK7 at the hydrogen F, helium d and hydrogen C lines
+        let bk7 = Dispersion::bk7();
+        assert!((bk7.index(486.1) - 1.5224).abs() < 1e-4);
+        assert!((bk7.index(587.6) - 1.5168).abs() < 1e-4);
+        assert!((bk7.index(656.3) - 1.5143).abs() < 1e-4);
+        assert!((Dispersion::fused_silica().index(587.6) - 1.4585).abs() < 1e-4);
+        assert!((Dispersion::diamond().index(589.3) - 2.417).abs() < 1e-3);
+        let cauchy = Dispersion::Cauchy { a: 1.5, b: 0.004 };
+        assert!((cauchy.index(500.0) - 1.516).abs() < 1e-5);
+        assert_eq!(Dispersion::preset("diamond"), Ok(Dispersion::diamond()));
+        assert!(Dispersion::preset("crystal").is_err());
+    }
+
+    #[test]
+    fn dispersive_glass_bends_blue_more_than_red() {
+        let prism = DispersiveDielectric::new(Dispersion::diamond());
+        let angle = 40f32.to_radians();
+        let (ray, hit_record) = hit_from(&Vec3::new(-angle.sin(), angle.cos(), 0.0));
+        let mut sampler = IndependentSampler::new(31);
+        let mut scattered = Ray::new(Vec3::origin(), Vec3::origin());
+        let mut attenuation = Vec3::origin();
+        // Sine of the angle of refraction at each wavelength, from Snell's
+        // law; RGB rays see the index at the reference wavelength
+        let mut refract = |wavelength: Option<f32>| {
+            let ray = match wavelength {
+                Some(wavelength) => ray.clone().with_wavelength(wavelength),
+                None => ray.clone(),
+            };
+            let index = prism
+                .dispersion
+                .index(wavelength.unwrap_or(REFERENCE_WAVELENGTH));
+            for _ in 0..100 {
+                prism.scatter(
+                    &ray,
+                    &hit_record,
+                    &mut attenuation,
+                    &mut scattered,
+                    &mut sampler,
+                );
+                let direction = unit_vector(&scattered.direction());
+                if direction.y() < 0.0 {
+                    assert!((index * direction.x() - angle.sin()).abs() < 1e-4);
+                    return direction.x();
+                }
+            }
+            panic!("no ray refracted");
+        };
+        let blue = refract(Some(450.0));
+        let red = refract(Some(650.0));
+        assert!(blue < red - 0.003, "{} vs {}", blue, red);
+        refract(None);
+        assert!(prism.is_dispersive() && !Dielectric::new(1.5).is_dispersive());
+    }
+
+    #[test]
+    fn henyey_greenstein_sampling_matches_its_density() {
+        let forward = unit_vector(&Vec3::new(1.0, 2.0, -0.5));
+        let mut sampler = IndependentSampler::new(7);
+        for &g in [-0.6f32, 0.0, 0.3, 0.85].iter() {
+            // The density integrates to one over the sphere
+            let steps = 20000;
+            let integral: f32 = (0..steps)
+                .map(|i| {
+                    let cos = -1.0 + 2.0 * (i as f32 + 0.5) / steps as f32;
+                    henyey_greenstein(cos, g) * 2.0 * f32::consts::PI * 2.0 / steps as f32
+                })
+                .sum();
+            assert!((integral - 1.0).abs() < 1e-2, "g = {}: {}", g, integral);
+            // and the mean cosine of the sampled directions is g
+            let n = 20000;
+            let mean: f32 = (0..n)
+                .map(|_| {
+                    let direction = sample_henyey_greenstein(&forward, g, sampler.get_2d());
+                    assert!((direction.length() - 1.0).abs() < 1e-4);
+                    dot(&direction, &forward)
+                })
+                .sum::<f32>()
+                / n as f32;
+            assert!((mean - g).abs() < 0.02, "g = {}: mean {}", g, mean);
+        }
+    }
 }
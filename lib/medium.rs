@@ -0,0 +1,260 @@
+//! Participating media: fog, smoke and other volumes that scatter light
+//! inside them rather than at a surface.
+//!
+//! How far a ray gets through a medium is decided by the sample it carries,
+//! which the renderer draws from its sampler for every ray it traces. Rays
+//! traced without one fall back to a hash of the ray itself. Either is hashed
+//! together with where the ray first crosses the medium's boundary, so that
+//! media along the same ray scatter independently of each other, at the cost
+//! of the sample's stratification.
+
+use std::f32;
+
+use super::aabb::Aabb;
+use super::materials::Material;
+use super::rng::mix;
+use super::{Hit, HitRecord, Ray, Vec3};
+
+/// Gap left between a boundary crossing and the search for the next one
+const EPSILON: f32 = 1e-4;
+
+/// Hash of the ray, for rays that carry no medium sample
+fn ray_hash(ray: &Ray, t_min: f32) -> u64 {
+    let origin = ray.origin();
+    let direction = ray.direction();
+    let words = [
+        origin.x,
+        origin.y,
+        origin.z,
+        direction.x,
+        direction.y,
+        direction.z,
+        ray.time(),
+        t_min,
+    ];
+    words
+        .iter()
+        .fold(0u64, |hash, word| mix(hash ^ u64::from(word.to_bits())))
+}
+
+/// Uniform number in `(0, 1]` deciding how far `ray` gets through a medium
+/// whose boundary it first crosses at `entry`
+fn free_flight_unit(ray: &Ray, t_min: f32, entry: f32) -> f32 {
+    let seed = match ray.medium_sample() {
+        Some(u) => mix(u64::from(u.to_bits())),
+        None => ray_hash(ray, t_min),
+    };
+    let hash = mix(seed ^ u64::from(entry.to_bits()));
+    ((hash >> 40) + 1) as f32 * (1.0 / (1u64 << 24) as f32)
+}
+
+/// Volume of constant density filling the inside of `boundary`, which must
+/// be closed but need not be convex. Rays travel an exponentially distributed
+/// distance through it before they scatter by the phase function `phase`,
+/// such as `Isotropic` or `HenyeyGreenstein`.
+pub struct ConstantMedium<H: Hit> {
+    pub boundary: H,
+    /// Chance of scattering per unit of distance
+    pub density: f32,
+    phase: Box<dyn Material>,
+}
+
+impl<H: Hit> ConstantMedium<H> {
+    pub fn new(boundary: H, density: f32, phase: Box<dyn Material>) -> ConstantMedium<H> {
+        ConstantMedium {
+            boundary,
+            density,
+            phase,
+        }
+    }
+}
+
+impl<H: Hit> Hit for ConstantMedium<H> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let speed = ray.direction().length();
+        // Distance still to travel before scattering, drawn at the first
+        // crossing
+        let mut remaining = None;
+        let mut entry_record = HitRecord::new();
+        let mut exit_record = HitRecord::new();
+        let mut search_from = f32::NEG_INFINITY;
+        // Crossings alternate between entering and leaving the boundary, so
+        // each pair found from minus infinity encloses one span of the inside
+        loop {
+            if !self
+                .boundary
+                .hit(ray, search_from, f32::INFINITY, &mut entry_record)
+            {
+                return false;
+            }
+            if !self.boundary.hit(
+                ray,
+                entry_record.t + EPSILON,
+                f32::INFINITY,
+                &mut exit_record,
+            ) {
+                return false;
+            }
+            let remaining = remaining.get_or_insert_with(|| {
+                -free_flight_unit(ray, t_min, entry_record.t).ln() / self.density
+            });
+            let start = entry_record.t.max(t_min);
+            let end = exit_record.t.min(t_max);
+            if start >= t_max {
+                return false;
+            }
+            if start < end {
+                let inside = (end - start) * speed;
+                if *remaining < inside {
+                    let t = start + *remaining / speed;
+                    hit_record.t = t;
+                    hit_record.p = ray.point_at_param(t);
+                    // Phase functions do not use the normal
+                    hit_record.normal = Vec3::new(1.0, 0.0, 0.0);
+                    hit_record.u = 0.0;
+                    hit_record.v = 0.0;
+                    hit_record.mat = Some(self.phase.clone_box());
+                    return true;
+                }
+                *remaining -= inside;
+            }
+            search_from = exit_record.t + EPSILON;
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.boundary.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::materials::{Isotropic, Lambertian};
+    use super::super::shapes::Cuboid;
+    use super::super::vec::Transform;
+    use super::super::{HitList, Sphere, Transformed};
+    use super::*;
+
+    fn grey() -> Box<dyn Material> {
+        Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))
+    }
+
+    /// Fraction of rays along +x, with evenly spread medium samples, that
+    /// pass through `medium` without scattering
+    fn transmitted<H: Hit>(medium: &ConstantMedium<H>, y: f32) -> f32 {
+        let n = 20000;
+        let mut hit_record = HitRecord::new();
+        let passed = (0..n)
+            .filter(|&i| {
+                let ray = Ray::new(Vec3::new(-10.0, y, 0.0), Vec3::new(2.0, 0.0, 0.0))
+                    .with_medium_sample((i as f32 + 0.5) / n as f32);
+                !medium.hit(&ray, 0.001, f32::MAX, &mut hit_record)
+            })
+            .count();
+        passed as f32 / n as f32
+    }
+
+    #[test]
+    fn transmittance_follows_beer_lambert() {
+        let phase = Box::new(Isotropic::new(Vec3::new(1.0, 1.0, 1.0)));
+        let fog = ConstantMedium::new(
+            Cuboid::new(
+                &Vec3::new(-1.0, -1.0, -1.0),
+                &Vec3::new(1.0, 1.0, 1.0),
+                grey(),
+            ),
+            0.5,
+            phase.clone(),
+        );
+        let expected = (-0.5f32 * 2.0).exp();
+        assert!((transmitted(&fog, 0.0) - expected).abs() < 0.015);
+
+        // Two separate spheres count as one non-convex boundary, 4 units thick
+        let mut spheres = HitList::new(2);
+        spheres
+            .list
+            .push(Sphere::new(Vec3::new(-2.0, 0.0, 0.0), 1.0, grey()));
+        spheres
+            .list
+            .push(Sphere::new(Vec3::new(2.0, 0.0, 0.0), 1.0, grey()));
+        let smoke = ConstantMedium::new(spheres, 0.25, phase);
+        let expected = (-0.25f32 * 4.0).exp();
+        assert!((transmitted(&smoke, 0.0) - expected).abs() < 0.015);
+        assert_eq!(transmitted(&smoke, 1.5), 1.0);
+    }
+
+    #[test]
+    fn separate_media_scatter_independently() {
+        let phase = Box::new(Isotropic::new(Vec3::new(1.0, 1.0, 1.0)));
+        let block = |x: f32, width: f32, density: f32| {
+            Box::new(ConstantMedium::new(
+                Cuboid::new(
+                    &Vec3::new(x, -1.0, -1.0),
+                    &Vec3::new(x + width, 1.0, 1.0),
+                    grey(),
+                ),
+                density,
+                phase.clone(),
+            )) as Box<dyn Hit>
+        };
+        let mut blocks = HitList::new(2);
+        blocks.list.push(block(-4.0, 2.0, 0.5));
+        blocks.list.push(block(1.0, 3.0, 0.2));
+        let n = 20000;
+        let mut hit_record = HitRecord::new();
+        let passed = (0..n)
+            .filter(|&i| {
+                let ray = Ray::new(Vec3::new(-10.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0))
+                    .with_medium_sample((i as f32 + 0.5) / n as f32);
+                !blocks.hit(&ray, 0.001, f32::MAX, &mut hit_record)
+            })
+            .count();
+        let expected = (-0.5f32 * 2.0 - 0.2 * 3.0).exp();
+        let transmitted = passed as f32 / n as f32;
+        assert!((transmitted - expected).abs() < 0.015, "{}", transmitted);
+    }
+
+    #[test]
+    fn transformed_media_keep_the_ray_sample() {
+        let fog = || {
+            ConstantMedium::new(
+                Sphere::new(Vec3::origin(), 1.0, grey()),
+                0.5,
+                Box::new(Isotropic::new(Vec3::new(1.0, 1.0, 1.0))),
+            )
+        };
+        let moved = Transformed::new(fog(), Transform::translate(&Vec3::new(0.0, 3.0, 0.0)));
+        let mut here = HitRecord::new();
+        let mut there = HitRecord::new();
+        for i in 0..100 {
+            let u = i as f32 / 100.0;
+            let ray = Ray::new(Vec3::new(-2.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+            let shifted = Ray::new(Vec3::new(-2.0, 3.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+            let hit = fog().hit(&ray.with_medium_sample(u), 0.001, f32::MAX, &mut here);
+            let moved_hit = moved.hit(&shifted.with_medium_sample(u), 0.001, f32::MAX, &mut there);
+            assert_eq!(hit, moved_hit);
+            if hit {
+                assert!((here.t - there.t).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn scattering_happens_inside_the_boundary() {
+        let medium = ConstantMedium::new(
+            Sphere::new(Vec3::origin(), 1.0, grey()),
+            2.0,
+            Box::new(Isotropic::new(Vec3::new(1.0, 1.0, 1.0))),
+        );
+        let mut hit_record = HitRecord::new();
+        for i in 0..1000 {
+            // Rays starting inside the sphere as well as outside it
+            let x = -3.0 + i as f32 * 0.003;
+            let ray = Ray::new(Vec3::new(x, 0.1, 0.0), Vec3::new(1.0, 0.0, 0.0));
+            if medium.hit(&ray, 0.001, f32::MAX, &mut hit_record) {
+                assert!(hit_record.p.length() <= 1.0 + 1e-4);
+                assert!(hit_record.t > 0.001);
+            }
+        }
+    }
+}
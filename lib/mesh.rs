@@ -0,0 +1,307 @@
+use std::sync::Arc;
+
+use super::aabb::Aabb;
+use super::bvh::Bvh;
+use super::materials::Material;
+use super::{cross, dot, unit_vector, Hit, HitRecord, Ray, Vec3};
+
+/// Möller–Trumbore ray/triangle intersection. Returns the ray parameter and
+/// the barycentric coordinates of the hit relative to `p1` and `p2`.
+pub fn intersect_triangle(
+    p0: &Vec3,
+    p1: &Vec3,
+    p2: &Vec3,
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(f32, f32, f32)> {
+    let direction = ray.direction();
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let pvec = cross(&direction, &e2);
+    let det = dot(&e1, &pvec);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let tvec = &ray.origin() - p0;
+    let b1 = dot(&tvec, &pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let qvec = cross(&tvec, &e1);
+    let b2 = dot(&direction, &qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    let t = dot(&e2, &qvec) * inv_det;
+    if t < t_max && t > t_min {
+        Some((t, b1, b2))
+    } else {
+        None
+    }
+}
+
+#[inline(always)]
+fn interpolate(values: [&Vec3; 3], b1: f32, b2: f32) -> Vec3 {
+    (1.0 - b1 - b2) * values[0] + b1 * values[1] + b2 * values[2]
+}
+
+#[inline(always)]
+fn interpolate_uv(uvs: [(f32, f32); 3], b1: f32, b2: f32) -> (f32, f32) {
+    let b0 = 1.0 - b1 - b2;
+    (
+        b0 * uvs[0].0 + b1 * uvs[1].0 + b2 * uvs[2].0,
+        b0 * uvs[0].1 + b1 * uvs[1].1 + b2 * uvs[2].1,
+    )
+}
+
+fn triangle_bounds(p0: &Vec3, p1: &Vec3, p2: &Vec3) -> Aabb {
+    // Pad flat boxes so axis-aligned triangles still have a volume to hit
+    let pad = Vec3::new(1e-4, 1e-4, 1e-4);
+    let min = p0.min(p1).min(p2);
+    let max = p0.max(p1).max(p2);
+    Aabb::new(min - pad.clone(), max + pad)
+}
+
+/// A single free-standing triangle. Without per-vertex normals the face is
+/// flat shaded, and without per-vertex UVs the barycentric coordinates are
+/// used as texture coordinates.
+#[derive(Clone)]
+pub struct Triangle {
+    pub vertices: [Vec3; 3],
+    pub normals: Option<[Vec3; 3]>,
+    pub uvs: Option<[(f32, f32); 3]>,
+    material: Box<dyn Material>,
+}
+
+impl Triangle {
+    pub fn new(a: Vec3, b: Vec3, c: Vec3, mat: Box<dyn Material>) -> Triangle {
+        Triangle {
+            vertices: [a, b, c],
+            normals: None,
+            uvs: None,
+            material: mat,
+        }
+    }
+}
+
+impl Hit for Triangle {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let [ref p0, ref p1, ref p2] = self.vertices;
+        match intersect_triangle(p0, p1, p2, ray, t_min, t_max) {
+            Some((t, b1, b2)) => {
+                hit_record.t = t;
+                hit_record.p = ray.point_at_param(t);
+                hit_record.normal = match self.normals {
+                    Some(ref n) => unit_vector(&interpolate([&n[0], &n[1], &n[2]], b1, b2)),
+                    None => unit_vector(&cross(&(p1 - p0), &(p2 - p0))),
+                };
+                let (u, v) = match self.uvs {
+                    Some(uvs) => interpolate_uv(uvs, b1, b2),
+                    None => (b1, b2),
+                };
+                hit_record.u = u;
+                hit_record.v = v;
+                hit_record.mat = Some(self.material.clone_box());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let [ref p0, ref p1, ref p2] = self.vertices;
+        Some(triangle_bounds(p0, p1, p2))
+    }
+}
+
+/// One triangle of a `MeshData`. Each attribute is indexed separately, as in
+/// most interchange formats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Face {
+    pub vertices: [usize; 3],
+    pub normals: Option<[usize; 3]>,
+    pub uvs: Option<[usize; 3]>,
+    /// Index into `MeshData::materials`
+    pub material: usize,
+}
+
+impl Face {
+    pub fn new(vertices: [usize; 3]) -> Face {
+        Face {
+            vertices,
+            normals: None,
+            uvs: None,
+            material: 0,
+        }
+    }
+}
+
+/// Vertex, normal and UV buffers shared by every triangle in a mesh
+pub struct MeshData {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f32, f32)>,
+    pub faces: Vec<Face>,
+    pub materials: Vec<Box<dyn Material>>,
+}
+
+impl MeshData {
+    pub fn new(positions: Vec<Vec3>, faces: Vec<Face>, mat: Box<dyn Material>) -> MeshData {
+        MeshData {
+            positions,
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            faces,
+            materials: vec![mat],
+        }
+    }
+}
+
+struct MeshTriangle {
+    mesh: Arc<MeshData>,
+    face: usize,
+}
+
+impl MeshTriangle {
+    #[inline(always)]
+    fn positions(&self) -> [&Vec3; 3] {
+        let v = self.mesh.faces[self.face].vertices;
+        let positions = &self.mesh.positions;
+        [&positions[v[0]], &positions[v[1]], &positions[v[2]]]
+    }
+}
+
+impl Hit for MeshTriangle {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let [p0, p1, p2] = self.positions();
+        match intersect_triangle(p0, p1, p2, ray, t_min, t_max) {
+            Some((t, b1, b2)) => {
+                let face = &self.mesh.faces[self.face];
+                hit_record.t = t;
+                hit_record.p = ray.point_at_param(t);
+                hit_record.normal = match face.normals {
+                    Some(n) => {
+                        let normals = &self.mesh.normals;
+                        let values = [&normals[n[0]], &normals[n[1]], &normals[n[2]]];
+                        unit_vector(&interpolate(values, b1, b2))
+                    }
+                    None => unit_vector(&cross(&(p1 - p0), &(p2 - p0))),
+                };
+                let (u, v) = match face.uvs {
+                    Some(i) => {
+                        let uvs = &self.mesh.uvs;
+                        interpolate_uv([uvs[i[0]], uvs[i[1]], uvs[i[2]]], b1, b2)
+                    }
+                    None => (b1, b2),
+                };
+                hit_record.u = u;
+                hit_record.v = v;
+                hit_record.mat = Some(self.mesh.materials[face.material].clone_box());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let [p0, p1, p2] = self.positions();
+        Some(triangle_bounds(p0, p1, p2))
+    }
+}
+
+/// Indexed triangle mesh with its own bounding volume hierarchy
+pub struct TriangleMesh {
+    data: Arc<MeshData>,
+    bvh: Bvh<MeshTriangle>,
+}
+
+impl TriangleMesh {
+    pub fn new(data: MeshData) -> TriangleMesh {
+        let data = Arc::new(data);
+        let triangles = (0..data.faces.len())
+            .map(|face| MeshTriangle {
+                mesh: data.clone(),
+                face,
+            })
+            .collect();
+        TriangleMesh {
+            bvh: Bvh::new(triangles),
+            data,
+        }
+    }
+
+    pub fn data(&self) -> &MeshData {
+        &self.data
+    }
+}
+
+impl Hit for TriangleMesh {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        self.bvh.hit(ray, t_min, t_max, hit_record)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::materials::Lambertian;
+    use super::*;
+
+    fn grey() -> Box<dyn Material> {
+        Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))
+    }
+
+    #[test]
+    fn triangle_barycentrics() {
+        let triangle = Triangle::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            grey(),
+        );
+        let ray = Ray::new(Vec3::new(0.25, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut hit_record = HitRecord::new();
+        assert!(triangle.hit(&ray, 0.001, 10.0, &mut hit_record));
+        assert_eq!(hit_record.t, 1.0);
+        assert_eq!((hit_record.u, hit_record.v), (0.25, 0.5));
+        assert_eq!(hit_record.normal, Vec3::new(0.0, 0.0, 1.0));
+
+        let miss = Ray::new(Vec3::new(0.75, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(!triangle.hit(&miss, 0.001, 10.0, &mut hit_record));
+    }
+
+    #[test]
+    fn mesh_interpolates_normals_and_uvs() {
+        let mut data = MeshData::new(
+            vec![
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(1.0, 1.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+            ],
+            vec![Face::new([0, 1, 2]), Face::new([0, 2, 3])],
+            grey(),
+        );
+        data.normals = vec![Vec3::new(0.0, 0.0, 1.0), Vec3::new(1.0, 0.0, 0.0)];
+        data.uvs = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        for face in data.faces.iter_mut() {
+            face.normals = Some([0, 1, 0]);
+            face.uvs = Some(face.vertices);
+        }
+        let mesh = TriangleMesh::new(data);
+
+        let ray = Ray::new(Vec3::new(0.25, 0.75, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut hit_record = HitRecord::new();
+        assert!(mesh.hit(&ray, 0.001, 10.0, &mut hit_record));
+        assert!((hit_record.u - 0.25).abs() < 1e-6);
+        assert!((hit_record.v - 0.75).abs() < 1e-6);
+        // The middle vertex of the second face carries the tilted normal
+        let expected = unit_vector(&Vec3::new(0.25, 0.0, 0.75));
+        assert!((&hit_record.normal - &expected).length() < 1e-6);
+    }
+}
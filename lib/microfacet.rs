@@ -0,0 +1,228 @@
+//! Microfacet models of rough surfaces: the GGX (Trowbridge–Reitz)
+//! distribution of microfacet normals, Smith masking-shadowing and sampling
+//! of the normals a direction can see.
+//!
+//! Everything here works in a local frame whose z axis is the macroscopic
+//! surface normal.
+
+use std::f32;
+
+use super::{cross, dot, orthonormal_basis, unit_vector, Vec3};
+
+/// An orthonormal frame around a surface normal, for moving directions in
+/// and out of the local frame of a microfacet model
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+    pub normal: Vec3,
+}
+
+impl Frame {
+    /// Frame around the unit vector `normal`
+    pub fn new(normal: &Vec3) -> Frame {
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        Frame {
+            tangent,
+            bitangent,
+            normal: normal.clone(),
+        }
+    }
+
+    pub fn to_local(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            dot(v, &self.tangent),
+            dot(v, &self.bitangent),
+            dot(v, &self.normal),
+        )
+    }
+
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        v.x() * &self.tangent + v.y() * &self.bitangent + v.z() * &self.normal
+    }
+}
+
+/// Isotropic GGX distribution of microfacet normals
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ggx {
+    /// Width of the distribution: 0 is a mirror, 1 is very rough
+    pub alpha: f32,
+}
+
+impl Ggx {
+    pub fn new(alpha: f32) -> Ggx {
+        Ggx { alpha }
+    }
+
+    /// Density of microfacet normals `h` per unit of solid angle, scaled so
+    /// that their projected area adds up to one
+    pub fn d(&self, h: &Vec3) -> f32 {
+        if h.z() <= 0.0 {
+            return 0.0;
+        }
+        let alpha_squared = self.alpha * self.alpha;
+        let cos_squared = h.z() * h.z();
+        let denominator = cos_squared * (alpha_squared - 1.0) + 1.0;
+        alpha_squared / (f32::consts::PI * denominator * denominator)
+    }
+
+    /// Smith's auxiliary function for the unit direction `w`
+    pub fn lambda(&self, w: &Vec3) -> f32 {
+        let cos_squared = w.z() * w.z();
+        if cos_squared == 0.0 {
+            return f32::INFINITY;
+        }
+        let tan_squared = (1.0 - cos_squared).max(0.0) / cos_squared;
+        0.5 * ((1.0 + self.alpha * self.alpha * tan_squared).sqrt() - 1.0)
+    }
+
+    /// Fraction of the microfacets facing `w` that are not hidden from it by
+    /// others
+    pub fn g1(&self, w: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Fraction of microfacets visible from both `wo` and `wi`, accounting
+    /// for the correlation between the two through the facets' heights
+    pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Distribution of the normals seen from the unit direction `wo` above
+    /// the surface, in proportion to their projected area
+    pub fn visible_pdf(&self, wo: &Vec3, h: &Vec3) -> f32 {
+        if wo.z() <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * dot(wo, h).max(0.0) * self.d(h) / wo.z()
+    }
+
+    /// Samples a normal from `visible_pdf` for the unit direction `wo` above
+    /// the surface (Heitz 2018)
+    pub fn sample_visible(&self, wo: &Vec3, u: (f32, f32)) -> Vec3 {
+        // Stretch the view into the frame where the distribution is a
+        // hemisphere, and sample the half disk it projects to
+        let view = unit_vector(&Vec3::new(self.alpha * wo.x(), self.alpha * wo.y(), wo.z()));
+        let length_squared = view.x() * view.x() + view.y() * view.y();
+        let t1 = if length_squared > 0.0 {
+            Vec3::new(-view.y(), view.x(), 0.0) / length_squared.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = cross(&view, &t1);
+        let r = u.0.sqrt();
+        let phi = 2.0 * f32::consts::PI * u.1;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + view.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let p3 = (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        let normal = p1 * t1 + p2 * t2 + p3 * view;
+        unit_vector(&Vec3::new(
+            self.alpha * normal.x(),
+            self.alpha * normal.y(),
+            normal.z().max(1e-6),
+        ))
+    }
+}
+
+/// Mirror image of the unit direction `wo` about the normal `h`
+#[inline(always)]
+pub fn reflect_about(wo: &Vec3, h: &Vec3) -> Vec3 {
+    &(2.0 * dot(wo, h) * h) - wo
+}
+
+/// Direction in which the unit direction `wo` refracts through a microfacet
+/// with normal `h` on the same side, into a medium whose index of refraction
+/// is `eta` times that on the side of `wo`. `None` if it is totally
+/// internally reflected instead.
+#[inline(always)]
+pub fn refract_about(wo: &Vec3, h: &Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = dot(wo, h);
+    let sin_squared_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin_squared_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin_squared_t).sqrt();
+    Some(&((cos_i / eta - cos_t) * h) - &(wo / eta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sampler::{IndependentSampler, Sampler};
+    use super::*;
+
+    #[test]
+    fn projected_microfacet_area_is_one() {
+        for &alpha in [0.05f32, 0.3, 0.8].iter() {
+            let ggx = Ggx::new(alpha);
+            // The integral of D(h) cos over the hemisphere, in cos^2 so that
+            // the narrow peak of smooth surfaces is resolved
+            let steps = 20000;
+            let integral: f32 = (0..steps)
+                .map(|i| {
+                    let cos_squared = (i as f32 + 0.5) / steps as f32;
+                    let h = Vec3::new((1.0 - cos_squared).sqrt(), 0.0, cos_squared.sqrt());
+                    // d(solid angle) cos = pi d(cos^2) once integrated over phi
+                    ggx.d(&h) * f32::consts::PI / steps as f32
+                })
+                .sum();
+            assert!(
+                (integral - 1.0).abs() < 0.01,
+                "alpha {}: {}",
+                alpha,
+                integral
+            );
+        }
+    }
+
+    #[test]
+    fn visible_normals_follow_their_density() {
+        let mut sampler = IndependentSampler::new(11);
+        let wo = unit_vector(&Vec3::new(0.6, -0.2, 0.5));
+        for &alpha in [0.1f32, 0.5, 1.0].iter() {
+            let ggx = Ggx::new(alpha);
+            // Integrate the density and its mean cosine over the hemisphere
+            let steps = 400;
+            let step = 0.5 * f32::consts::PI / steps as f32;
+            let (mut integral, mut mean_cos) = (0.0, 0.0);
+            for i in 0..steps {
+                let theta = (i as f32 + 0.5) * step;
+                for j in 0..4 * steps {
+                    let phi = (j as f32 + 0.5) * step;
+                    let h = Vec3::new(
+                        theta.sin() * phi.cos(),
+                        theta.sin() * phi.sin(),
+                        theta.cos(),
+                    );
+                    let weight = ggx.visible_pdf(&wo, &h) * theta.sin() * step * step;
+                    integral += weight;
+                    mean_cos += weight * h.z();
+                }
+            }
+            assert!(
+                (integral - 1.0).abs() < 0.01,
+                "alpha {}: {}",
+                alpha,
+                integral
+            );
+
+            let n = 40000;
+            let sampled: f32 = (0..n)
+                .map(|_| {
+                    let h = ggx.sample_visible(&wo, sampler.get_2d());
+                    assert!((h.length() - 1.0).abs() < 1e-4 && h.z() > 0.0);
+                    assert!(dot(&wo, &h) >= 0.0);
+                    h.z()
+                })
+                .sum::<f32>()
+                / n as f32;
+            assert!(
+                (sampled - mean_cos).abs() < 0.005,
+                "alpha {}: {} != {}",
+                alpha,
+                sampled,
+                mean_cos
+            );
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use super::materials::{Dielectric, DiffuseLight, Lambertian, Material, Metal};
+use super::mesh::{Face, MeshData, TriangleMesh};
+use super::Vec3;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(io::Error),
+    /// A malformed record, with the 1-based line it was found on
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl ObjError {
+    fn parse<S: Into<String>>(line: usize, message: S) -> ObjError {
+        ObjError::Parse {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjError::Io(ref err) => write!(f, "{}", err),
+            ObjError::Parse { line, ref message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for ObjError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ObjError::Io(ref err) => Some(err),
+            ObjError::Parse { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for ObjError {
+    fn from(err: io::Error) -> ObjError {
+        ObjError::Io(err)
+    }
+}
+
+/// Faces that were declared under the same `g` or `o` statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjGroup {
+    pub name: String,
+    pub faces: Range<usize>,
+}
+
+/// Geometry read from an OBJ file. Faces have been triangulated. A face's
+/// `material` is 0 if no `usemtl` preceded it, and otherwise one past its
+/// index in `material_names`.
+#[derive(Debug, Clone, Default)]
+pub struct ObjModel {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<(f32, f32)>,
+    pub faces: Vec<Face>,
+    pub groups: Vec<ObjGroup>,
+    pub material_libraries: Vec<String>,
+    pub material_names: Vec<String>,
+}
+
+impl ObjModel {
+    /// Builds a mesh, looking each face's material up by name. Faces with no
+    /// material, or one missing from `materials`, are shaded grey.
+    pub fn into_mesh(self, materials: &HashMap<String, Box<dyn Material>>) -> TriangleMesh {
+        let default: Box<dyn Material> = Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+        let mut mesh_materials = vec![default];
+        for name in self.material_names.iter() {
+            mesh_materials.push(match materials.get(name) {
+                Some(mat) => mat.clone(),
+                None => mesh_materials[0].clone(),
+            });
+        }
+        TriangleMesh::new(MeshData {
+            positions: self.positions,
+            normals: self.normals,
+            uvs: self.uvs,
+            faces: self.faces,
+            materials: mesh_materials,
+        })
+    }
+
+    /// Builds a mesh with every face using `mat`, ignoring `usemtl`
+    pub fn into_mesh_with_material(self, mat: Box<dyn Material>) -> TriangleMesh {
+        let faces = self
+            .faces
+            .into_iter()
+            .map(|mut face| {
+                face.material = 0;
+                face
+            })
+            .collect();
+        let mut data = MeshData::new(self.positions, faces, mat);
+        data.normals = self.normals;
+        data.uvs = self.uvs;
+        TriangleMesh::new(data)
+    }
+}
+
+fn parse_f32(line: usize, token: Option<&str>, what: &str) -> Result<f32, ObjError> {
+    match token {
+        Some(token) => token
+            .parse::<f32>()
+            .map_err(|_| ObjError::parse(line, format!("invalid {} '{}'", what, token))),
+        None => Err(ObjError::parse(line, format!("missing {}", what))),
+    }
+}
+
+fn parse_vec3<'a, I: Iterator<Item = &'a str>>(
+    line: usize,
+    tokens: &mut I,
+    what: &str,
+) -> Result<Vec3, ObjError> {
+    Ok(Vec3::new(
+        parse_f32(line, tokens.next(), what)?,
+        parse_f32(line, tokens.next(), what)?,
+        parse_f32(line, tokens.next(), what)?,
+    ))
+}
+
+/// Resolves a 1-based or negative (relative to the end) OBJ index
+fn resolve_index(line: usize, token: &str, count: usize, what: &str) -> Result<usize, ObjError> {
+    let index = token
+        .parse::<i64>()
+        .map_err(|_| ObjError::parse(line, format!("invalid {} index '{}'", what, token)))?;
+    let resolved = if index > 0 {
+        index - 1
+    } else if index < 0 {
+        count as i64 + index
+    } else {
+        return Err(ObjError::parse(line, format!("{} index cannot be 0", what)));
+    };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(ObjError::parse(
+            line,
+            format!("{} index {} out of range ({} defined)", what, index, count),
+        ));
+    }
+    Ok(resolved as usize)
+}
+
+struct FaceVertex {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+fn parse_face_vertex(line: usize, token: &str, model: &ObjModel) -> Result<FaceVertex, ObjError> {
+    let mut parts = token.split('/');
+    let position = resolve_index(
+        line,
+        parts.next().unwrap_or(""),
+        model.positions.len(),
+        "vertex",
+    )?;
+    let uv = match parts.next() {
+        Some("") | None => None,
+        Some(part) => Some(resolve_index(line, part, model.uvs.len(), "texture")?),
+    };
+    let normal = match parts.next() {
+        Some("") | None => None,
+        Some(part) => Some(resolve_index(line, part, model.normals.len(), "normal")?),
+    };
+    if parts.next().is_some() {
+        return Err(ObjError::parse(
+            line,
+            format!("malformed face vertex '{}'", token),
+        ));
+    }
+    Ok(FaceVertex {
+        position,
+        uv,
+        normal,
+    })
+}
+
+fn close_group(model: &mut ObjModel) {
+    let end = model.faces.len();
+    if let Some(group) = model.groups.last_mut() {
+        group.faces.end = end;
+    }
+}
+
+/// Parses the text of an OBJ file. Polygons are triangulated as fans around
+/// their first vertex, and records other than geometry, groups and materials
+/// are ignored.
+pub fn parse_obj(source: &str) -> Result<ObjModel, ObjError> {
+    let mut model = ObjModel::default();
+    let mut material = 0;
+    for (number, text) in source.lines().enumerate() {
+        let line = number + 1;
+        let text = match text.find('#') {
+            Some(comment) => &text[..comment],
+            None => text,
+        };
+        let mut tokens = text.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        match keyword {
+            "v" => model
+                .positions
+                .push(parse_vec3(line, &mut tokens, "vertex coordinate")?),
+            "vn" => model
+                .normals
+                .push(parse_vec3(line, &mut tokens, "normal coordinate")?),
+            "vt" => {
+                let u = parse_f32(line, tokens.next(), "texture coordinate")?;
+                let v = match tokens.next() {
+                    Some(token) => parse_f32(line, Some(token), "texture coordinate")?,
+                    None => 0.0,
+                };
+                model.uvs.push((u, v));
+            }
+            "f" => {
+                let vertices = tokens
+                    .map(|token| parse_face_vertex(line, token, &model))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if vertices.len() < 3 {
+                    return Err(ObjError::parse(line, "face needs at least 3 vertices"));
+                }
+                let has_uvs = vertices[0].uv.is_some();
+                let has_normals = vertices[0].normal.is_some();
+                if vertices
+                    .iter()
+                    .any(|v| v.uv.is_some() != has_uvs || v.normal.is_some() != has_normals)
+                {
+                    return Err(ObjError::parse(
+                        line,
+                        "face vertices must all use the same attributes",
+                    ));
+                }
+                for i in 1..vertices.len() - 1 {
+                    let corners = [&vertices[0], &vertices[i], &vertices[i + 1]];
+                    let mut face = Face::new([
+                        corners[0].position,
+                        corners[1].position,
+                        corners[2].position,
+                    ]);
+                    if has_uvs {
+                        face.uvs = Some([
+                            corners[0].uv.unwrap(),
+                            corners[1].uv.unwrap(),
+                            corners[2].uv.unwrap(),
+                        ]);
+                    }
+                    if has_normals {
+                        face.normals = Some([
+                            corners[0].normal.unwrap(),
+                            corners[1].normal.unwrap(),
+                            corners[2].normal.unwrap(),
+                        ]);
+                    }
+                    face.material = material;
+                    model.faces.push(face);
+                }
+            }
+            "g" | "o" => {
+                close_group(&mut model);
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                let start = model.faces.len();
+                model.groups.push(ObjGroup {
+                    name,
+                    faces: start..start,
+                });
+            }
+            "usemtl" => {
+                let name = match tokens.next() {
+                    Some(name) => name.to_string(),
+                    None => return Err(ObjError::parse(line, "usemtl needs a material name")),
+                };
+                material = match model.material_names.iter().position(|n| *n == name) {
+                    Some(index) => index + 1,
+                    None => {
+                        model.material_names.push(name);
+                        model.material_names.len()
+                    }
+                };
+            }
+            "mtllib" => {
+                let names = tokens.collect::<Vec<_>>();
+                if names.is_empty() {
+                    return Err(ObjError::parse(line, "mtllib needs a file name"));
+                }
+                model
+                    .material_libraries
+                    .extend(names.into_iter().map(String::from));
+            }
+            // Lines, points, smoothing groups and free-form geometry
+            _ => {}
+        }
+    }
+    close_group(&mut model);
+    Ok(model)
+}
+
+/// Properties of one `newmtl` block that the tracer understands
+#[derive(Debug, Clone, PartialEq)]
+pub struct MtlMaterial {
+    pub name: String,
+    /// Diffuse colour
+    pub kd: Vec3,
+    /// Specular colour
+    pub ks: Vec3,
+    /// Specular exponent, 0 to 1000
+    pub ns: f32,
+    /// Index of refraction
+    pub ni: f32,
+    /// Opacity, where anything below 1 is treated as glass
+    pub d: f32,
+    /// Emitted radiance
+    pub ke: Vec3,
+}
+
+impl MtlMaterial {
+    pub fn new(name: String) -> MtlMaterial {
+        MtlMaterial {
+            name,
+            kd: Vec3::new(0.8, 0.8, 0.8),
+            ks: Vec3::origin(),
+            ns: 0.0,
+            ni: 1.5,
+            d: 1.0,
+            ke: Vec3::origin(),
+        }
+    }
+
+    /// Picks the closest of the tracer's materials: emissive materials become
+    /// `DiffuseLight`, translucent materials become `Dielectric`, materials
+    /// with a stronger specular than diffuse colour become `Metal` with a
+    /// fuzz derived from the exponent, and everything else becomes
+    /// `Lambertian`.
+    pub fn to_material(&self) -> Box<dyn Material> {
+        let max = |v: &Vec3| v.x.max(v.y).max(v.z);
+        if max(&self.ke) > 0.0 {
+            Box::new(DiffuseLight::new(self.ke.clone()))
+        } else if self.d < 1.0 {
+            Box::new(Dielectric::new(self.ni))
+        } else if max(&self.ks) > max(&self.kd) {
+            let fuzz = (2.0 / (self.ns + 2.0)).sqrt().min(1.0);
+            Box::new(Metal::new(self.ks.clone(), fuzz))
+        } else {
+            Box::new(Lambertian::new(self.kd.clone()))
+        }
+    }
+}
+
+/// Parses the text of an MTL file
+pub fn parse_mtl(source: &str) -> Result<Vec<MtlMaterial>, ObjError> {
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+    for (number, text) in source.lines().enumerate() {
+        let line = number + 1;
+        let text = match text.find('#') {
+            Some(comment) => &text[..comment],
+            None => text,
+        };
+        let mut tokens = text.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            let name = tokens.collect::<Vec<_>>().join(" ");
+            if name.is_empty() {
+                return Err(ObjError::parse(line, "newmtl needs a material name"));
+            }
+            materials.push(MtlMaterial::new(name));
+            continue;
+        }
+        let current = match materials.last_mut() {
+            Some(current) => current,
+            None => {
+                return Err(ObjError::parse(
+                    line,
+                    format!("'{}' before any newmtl", keyword),
+                ))
+            }
+        };
+        match keyword {
+            "Kd" => current.kd = parse_vec3(line, &mut tokens, "Kd component")?,
+            "Ks" => current.ks = parse_vec3(line, &mut tokens, "Ks component")?,
+            "Ke" => current.ke = parse_vec3(line, &mut tokens, "Ke component")?,
+            "Ns" => current.ns = parse_f32(line, tokens.next(), "Ns")?,
+            "Ni" => current.ni = parse_f32(line, tokens.next(), "Ni")?,
+            "d" => current.d = parse_f32(line, tokens.next(), "d")?,
+            "Tr" => current.d = 1.0 - parse_f32(line, tokens.next(), "Tr")?,
+            // Ambient colour, illumination model and texture maps have no
+            // counterpart in the tracer
+            _ => {}
+        }
+    }
+    Ok(materials)
+}
+
+/// Loads an OBJ file and any material libraries it references, which are
+/// looked up relative to the OBJ file's directory
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<TriangleMesh, ObjError> {
+    let path = path.as_ref();
+    let model = parse_obj(&fs::read_to_string(path)?)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut materials = HashMap::new();
+    for library in model.material_libraries.iter() {
+        let mtl_path = dir.join(library);
+        let source = fs::read_to_string(&mtl_path)?;
+        let parsed = parse_mtl(&source).map_err(|err| match err {
+            ObjError::Parse { line, message } => ObjError::Parse {
+                line,
+                message: format!("{}: {}", mtl_path.display(), message),
+            },
+            err => err,
+        })?;
+        for mtl in parsed {
+            materials.insert(mtl.name.clone(), mtl.to_material());
+        }
+    }
+    Ok(model.into_mesh(&materials))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_quad_with_negative_indices() {
+        let model = parse_obj(
+            "# a unit quad\n\
+             v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+             vn 0 0 1\n\
+             g quad\n\
+             usemtl red\n\
+             f -4/-4/-1 -3/-3/-1 -2/-2/-1 -1/-1/-1\n",
+        )
+        .unwrap();
+        assert_eq!(model.faces.len(), 2);
+        assert_eq!(model.faces[0].vertices, [0, 1, 2]);
+        assert_eq!(model.faces[1].vertices, [0, 2, 3]);
+        assert_eq!(model.faces[1].uvs, Some([0, 2, 3]));
+        assert_eq!(model.faces[1].normals, Some([0, 0, 0]));
+        assert_eq!(model.faces[0].material, 1);
+        assert_eq!(model.material_names, vec!["red".to_string()]);
+        assert_eq!(
+            model.groups,
+            vec![ObjGroup {
+                name: "quad".to_string(),
+                faces: 0..2,
+            }]
+        );
+    }
+
+    #[test]
+    fn errors_report_line_numbers() {
+        match parse_obj("v 0 0 0\nv 1 0 0\n\nf 1 2 5\n") {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 4),
+            other => panic!("expected parse error, got {:?}", other.map(|_| ())),
+        }
+        match parse_mtl("newmtl a\nKd 1 x 0\n") {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 2),
+            other => panic!("expected parse error, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,1287 @@
+//! Text scene descriptions.
+//!
+//! A scene file is a sequence of statements. `#` starts a comment that runs
+//! to the end of the line, and blocks hold `key value...` properties:
+//!
+//! ```text
+//! image 800 400
+//! samples 64
+//! sampler sobol                       # independent, stratified, halton
+//! filter gaussian:1.5                 # box, triangle, mitchell, lanczos; :radius
+//! roulette_depth 3                    # bounces before paths may end early
+//! max_depth 50
+//! mis power                           # or: balance, to weigh light and BSDF samples
+//! integrator spectral                 # or: rgb
+//! exposure 0.5                        # in stops
+//! tonemap aces                        # clamp, reinhard, reinhard-extended:W, hable:W
+//! background sky                      # or: solid r g b, gradient r g b r g b
+//! camera { from 13 2 3 at 0 0 0 up 0 1 0 fov 20 aperture 0.1 focus 10 shutter 0 1 }
+//! texture tiles checker { odd 0.2 0.3 0.1 even 0.9 0.9 0.9 scale 10 }
+//! material ground lambertian { albedo tiles }
+//! material glass dielectric { index 1.5 }
+//! material frosted dielectric { index 1.5 roughness 0.2 }
+//! material bottle dielectric { transmittance 0.4 0.8 0.5 distance 2 }  # after 2 units inside
+//! material prism dielectric { glass bk7 }  # fused-silica, diamond; or: cauchy a b
+//! material gem dielectric { sellmeier 0.33 4.34 0 0.0306 0.0112 0 }  # b1 b2 b3 c1 c2 c3
+//! material steel metal { albedo 0.7 0.6 0.5 fuzz 0.1 }
+//! material gold conductor { metal gold roughness 0.2 }  # copper, silver, aluminium
+//! material alloy conductor { eta 0.2 0.9 1.1 k 3.9 2.5 2.1 roughness 0.1 }
+//! material lamp light { emit 4 4 4 }
+//! sphere { center 0 -1000 0 radius 1000 material ground }
+//! sphere { center 4 1 0 center1 4 1.5 0 time 0 1 radius 1 material steel }  # moving
+//! triangle { a 0 0 0 b 1 0 0 c 0 1 0 material steel }
+//! mesh { file "teapot.obj" material glass }
+//! mesh { file "teapot.obj" scale 2 2 2 rotate 45 0 1 0 translate 3 0 0 }
+//! quad { corner 0 0 0 u 1 0 0 v 0 0 1 material lamp }  # faces along u × v
+//! plane { point 0 0 0 normal 0 1 0 material ground }
+//! box { min 0 0 0 max 1 2 1 material steel rotate 15 0 1 0 }
+//! material smoke henyey-greenstein { albedo 0.8 0.8 0.8 g 0.3 }  # or: isotropic
+//! medium { density 0.5 material smoke boundary sphere { center 0 1 0 radius 1 material glass } }
+//! ```
+//!
+//! Colours may be given either as three numbers or as the name of a
+//! texture. Relative file names are resolved against the scene file's
+//! directory.
+//!
+//! Every object takes `translate x y z`, `rotate degrees x y z` (about the
+//! given axis) and `scale x y z`, applied in the order they are written.
+//! Meshes loaded more than once from the same file with the same material
+//! share one copy of their triangles.
+//!
+//! Spheres and quads made of a `light` material are also sampled directly as
+//! lights, except for spheres that move or are transformed and the boundaries
+//! of media.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::bvh::Bvh;
+use super::camera::{Camera, CameraSettings};
+use super::filter::Filter;
+use super::image::load;
+use super::light::LightList;
+use super::materials::{
+    Conductor, Dielectric, DiffuseLight, Dispersion, DispersiveDielectric, HenyeyGreenstein,
+    Isotropic, Lambertian, Material, Metal, RoughDielectric,
+};
+use super::medium::ConstantMedium;
+use super::mesh::{Triangle, TriangleMesh};
+use super::obj::{load_obj, parse_obj};
+use super::render::{Heuristic, Integrator};
+use super::sampler::SamplerKind;
+use super::scene::{Background, Scene};
+use super::shapes::{Cuboid, Plane, Quad};
+use super::texture::{Checker, ImageTexture, Noise, NoiseKind, SolidColor, Texture, Wrap};
+use super::tonemap::ToneMap;
+use super::{cross, Hit, MovingSphere, Sphere, Transform, Transformed, Vec3};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneError {
+    /// 1-based position of the offending token, or 0 for errors that are not
+    /// tied to a position such as failing to read the file
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "{}", self.message);
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for SceneError {}
+
+/// A parsed scene file: the world along with how to view and render it
+pub struct SceneDescription {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    pub sampler: SamplerKind,
+    pub filter: Filter,
+    pub roulette_depth: i32,
+    pub max_depth: i32,
+    pub heuristic: Heuristic,
+    pub integrator: Integrator,
+    pub tone_map: ToneMap,
+    pub camera: CameraSettings,
+    pub scene: Scene,
+}
+
+impl SceneDescription {
+    /// Camera matching the aspect ratio of the image size
+    pub fn camera(&self) -> Camera {
+        self.camera.camera(self.width as f32 / self.height as f32)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Word,
+    Str,
+    Open,
+    Close,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, SceneError> {
+    let mut tokens = Vec::new();
+    for (number, text) in source.lines().enumerate() {
+        let line = number + 1;
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let column = text[..start].chars().count() + 1;
+            let token = |kind, text: &str| Token {
+                kind,
+                text: text.to_string(),
+                line,
+                column,
+            };
+            match c {
+                '#' => break,
+                '{' => tokens.push(token(TokenKind::Open, "{")),
+                '}' => tokens.push(token(TokenKind::Close, "}")),
+                '"' => {
+                    let mut end = None;
+                    for (i, c) in chars.by_ref() {
+                        if c == '"' {
+                            end = Some(i);
+                            break;
+                        }
+                    }
+                    match end {
+                        Some(end) => tokens.push(token(TokenKind::Str, &text[start + 1..end])),
+                        None => {
+                            return Err(SceneError {
+                                line,
+                                column,
+                                message: "unterminated string".to_string(),
+                            })
+                        }
+                    }
+                }
+                c if c.is_whitespace() => {}
+                _ => {
+                    let mut end = text.len();
+                    while let Some(&(i, c)) = chars.peek() {
+                        if c.is_whitespace() || c == '{' || c == '}' || c == '"' || c == '#' {
+                            end = i;
+                            break;
+                        }
+                        chars.next();
+                    }
+                    tokens.push(token(TokenKind::Word, &text[start..end]));
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    base_dir: PathBuf,
+    /// Position reported for errors at the end of the input
+    end: (usize, usize),
+    textures: HashMap<String, Box<dyn Texture>>,
+    materials: HashMap<String, Box<dyn Material>>,
+    /// Meshes already loaded, by file and material name
+    meshes: HashMap<(PathBuf, Option<String>), Arc<TriangleMesh>>,
+    /// Emitters found so far, to be sampled directly
+    lights: LightList,
+}
+
+impl Parser {
+    fn error_at<S: Into<String>>(&self, token: &Token, message: S) -> SceneError {
+        SceneError {
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+        }
+    }
+
+    fn next(&mut self, what: &str) -> Result<Token, SceneError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(SceneError {
+                line: self.end.0,
+                column: self.end.1,
+                message: format!("expected {}, found end of file", what),
+            }),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn word(&mut self, what: &str) -> Result<Token, SceneError> {
+        let token = self.next(what)?;
+        if token.kind != TokenKind::Word {
+            return Err(self.error_at(&token, format!("expected {}, found '{}'", what, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn number(&mut self) -> Result<f32, SceneError> {
+        let token = self.next("a number")?;
+        match token.text.parse::<f32>() {
+            Ok(value) if token.kind == TokenKind::Word => Ok(value),
+            _ => Err(self.error_at(&token, format!("expected a number, found '{}'", token.text))),
+        }
+    }
+
+    fn count(&mut self) -> Result<usize, SceneError> {
+        let token = self.next("a whole number")?;
+        match token.text.parse::<usize>() {
+            Ok(value) if token.kind == TokenKind::Word => Ok(value),
+            _ => Err(self.error_at(
+                &token,
+                format!("expected a whole number, found '{}'", token.text),
+            )),
+        }
+    }
+
+    fn vec3(&mut self) -> Result<Vec3, SceneError> {
+        Ok(Vec3::new(self.number()?, self.number()?, self.number()?))
+    }
+
+    fn path(&mut self) -> Result<(Token, PathBuf), SceneError> {
+        let token = self.next("a file name")?;
+        if token.kind != TokenKind::Str && token.kind != TokenKind::Word {
+            return Err(self.error_at(
+                &token,
+                format!("expected a file name, found '{}'", token.text),
+            ));
+        }
+        let path = self.base_dir.join(&token.text);
+        Ok((token, path))
+    }
+
+    fn open(&mut self) -> Result<(), SceneError> {
+        let token = self.next("'{'")?;
+        if token.kind != TokenKind::Open {
+            return Err(self.error_at(&token, format!("expected '{{', found '{}'", token.text)));
+        }
+        Ok(())
+    }
+
+    /// Next property name in a block, or `None` once the block is closed
+    fn key(&mut self) -> Result<Option<Token>, SceneError> {
+        let token = self.next("a property or '}'")?;
+        match token.kind {
+            TokenKind::Close => Ok(None),
+            TokenKind::Word => Ok(Some(token)),
+            _ => Err(self.error_at(
+                &token,
+                format!("expected a property, found '{}'", token.text),
+            )),
+        }
+    }
+
+    fn unknown_key(&self, key: &Token, block: &str) -> SceneError {
+        self.error_at(key, format!("unknown {} property '{}'", block, key.text))
+    }
+
+    fn require<T>(&self, value: Option<T>, start: &Token, what: &str) -> Result<T, SceneError> {
+        value.ok_or_else(|| self.error_at(start, format!("missing {}", what)))
+    }
+
+    /// Either three numbers or the name of a texture
+    fn texture(&mut self) -> Result<Box<dyn Texture>, SceneError> {
+        let is_name = match self.peek() {
+            Some(token) => token.kind == TokenKind::Word && token.text.parse::<f32>().is_err(),
+            None => false,
+        };
+        if is_name {
+            let token = self.word("a texture name")?;
+            match self.textures.get(&token.text) {
+                Some(texture) => Ok(texture.clone()),
+                None => Err(self.error_at(&token, format!("unknown texture '{}'", token.text))),
+            }
+        } else {
+            Ok(Box::new(SolidColor::new(self.vec3()?)))
+        }
+    }
+
+    fn material_ref(&mut self) -> Result<Box<dyn Material>, SceneError> {
+        let token = self.word("a material name")?;
+        match self.materials.get(&token.text) {
+            Some(mat) => Ok(mat.clone()),
+            None => Err(self.error_at(&token, format!("unknown material '{}'", token.text))),
+        }
+    }
+
+    /// The arguments of an object's `translate`, `rotate` or `scale` key
+    fn transform(&mut self, key: &Token) -> Result<Transform, SceneError> {
+        match key.text.as_str() {
+            "translate" => Ok(Transform::translate(&self.vec3()?)),
+            "rotate" => {
+                let degrees = self.number()?;
+                let axis = self.vec3()?;
+                if axis.length() == 0.0 {
+                    return Err(self.error_at(key, "rotation axis must not be zero"));
+                }
+                Ok(Transform::rotate(degrees, &axis))
+            }
+            _ => {
+                let factors = self.vec3()?;
+                if factors.x == 0.0 || factors.y == 0.0 || factors.z == 0.0 {
+                    return Err(self.error_at(key, "scale factors must not be zero"));
+                }
+                Ok(Transform::scale(&factors))
+            }
+        }
+    }
+
+    fn parse_camera(&mut self, start: &Token) -> Result<CameraSettings, SceneError> {
+        let mut settings = CameraSettings::default();
+        let mut focus = None;
+        self.open()?;
+        while let Some(key) = self.key()? {
+            match key.text.as_str() {
+                "from" => settings.look_from = self.vec3()?,
+                "at" => settings.look_at = self.vec3()?,
+                "up" => settings.vup = self.vec3()?,
+                "fov" => settings.vfov = self.number()?,
+                "aperture" => settings.aperture = self.number()?,
+                "focus" => focus = Some(self.number()?),
+                "shutter" => {
+                    settings.shutter_open = self.number()?;
+                    settings.shutter_close = self.number()?;
+                }
+                _ => return Err(self.unknown_key(&key, "camera")),
+            }
+        }
+        let distance = (&settings.look_from - &settings.look_at).length();
+        if distance == 0.0 {
+            return Err(self.error_at(start, "camera 'from' and 'at' must differ"));
+        }
+        settings.focus = focus.unwrap_or(distance);
+        Ok(settings)
+    }
+
+    fn parse_texture(&mut self, kind: &Token) -> Result<Box<dyn Texture>, SceneError> {
+        self.open()?;
+        let texture: Box<dyn Texture> = match kind.text.as_str() {
+            "solid" => {
+                let mut color = None;
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "color" => color = Some(self.vec3()?),
+                        _ => return Err(self.unknown_key(&key, "solid texture")),
+                    }
+                }
+                Box::new(SolidColor::new(self.require(color, kind, "'color'")?))
+            }
+            "checker" => {
+                let mut odd = None;
+                let mut even = None;
+                let mut scale = 10.0;
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "odd" => odd = Some(self.texture()?),
+                        "even" => even = Some(self.texture()?),
+                        "scale" => scale = self.number()?,
+                        _ => return Err(self.unknown_key(&key, "checker texture")),
+                    }
+                }
+                Box::new(Checker::new(
+                    self.require(odd, kind, "'odd'")?,
+                    self.require(even, kind, "'even'")?,
+                    scale,
+                ))
+            }
+            "noise" => {
+                let mut scale = 1.0;
+                let mut style = NoiseKind::Smooth;
+                let mut seed = 0;
+                let mut color = Vec3::new(1.0, 1.0, 1.0);
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "scale" => scale = self.number()?,
+                        "seed" => seed = self.count()? as u64,
+                        "color" => color = self.vec3()?,
+                        "style" => {
+                            let token = self.word("a noise style")?;
+                            style = match token.text.as_str() {
+                                "smooth" => NoiseKind::Smooth,
+                                "turbulence" => NoiseKind::Turbulence,
+                                "marble" => NoiseKind::Marble,
+                                _ => {
+                                    return Err(self.error_at(
+                                        &token,
+                                        format!("unknown noise style '{}'", token.text),
+                                    ))
+                                }
+                            };
+                        }
+                        _ => return Err(self.unknown_key(&key, "noise texture")),
+                    }
+                }
+                let mut noise = Noise::new(scale, style, seed);
+                noise.color = color;
+                Box::new(noise)
+            }
+            "image" => {
+                let mut file = None;
+                let mut wrap = Wrap::Repeat;
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "file" => file = Some(self.path()?),
+                        "wrap" => {
+                            let token = self.word("a wrap mode")?;
+                            wrap = match token.text.as_str() {
+                                "repeat" => Wrap::Repeat,
+                                "clamp" => Wrap::Clamp,
+                                "mirror" => Wrap::Mirror,
+                                _ => {
+                                    return Err(self.error_at(
+                                        &token,
+                                        format!("unknown wrap mode '{}'", token.text),
+                                    ))
+                                }
+                            };
+                        }
+                        _ => return Err(self.unknown_key(&key, "image texture")),
+                    }
+                }
+                let (token, path) = self.require(file, kind, "'file'")?;
+                let image = load(&path).map_err(|err| {
+                    self.error_at(&token, format!("cannot load '{}': {}", path.display(), err))
+                })?;
+                Box::new(ImageTexture::new(image, wrap))
+            }
+            _ => return Err(self.error_at(kind, format!("unknown texture type '{}'", kind.text))),
+        };
+        Ok(texture)
+    }
+
+    fn parse_material(&mut self, kind: &Token) -> Result<Box<dyn Material>, SceneError> {
+        self.open()?;
+        let material: Box<dyn Material> = match kind.text.as_str() {
+            "lambertian" => {
+                let mut albedo = None;
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "albedo" => albedo = Some(self.texture()?),
+                        _ => return Err(self.unknown_key(&key, "lambertian")),
+                    }
+                }
+                Box::new(Lambertian::textured(
+                    self.require(albedo, kind, "'albedo'")?,
+                ))
+            }
+            "metal" => {
+                let mut albedo = None;
+                let mut fuzz = 0.0;
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "albedo" => albedo = Some(self.texture()?),
+                        "fuzz" => fuzz = self.number()?,
+                        _ => return Err(self.unknown_key(&key, "metal")),
+                    }
+                }
+                Box::new(Metal::textured(
+                    self.require(albedo, kind, "'albedo'")?,
+                    fuzz,
+                ))
+            }
+            "conductor" => {
+                let mut conductor = None;
+                let mut eta = None;
+                let mut k = None;
+                let mut roughness = 0.0;
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "metal" => {
+                            let name = self.word("a metal")?;
+                            conductor = Some(
+                                Conductor::preset(&name.text, 0.0)
+                                    .map_err(|message: String| self.error_at(&name, message))?,
+                            );
+                        }
+                        "eta" => eta = Some(self.vec3()?),
+                        "k" => k = Some(self.vec3()?),
+                        "roughness" => {
+                            roughness = self.number()?;
+                            if roughness < 0.0 {
+                                return Err(self.error_at(&key, "'roughness' must not be negative"));
+                            }
+                        }
+                        _ => return Err(self.unknown_key(&key, "conductor")),
+                    }
+                }
+                // A named metal can still have its indices overridden
+                let mut conductor = match conductor {
+                    Some(conductor) => conductor,
+                    None => Conductor::new(
+                        self.require(eta.clone(), kind, "'metal' or 'eta'")?,
+                        self.require(k.clone(), kind, "'k'")?,
+                        0.0,
+                    ),
+                };
+                conductor.eta = eta.unwrap_or(conductor.eta);
+                conductor.k = k.unwrap_or(conductor.k);
+                conductor.roughness = roughness;
+                Box::new(conductor)
+            }
+            "dielectric" => {
+                let mut index = 1.5;
+                let mut roughness = 0.0;
+                let mut transmittance = Vec3::new(1.0, 1.0, 1.0);
+                let mut distance = 1.0;
+                let mut dispersion = None;
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "index" => index = self.number()?,
+                        "glass" => {
+                            let name = self.word("a glass")?;
+                            dispersion = Some(
+                                Dispersion::preset(&name.text)
+                                    .map_err(|message: String| self.error_at(&name, message))?,
+                            );
+                        }
+                        "cauchy" => {
+                            dispersion = Some(Dispersion::Cauchy {
+                                a: self.number()?,
+                                b: self.number()?,
+                            })
+                        }
+                        "sellmeier" => {
+                            let b = [self.number()?, self.number()?, self.number()?];
+                            let c = [self.number()?, self.number()?, self.number()?];
+                            dispersion = Some(Dispersion::Sellmeier { b, c });
+                        }
+                        "transmittance" => transmittance = self.vec3()?,
+                        "distance" => {
+                            distance = self.number()?;
+                            if distance <= 0.0 {
+                                return Err(self.error_at(&key, "'distance' must be positive"));
+                            }
+                        }
+                        "roughness" => {
+                            roughness = self.number()?;
+                            if roughness < 0.0 {
+                                return Err(self.error_at(&key, "'roughness' must not be negative"));
+                            }
+                        }
+                        _ => return Err(self.unknown_key(&key, "dielectric")),
+                    }
+                }
+                if let Some(dispersion) = dispersion {
+                    if roughness > 0.0 {
+                        return Err(self.error_at(kind, "dispersive dielectrics must be smooth"));
+                    }
+                    Box::new(DispersiveDielectric::new(dispersion).tinted(transmittance, distance))
+                } else if roughness > 0.0 {
+                    Box::new(RoughDielectric::new(index, roughness).tinted(transmittance, distance))
+                } else {
+                    Box::new(Dielectric::new(index).tinted(transmittance, distance))
+                }
+            }
+            "light" => {
+                let mut emit = None;
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "emit" => emit = Some(self.texture()?),
+                        _ => return Err(self.unknown_key(&key, "light")),
+                    }
+                }
+                Box::new(DiffuseLight::textured(self.require(emit, kind, "'emit'")?))
+            }
+            "isotropic" | "henyey-greenstein" => {
+                let mut albedo = None;
+                let mut g = 0.0;
+                while let Some(key) = self.key()? {
+                    match key.text.as_str() {
+                        "albedo" => albedo = Some(self.texture()?),
+                        "g" if kind.text == "henyey-greenstein" => {
+                            g = self.number()?;
+                            if g <= -1.0 || g >= 1.0 {
+                                return Err(self.error_at(&key, "'g' must lie between -1 and 1"));
+                            }
+                        }
+                        _ => return Err(self.unknown_key(&key, &kind.text)),
+                    }
+                }
+                let albedo = self.require(albedo, kind, "'albedo'")?;
+                if kind.text == "isotropic" {
+                    Box::new(Isotropic::textured(albedo))
+                } else {
+                    Box::new(HenyeyGreenstein::textured(albedo, g))
+                }
+            }
+            _ => return Err(self.error_at(kind, format!("unknown material type '{}'", kind.text))),
+        };
+        Ok(material)
+    }
+
+    /// An object statement of any kind, starting after its name
+    fn parse_object(&mut self, kind: &Token) -> Result<Box<dyn Hit>, SceneError> {
+        match kind.text.as_str() {
+            "sphere" => self.parse_sphere(kind),
+            "triangle" => self.parse_triangle(kind),
+            "mesh" => self.parse_mesh(kind),
+            "quad" => self.parse_quad(kind),
+            "plane" => self.parse_plane(kind),
+            "box" => self.parse_box(kind),
+            "medium" => self.parse_medium(kind),
+            _ => Err(self.error_at(kind, format!("unknown object type '{}'", kind.text))),
+        }
+    }
+
+    /// A volume filling a closed object. The boundary's own material is never
+    /// seen.
+    fn parse_medium(&mut self, start: &Token) -> Result<Box<dyn Hit>, SceneError> {
+        let mut density = None;
+        let mut material = None;
+        let mut boundary = None;
+        self.open()?;
+        while let Some(key) = self.key()? {
+            match key.text.as_str() {
+                "density" => density = Some(self.number()?),
+                "material" => material = Some(self.material_ref()?),
+                "boundary" => {
+                    let kind = self.word("an object type")?;
+                    // The boundary is not sampled as a light either, whatever
+                    // its material
+                    let lights = self.lights.lights.len();
+                    boundary = Some(self.parse_object(&kind)?);
+                    self.lights.lights.truncate(lights);
+                }
+                _ => return Err(self.unknown_key(&key, "medium")),
+            }
+        }
+        let density = self.require(density, start, "medium 'density'")?;
+        if density <= 0.0 {
+            return Err(self.error_at(start, "medium 'density' must be positive"));
+        }
+        Ok(Box::new(ConstantMedium::new(
+            self.require(boundary, start, "medium 'boundary'")?,
+            density,
+            self.require(material, start, "medium 'material'")?,
+        )))
+    }
+
+    fn parse_sphere(&mut self, start: &Token) -> Result<Box<dyn Hit>, SceneError> {
+        let mut center = None;
+        let mut center1 = None;
+        let mut times = (0.0, 1.0);
+        let mut radius = None;
+        let mut material = None;
+        let mut transform = Transform::identity();
+        self.open()?;
+        while let Some(key) = self.key()? {
+            match key.text.as_str() {
+                "translate" | "rotate" | "scale" => transform = self.transform(&key)? * transform,
+                "center" => center = Some(self.vec3()?),
+                "center1" => center1 = Some(self.vec3()?),
+                "time" => times = (self.number()?, self.number()?),
+                "radius" => radius = Some(self.number()?),
+                "material" => material = Some(self.material_ref()?),
+                _ => return Err(self.unknown_key(&key, "sphere")),
+            }
+        }
+        let center = self.require(center, start, "sphere 'center'")?;
+        let radius = self.require(radius, start, "sphere 'radius'")?;
+        let material = self.require(material, start, "sphere 'material'")?;
+        let sphere: Box<dyn Hit> = match center1 {
+            Some(center1) => Box::new(MovingSphere::new(
+                center, center1, times.0, times.1, radius, material,
+            )),
+            None => {
+                let sphere = Sphere::new(center, radius, material);
+                // Spheres under a transform cannot be sampled, but are still
+                // found by scattered rays
+                if sphere.material().is_emissive() && transform == Transform::identity() {
+                    self.lights.push(sphere.clone());
+                }
+                Box::new(sphere)
+            }
+        };
+        Ok(place(sphere, transform))
+    }
+
+    fn parse_triangle(&mut self, start: &Token) -> Result<Box<dyn Hit>, SceneError> {
+        let mut a = None;
+        let mut b = None;
+        let mut c = None;
+        let mut material = None;
+        let mut transform = Transform::identity();
+        self.open()?;
+        while let Some(key) = self.key()? {
+            match key.text.as_str() {
+                "translate" | "rotate" | "scale" => transform = self.transform(&key)? * transform,
+                "a" => a = Some(self.vec3()?),
+                "b" => b = Some(self.vec3()?),
+                "c" => c = Some(self.vec3()?),
+                "material" => material = Some(self.material_ref()?),
+                _ => return Err(self.unknown_key(&key, "triangle")),
+            }
+        }
+        let triangle = Triangle::new(
+            self.require(a, start, "triangle 'a'")?,
+            self.require(b, start, "triangle 'b'")?,
+            self.require(c, start, "triangle 'c'")?,
+            self.require(material, start, "triangle 'material'")?,
+        );
+        Ok(place(Box::new(triangle), transform))
+    }
+
+    fn parse_quad(&mut self, start: &Token) -> Result<Box<dyn Hit>, SceneError> {
+        let mut corner = None;
+        let mut u = None;
+        let mut v = None;
+        let mut material = None;
+        let mut transform = Transform::identity();
+        self.open()?;
+        while let Some(key) = self.key()? {
+            match key.text.as_str() {
+                "translate" | "rotate" | "scale" => transform = self.transform(&key)? * transform,
+                "corner" => corner = Some(self.vec3()?),
+                "u" => u = Some(self.vec3()?),
+                "v" => v = Some(self.vec3()?),
+                "material" => material = Some(self.material_ref()?),
+                _ => return Err(self.unknown_key(&key, "quad")),
+            }
+        }
+        let corner = self.require(corner, start, "quad 'corner'")?;
+        let u = self.require(u, start, "quad 'u'")?;
+        let v = self.require(v, start, "quad 'v'")?;
+        let material = self.require(material, start, "quad 'material'")?;
+        if cross(&u, &v).length() == 0.0 {
+            return Err(self.error_at(start, "quad edges 'u' and 'v' must not be parallel"));
+        }
+        // A transformed parallelogram is still one, so the transform is
+        // applied here and the quad can be sampled as a light
+        let quad = Quad::new(
+            transform.point(&corner),
+            transform.vector(&u),
+            transform.vector(&v),
+            material,
+        );
+        if quad.material().is_emissive() {
+            self.lights.push(quad.clone());
+        }
+        Ok(Box::new(quad))
+    }
+
+    fn parse_plane(&mut self, start: &Token) -> Result<Box<dyn Hit>, SceneError> {
+        let mut point = Vec3::origin();
+        let mut normal = Vec3::new(0.0, 1.0, 0.0);
+        let mut material = None;
+        let mut transform = Transform::identity();
+        self.open()?;
+        while let Some(key) = self.key()? {
+            match key.text.as_str() {
+                "translate" | "rotate" | "scale" => transform = self.transform(&key)? * transform,
+                "point" => point = self.vec3()?,
+                "normal" => normal = self.vec3()?,
+                "material" => material = Some(self.material_ref()?),
+                _ => return Err(self.unknown_key(&key, "plane")),
+            }
+        }
+        let material = self.require(material, start, "plane 'material'")?;
+        if normal.length() == 0.0 {
+            return Err(self.error_at(start, "plane 'normal' must not be zero"));
+        }
+        let plane = Plane::new(point, &normal, material);
+        Ok(place(Box::new(plane), transform))
+    }
+
+    fn parse_box(&mut self, start: &Token) -> Result<Box<dyn Hit>, SceneError> {
+        let mut min = None;
+        let mut max = None;
+        let mut material = None;
+        let mut transform = Transform::identity();
+        self.open()?;
+        while let Some(key) = self.key()? {
+            match key.text.as_str() {
+                "translate" | "rotate" | "scale" => transform = self.transform(&key)? * transform,
+                "min" => min = Some(self.vec3()?),
+                "max" => max = Some(self.vec3()?),
+                "material" => material = Some(self.material_ref()?),
+                _ => return Err(self.unknown_key(&key, "box")),
+            }
+        }
+        let cuboid = Cuboid::new(
+            &self.require(min, start, "box 'min'")?,
+            &self.require(max, start, "box 'max'")?,
+            self.require(material, start, "box 'material'")?,
+        );
+        Ok(place(Box::new(cuboid), transform))
+    }
+
+    /// An OBJ mesh, using its own MTL materials unless one is given
+    fn parse_mesh(&mut self, start: &Token) -> Result<Box<dyn Hit>, SceneError> {
+        let mut file = None;
+        let mut material = None;
+        let mut transform = Transform::identity();
+        self.open()?;
+        while let Some(key) = self.key()? {
+            match key.text.as_str() {
+                "file" => file = Some(self.path()?),
+                "material" => {
+                    let name = self.peek().map(|token| token.text.clone());
+                    material = Some((name, self.material_ref()?));
+                }
+                "translate" | "rotate" | "scale" => transform = self.transform(&key)? * transform,
+                _ => return Err(self.unknown_key(&key, "mesh")),
+            }
+        }
+        let (token, path) = self.require(file, start, "mesh 'file'")?;
+        let (name, material) = match material {
+            Some((name, material)) => (name, Some(material)),
+            None => (None, None),
+        };
+        let cache_key = (path.clone(), name);
+        if let Some(mesh) = self.meshes.get(&cache_key) {
+            return Ok(place(Box::new(mesh.clone()), transform));
+        }
+        let error = |err: &dyn fmt::Display| {
+            self.error_at(&token, format!("cannot load '{}': {}", path.display(), err))
+        };
+        let mesh = match material {
+            Some(material) => fs::read_to_string(&path)
+                .map_err(|err| error(&err))
+                .and_then(|source| parse_obj(&source).map_err(|err| error(&err)))?
+                .into_mesh_with_material(material),
+            None => load_obj(&path).map_err(|err| error(&err))?,
+        };
+        let mesh = Arc::new(mesh);
+        self.meshes.insert(cache_key, mesh.clone());
+        Ok(place(Box::new(mesh), transform))
+    }
+
+    fn parse_background(&mut self) -> Result<Background, SceneError> {
+        let token = self.word("a background type")?;
+        Ok(match token.text.as_str() {
+            "sky" => Background::sky(),
+            "solid" => Background::Solid(self.vec3()?),
+            "gradient" => Background::Gradient {
+                bottom: self.vec3()?,
+                top: self.vec3()?,
+            },
+            _ => {
+                return Err(
+                    self.error_at(&token, format!("unknown background type '{}'", token.text))
+                )
+            }
+        })
+    }
+
+    fn parse(&mut self) -> Result<SceneDescription, SceneError> {
+        let mut width = 400;
+        let mut height = 200;
+        let mut samples = 16;
+        let mut sampler = SamplerKind::default();
+        let mut filter = Filter::default();
+        let mut roulette_depth = 3;
+        let mut max_depth = 50;
+        let mut heuristic = Heuristic::default();
+        let mut integrator = Integrator::default();
+        let mut tone_map = ToneMap::default();
+        let mut background = Background::sky();
+        let mut camera = None;
+        let mut objects: Vec<Box<dyn Hit>> = Vec::new();
+
+        while self.peek().is_some() {
+            let statement = self.word("a statement")?;
+            match statement.text.as_str() {
+                "image" => {
+                    width = self.count()?;
+                    height = self.count()?;
+                    if width == 0 || height == 0 {
+                        return Err(self.error_at(&statement, "image size must be positive"));
+                    }
+                }
+                "samples" => {
+                    let token = self.peek().cloned();
+                    samples = self.count()?;
+                    if let (0, Some(token)) = (samples, token) {
+                        return Err(self.error_at(&token, "samples must be positive"));
+                    }
+                }
+                "sampler" => {
+                    let token = self.word("a sampler")?;
+                    sampler = token
+                        .text
+                        .parse()
+                        .map_err(|message: String| self.error_at(&token, message))?;
+                }
+                "filter" => {
+                    let token = self.word("a filter")?;
+                    filter = token
+                        .text
+                        .parse()
+                        .map_err(|message: String| self.error_at(&token, message))?;
+                }
+                "roulette_depth" => roulette_depth = self.count()? as i32,
+                "max_depth" => max_depth = self.count()? as i32,
+                "mis" => {
+                    let token = self.word("an MIS heuristic")?;
+                    heuristic = token
+                        .text
+                        .parse()
+                        .map_err(|message: String| self.error_at(&token, message))?;
+                }
+                "integrator" => {
+                    let token = self.word("an integrator")?;
+                    integrator = token
+                        .text
+                        .parse()
+                        .map_err(|message: String| self.error_at(&token, message))?;
+                }
+                "exposure" => tone_map.exposure = self.number()?,
+                "tonemap" => {
+                    let token = self.word("a tone mapping operator")?;
+                    tone_map.operator = token
+                        .text
+                        .parse()
+                        .map_err(|message: String| self.error_at(&token, message))?;
+                }
+                "background" => background = self.parse_background()?,
+                "camera" => camera = Some(self.parse_camera(&statement)?),
+                "texture" => {
+                    let name = self.word("a texture name")?;
+                    let kind = self.word("a texture type")?;
+                    let texture = self.parse_texture(&kind)?;
+                    self.textures.insert(name.text, texture);
+                }
+                "material" => {
+                    let name = self.word("a material name")?;
+                    let kind = self.word("a material type")?;
+                    let material = self.parse_material(&kind)?;
+                    // Meshes cached with an earlier material of this name
+                    // must not be reused for the new one
+                    self.meshes
+                        .retain(|(_, material), _| material.as_ref() != Some(&name.text));
+                    self.materials.insert(name.text, material);
+                }
+                "sphere" | "triangle" | "mesh" | "quad" | "plane" | "box" | "medium" => {
+                    objects.push(self.parse_object(&statement)?)
+                }
+                _ => {
+                    return Err(self.error_at(
+                        &statement,
+                        format!("unknown statement '{}'", statement.text),
+                    ))
+                }
+            }
+        }
+
+        let camera = match camera {
+            Some(camera) => camera,
+            None => {
+                return Err(SceneError {
+                    line: 1,
+                    column: 1,
+                    message: "scene has no camera".to_string(),
+                })
+            }
+        };
+        Ok(SceneDescription {
+            width,
+            height,
+            samples,
+            sampler,
+            filter,
+            roulette_depth,
+            max_depth,
+            heuristic,
+            integrator,
+            tone_map,
+            camera,
+            scene: Scene::new(Box::new(Bvh::new(objects)), background)
+                .with_lights(mem::take(&mut self.lights)),
+        })
+    }
+}
+
+/// `object` moved by `transform`, left unwrapped when that does nothing
+fn place(object: Box<dyn Hit>, transform: Transform) -> Box<dyn Hit> {
+    if transform == Transform::identity() {
+        object
+    } else {
+        Box::new(Transformed::new(object, transform))
+    }
+}
+
+/// Parses a scene description, resolving relative file names against
+/// `base_dir`
+pub fn parse_scene<P: AsRef<Path>>(
+    source: &str,
+    base_dir: P,
+) -> Result<SceneDescription, SceneError> {
+    let tokens = tokenize(source)?;
+    let lines = source.lines().count();
+    let last = source.lines().last().map_or(0, |line| line.chars().count());
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        base_dir: base_dir.as_ref().to_path_buf(),
+        end: (lines.max(1), last + 1),
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        meshes: HashMap::new(),
+        lights: LightList::new(),
+    };
+    parser.parse()
+}
+
+/// Reads and parses a scene file
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<SceneDescription, SceneError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|err| SceneError {
+        line: 0,
+        column: 0,
+        message: format!("cannot read '{}': {}", path.display(), err),
+    })?;
+    parse_scene(&source, path.parent().unwrap_or_else(|| Path::new("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::super::sampler::IndependentSampler;
+    use super::super::tonemap::Operator;
+    use super::super::{HitRecord, Ray};
+    use super::*;
+
+    #[test]
+    fn parse_small_scene() {
+        let description = parse_scene(
+            "image 40 20\n\
+             samples 8\n\
+             sampler halton\n\
+             filter triangle:2\n\
+             mis balance\n\
+             roulette_depth 5\n\
+             integrator spectral\n\
+             exposure -1.5\n\
+             tonemap hable:6\n\
+             camera { from 0 0 5 at 0 0 0 fov 40 }\n\
+             texture tiles checker { odd 0 0 0 even 1 1 1 scale 4 }\n\
+             material floor lambertian { albedo tiles }\n\
+             sphere { center 0 0 0 radius 1 material floor }\n",
+            ".",
+        )
+        .unwrap();
+        assert_eq!((description.width, description.height), (40, 20));
+        assert_eq!(description.samples, 8);
+        assert_eq!(description.sampler, SamplerKind::Halton);
+        assert_eq!(description.filter, Filter::Triangle { radius: 2.0 });
+        assert_eq!(description.heuristic, Heuristic::Balance);
+        assert_eq!(description.roulette_depth, 5);
+        assert_eq!(description.integrator, Integrator::Spectral);
+        assert_eq!(
+            description.tone_map,
+            ToneMap::new(Operator::Hable { white: 6.0 }, -1.5)
+        );
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut hit_record = HitRecord::new();
+        assert!(description
+            .scene
+            .world
+            .hit(&ray, 0.001, 100.0, &mut hit_record));
+        assert_eq!(hit_record.t, 4.0);
+    }
+
+    #[test]
+    fn objects_take_transforms() {
+        let description = parse_scene(
+            "camera { from 0 0 5 }\n\
+             material grey lambertian { albedo 0.5 0.5 0.5 }\n\
+             sphere { center 0 0 0 radius 1 material grey scale 1 1 2 translate 3 0 0 }\n",
+            ".",
+        )
+        .unwrap();
+        let ray = Ray::new(Vec3::new(3.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut hit_record = HitRecord::new();
+        assert!(description
+            .scene
+            .world
+            .hit(&ray, 0.001, 100.0, &mut hit_record));
+        assert!((hit_record.t - 3.0).abs() < 1e-5);
+
+        let description = parse_scene(
+            "camera { from 0 0 5 }\n\
+             material grey lambertian { albedo 0.5 0.5 0.5 }\n\
+             box { min -1 -1 -1 max 1 1 1 material grey rotate 45 0 1 0 }\n\
+             plane { point 0 0 -10 normal 0 0 1 material grey }\n",
+            ".",
+        )
+        .unwrap();
+        let world = &description.scene.world;
+        assert!(world.hit(&ray, 0.001, 100.0, &mut hit_record));
+        assert!((hit_record.t - 15.0).abs() < 1e-4);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        assert!(world.hit(&ray, 0.001, 100.0, &mut hit_record));
+        assert!((hit_record.t - (5.0 - 2f32.sqrt())).abs() < 1e-4);
+
+        let err = parse_scene(
+            "camera { from 0 0 5 }\n\
+             material grey lambertian { albedo 0.5 0.5 0.5 }\n\
+             sphere { center 0 0 0 radius 1 material grey scale 1 0 1 }\n",
+            ".",
+        )
+        .err()
+        .unwrap();
+        assert_eq!((err.line, err.column), (3, 46));
+    }
+
+    #[test]
+    fn redefined_materials_are_not_cached_with_meshes() {
+        let dir = env::temp_dir().join(format!("tracer-parser-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("tri.obj"),
+            "v -1 -1 0\nv 1 -1 0\nv 0 1 0\nf 1 2 3\n",
+        )
+        .unwrap();
+        let description = parse_scene(
+            "camera { from 0 0 5 }\n\
+             material m lambertian { albedo 1 0 0 }\n\
+             mesh { file \"tri.obj\" material m }\n\
+             material m lambertian { albedo 0 1 0 }\n\
+             mesh { file \"tri.obj\" material m translate 3 0 0 }\n",
+            &dir,
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut sampler = IndependentSampler::new(1);
+        let mut albedo = |x: f32| {
+            let ray = Ray::new(Vec3::new(x, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+            let mut hit_record = HitRecord::new();
+            assert!(description
+                .scene
+                .world
+                .hit(&ray, 0.001, 100.0, &mut hit_record));
+            let mut scattered = Ray::new(Vec3::origin(), Vec3::origin());
+            let mut attenuation = Vec3::origin();
+            let mat = hit_record.mat.clone().unwrap();
+            mat.scatter(
+                &ray,
+                &hit_record,
+                &mut attenuation,
+                &mut scattered,
+                &mut sampler,
+            );
+            attenuation
+        };
+        assert_eq!(albedo(0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(albedo(3.0), Vec3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn media_fill_other_objects() {
+        let description = parse_scene(
+            "camera { from 0 0 5 }\n\
+             material fog isotropic { albedo 1 1 1 }\n\
+             medium { density 1e6 material fog boundary box { min -1 -1 -1 max 1 1 1 material fog } }\n",
+            ".",
+        )
+        .unwrap();
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut hit_record = HitRecord::new();
+        assert!(description
+            .scene
+            .world
+            .hit(&ray, 0.001, 100.0, &mut hit_record));
+        assert!((hit_record.t - 4.0).abs() < 1e-3);
+
+        let err = parse_scene(
+            "camera { from 0 0 5 }\n\
+             material fog henyey-greenstein { albedo 1 1 1 g 1.5 }\n",
+            ".",
+        )
+        .err()
+        .unwrap();
+        assert_eq!((err.line, err.column), (2, 47));
+    }
+
+    #[test]
+    fn medium_boundaries_are_not_lights() {
+        let description = parse_scene(
+            "camera { from 0 0 5 }\n\
+             material fog isotropic { albedo 1 1 1 }\n\
+             material lamp light { emit 4 4 4 }\n\
+             medium { density 1 material fog boundary sphere { center 0 0 0 radius 1 material lamp } }\n\
+             medium { density 1 material fog boundary quad { corner 2 0 0 u 1 0 0 v 0 0 1 material lamp } }\n\
+             sphere { center 0 5 0 radius 1 material lamp }\n",
+            ".",
+        )
+        .unwrap();
+        assert_eq!(description.scene.lights.lights.len(), 1);
+    }
+
+    #[test]
+    fn errors_report_line_and_column() {
+        let err = parse_scene(
+            "camera { from 0 0 5 }\nmaterial red lambertian { albedo 1 0 zero }\n",
+            ".",
+        )
+        .err()
+        .unwrap();
+        assert_eq!((err.line, err.column), (2, 38));
+
+        let err = parse_scene("camera { from 0 0 5 }\nsphere { radius 1 }\n", ".")
+            .err()
+            .unwrap();
+        assert_eq!((err.line, err.column), (2, 1));
+
+        let err = parse_scene(
+            "camera { from 0 0 5 }\nmaterial m conductor { metal tin }\n",
+            ".",
+        )
+        .err()
+        .unwrap();
+        assert_eq!((err.line, err.column), (2, 30));
+        assert_eq!(err.message, "unknown metal 'tin'");
+
+        let err = parse_scene("samples 0\ncamera { from 0 0 5 }\n", ".")
+            .err()
+            .unwrap();
+        assert_eq!((err.line, err.column), (1, 9));
+        assert_eq!(err.message, "samples must be positive");
+
+        let err = parse_scene(
+            "camera { from 0 0 5 }\nmaterial m dielectric { glass crown }\n",
+            ".",
+        )
+        .err()
+        .unwrap();
+        assert_eq!((err.line, err.column), (2, 31));
+        assert_eq!(err.message, "unknown glass 'crown'");
+        let err = parse_scene(
+            "camera { from 0 0 5 }\nmaterial m dielectric { cauchy 1.5 0.004 roughness 0.1 }\n",
+            ".",
+        )
+        .err()
+        .unwrap();
+        assert_eq!((err.line, err.column), (2, 12));
+    }
+}
@@ -0,0 +1,448 @@
+//! Minimal PNG encoder: 8-bit RGB images compressed with fixed-code deflate.
+
+use std::io::{self, Write};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Longest hash chain followed when looking for a match
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+        *entry = c;
+    }
+    table
+}
+
+/// CRC-32 as used by PNG chunks (and zip, gzip)
+pub fn crc32(data: &[u8]) -> u32 {
+    let table = crc_table();
+    !data.iter().fold(!0u32, |crc, &byte| {
+        table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Adler-32 checksum that ends a zlib stream
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let mut a = 1u32;
+    let mut b = 0u32;
+    // 5552 is the most bytes that can be summed before `b` could overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+/// Packs variable-length codes into bytes, least significant bit first
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            out: Vec::new(),
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are defined most significant bit first, so they are
+    /// reversed before packing
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn write_literal(bits: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => bits.write_code(0x30 + symbol, 8),
+        144..=255 => bits.write_code(0x190 + symbol - 144, 9),
+        256..=279 => bits.write_code(symbol - 256, 7),
+        _ => bits.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(bits: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= length)
+        .unwrap();
+    write_literal(bits, 257 + code as u32);
+    bits.write(
+        (length - LENGTH_BASE[code] as usize) as u32,
+        LENGTH_EXTRA[code] as u32,
+    );
+    let code = DIST_BASE
+        .iter()
+        .rposition(|&base| base as usize <= distance)
+        .unwrap();
+    bits.write_code(code as u32, 5);
+    bits.write(
+        (distance - DIST_BASE[code] as usize) as u32,
+        DIST_EXTRA[code] as u32,
+    );
+}
+
+#[inline(always)]
+fn hash(data: &[u8], i: usize) -> usize {
+    let v = (data[i] as usize) << 16 | (data[i + 1] as usize) << 8 | data[i + 2] as usize;
+    (v.wrapping_mul(2_654_435_761) >> 8) & ((1 << HASH_BITS) - 1)
+}
+
+/// Compresses `data` as a single deflate block using the fixed Huffman
+/// codes, finding repeats with greedy hash-chain matching
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::new();
+    // Final block, fixed codes
+    bits.write(1, 1);
+    bits.write(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let insert = |head: &mut Vec<usize>, prev: &mut Vec<usize>, i: usize| {
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(data, i);
+            prev[i % WINDOW_SIZE] = head[h];
+            head[h] = i;
+        }
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best_length = 0;
+        let mut best_distance = 0;
+        if i + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - i);
+            let mut candidate = head[hash(data, i)];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = data[candidate..candidate + max_length]
+                    .iter()
+                    .zip(data[i..i + max_length].iter())
+                    .take_while(|&(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = i - candidate;
+                    if length == max_length {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW_SIZE];
+                // Entries older than the window may have been overwritten
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            write_match(&mut bits, best_length, best_distance);
+            for j in i..i + best_length {
+                insert(&mut head, &mut prev, j);
+            }
+            i += best_length;
+        } else {
+            write_literal(&mut bits, data[i] as u32);
+            insert(&mut head, &mut prev, i);
+            i += 1;
+        }
+    }
+    write_literal(&mut bits, 256);
+    bits.finish()
+}
+
+/// Wraps a deflate stream in a zlib header and Adler-32 trailer
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    // 32K window, deflate, and a check value making the header a multiple of 31
+    let mut out = vec![0x78, 0x01];
+    out.extend(deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    let mut body = Vec::with_capacity(data.len() + 4);
+    body.extend_from_slice(kind);
+    body.extend_from_slice(data);
+    writer.write_all(&body)?;
+    writer.write_all(&crc32(&body).to_be_bytes())
+}
+
+#[inline(always)]
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Applies the PNG filter that gives the smallest sum of absolute
+/// differences to each row, prefixing the row with its filter type
+fn filter_rows(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let stride = width * 3;
+    let zero = vec![0u8; stride];
+    let mut out = Vec::with_capacity((stride + 1) * height);
+    let mut candidate = vec![0u8; stride];
+    let mut best = vec![0u8; stride];
+    for y in 0..height {
+        let row = &rgb[y * stride..(y + 1) * stride];
+        let above = if y == 0 {
+            &zero[..]
+        } else {
+            &rgb[(y - 1) * stride..y * stride]
+        };
+        let mut best_type = 0;
+        let mut best_score = u64::MAX;
+        for filter in 0..5u8 {
+            for x in 0..stride {
+                let a = if x >= 3 { row[x - 3] } else { 0 };
+                let b = above[x];
+                let c = if x >= 3 { above[x - 3] } else { 0 };
+                let predicted = match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                candidate[x] = row[x].wrapping_sub(predicted);
+            }
+            let score = candidate
+                .iter()
+                .map(|&v| (v as i8).unsigned_abs() as u64)
+                .sum();
+            if score < best_score {
+                best_score = score;
+                best_type = filter;
+                best.copy_from_slice(&candidate);
+            }
+        }
+        out.push(best_type);
+        out.extend_from_slice(&best);
+    }
+    out
+}
+
+/// Encodes 8-bit RGB samples, stored row by row from the top, as a PNG file
+pub fn write_png_rgb8<W: Write>(
+    mut writer: W,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+) -> io::Result<()> {
+    assert_eq!(rgb.len(), width * height * 3);
+    writer.write_all(&SIGNATURE)?;
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, truecolour, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut writer, b"IHDR", &header)?;
+    write_chunk(
+        &mut writer,
+        b"IDAT",
+        &zlib(&filter_rows(rgb, width, height)),
+    )?;
+    write_chunk(&mut writer, b"IEND", &[])?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the bits of a deflate stream, least significant bit first
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn read(&mut self, count: u32) -> u32 {
+            let mut value = 0;
+            for i in 0..count {
+                let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+                value |= (bit as u32) << i;
+                self.pos += 1;
+            }
+            value
+        }
+
+        /// Huffman codes are stored most significant bit first
+        fn read_code(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |code, _| code << 1 | self.read(1))
+        }
+
+        fn read_literal(&mut self) -> u32 {
+            let code = self.read_code(7);
+            if code <= 0x17 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.read(1);
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => 280 + code - 0xc0,
+                _ => 144 + (code << 1 | self.read(1)) - 0x190,
+            }
+        }
+    }
+
+    /// Decompresses a single final deflate block with fixed Huffman codes
+    fn inflate_fixed(data: &[u8]) -> Vec<u8> {
+        let mut bits = BitReader { data, pos: 0 };
+        assert_eq!(bits.read(1), 1);
+        assert_eq!(bits.read(2), 1);
+        let mut out = Vec::new();
+        loop {
+            match bits.read_literal() {
+                literal @ 0..=255 => out.push(literal as u8),
+                256 => return out,
+                symbol => {
+                    let code = (symbol - 257) as usize;
+                    let length =
+                        LENGTH_BASE[code] as usize + bits.read(LENGTH_EXTRA[code] as u32) as usize;
+                    let code = bits.read_code(5) as usize;
+                    let distance =
+                        DIST_BASE[code] as usize + bits.read(DIST_EXTRA[code] as u32) as usize;
+                    for _ in 0..length {
+                        let byte = out[out.len() - distance];
+                        out.push(byte);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Splits a PNG file into the kind and data of its chunks, checking
+    /// their CRCs
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(png[..8], SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let body = &rest[4..8 + length];
+            let crc = &rest[8 + length..12 + length];
+            assert_eq!(crc32(body).to_be_bytes(), crc);
+            chunks.push(([body[0], body[1], body[2], body[3]], body[4..].to_vec()));
+            rest = &rest[12 + length..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let (width, height) = (37, 23);
+        // Gradients suit the predicting filters, and the repeated stripes give
+        // the compressor matches to find
+        let rgb: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                vec![
+                    (x * 7) as u8,
+                    (y * 11) as u8,
+                    if x % 8 < 4 { 200 } else { 30 },
+                ]
+            })
+            .collect();
+        let mut png = Vec::new();
+        write_png_rgb8(&mut png, width, height, &rgb).unwrap();
+
+        let chunks = chunks(&png);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|chunk| &chunk.0).collect();
+        assert_eq!(kinds, vec![b"IHDR", b"IDAT", b"IEND"]);
+        let header = &chunks[0].1;
+        assert_eq!(header[..4], (width as u32).to_be_bytes());
+        assert_eq!(header[4..8], (height as u32).to_be_bytes());
+        assert_eq!(header[8..], [8, 2, 0, 0, 0]);
+
+        let stream = &chunks[1].1;
+        assert_eq!(u16::from_be_bytes([stream[0], stream[1]]) % 31, 0);
+        let filtered = inflate_fixed(&stream[2..stream.len() - 4]);
+        assert_eq!(filtered, filter_rows(&rgb, width, height));
+        assert_eq!(stream[stream.len() - 4..], adler32(&filtered).to_be_bytes());
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[]), 1);
+    }
+
+    #[test]
+    fn deflate_fixed_codes() {
+        // A single literal 'a' followed by end-of-block, as zlib produces it
+        assert_eq!(deflate(b"a"), vec![0x4b, 0x04, 0x00]);
+        // Long runs collapse into back-references
+        let data = vec![7u8; 10_000];
+        assert!(deflate(&data).len() < 100);
+    }
+}
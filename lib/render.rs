@@ -0,0 +1,882 @@
+use std::f32;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use super::camera::Camera;
+use super::film::{Film, FilmTile};
+use super::filter::Filter;
+use super::sampler::{Sampler, SamplerKind};
+use super::scene::Scene;
+use super::spectrum::{SampledSpectrum, SampledWavelengths};
+use super::{HitRecord, Ray, Vec3};
+
+/// Linear RGB image, stored row by row starting from the top of the picture
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Vec3::origin(); width * height],
+        }
+    }
+
+    #[inline(always)]
+    pub fn get(&self, x: usize, y: usize) -> &Vec3 {
+        &self.pixels[y * self.width + x]
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, x: usize, y: usize, col: Vec3) {
+        self.pixels[y * self.width + x] = col;
+    }
+}
+
+/// How multiple importance sampling weighs a light sample against a BSDF
+/// sample that could have found the same light
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Heuristic {
+    /// Each strategy in proportion to its density
+    Balance,
+    /// In proportion to the squared densities, which trusts whichever
+    /// strategy is much better still more
+    #[default]
+    Power,
+}
+
+impl Heuristic {
+    /// Weight of a sample taken with density `pdf`, where the other strategy
+    /// would have picked it with density `other`
+    #[inline(always)]
+    pub fn weight(self, pdf: f32, other: f32) -> f32 {
+        let (pdf, other) = match self {
+            Heuristic::Balance => (pdf, other),
+            Heuristic::Power => (pdf * pdf, other * other),
+        };
+        if pdf + other > 0.0 {
+            pdf / (pdf + other)
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for Heuristic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Heuristic::Balance => write!(f, "balance"),
+            Heuristic::Power => write!(f, "power"),
+        }
+    }
+}
+
+impl FromStr for Heuristic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Heuristic, String> {
+        match s {
+            "balance" => Ok(Heuristic::Balance),
+            "power" => Ok(Heuristic::Power),
+            _ => Err(format!("unknown MIS heuristic '{}'", s)),
+        }
+    }
+}
+
+/// What paths carry: red, green and blue, or light at a few wavelengths,
+/// which is slower but shows how dispersive glass splits white light
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Integrator {
+    #[default]
+    Rgb,
+    Spectral,
+}
+
+impl fmt::Display for Integrator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Integrator::Rgb => write!(f, "rgb"),
+            Integrator::Spectral => write!(f, "spectral"),
+        }
+    }
+}
+
+impl FromStr for Integrator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Integrator, String> {
+        match s {
+            "rgb" => Ok(Integrator::Rgb),
+            "spectral" => Ok(Integrator::Spectral),
+            _ => Err(format!("unknown integrator '{}'", s)),
+        }
+    }
+}
+
+/// The channels of a path's throughput and radiance: red, green and blue
+/// in the first three, or the wavelengths picked for the path
+#[derive(Debug, Clone, Copy)]
+enum Channels {
+    Rgb,
+    Spectral(SampledWavelengths),
+}
+
+impl Channels {
+    /// The colour `rgb`, which the materials and lights work in, in each
+    /// channel
+    fn spectrum(&self, rgb: &Vec3) -> SampledSpectrum {
+        match *self {
+            Channels::Rgb => SampledSpectrum::new([rgb.r(), rgb.g(), rgb.b(), 0.0]),
+            Channels::Spectral(ref wavelengths) => SampledSpectrum::from_rgb(rgb, wavelengths),
+        }
+    }
+
+    fn rgb(&self, spectrum: &SampledSpectrum) -> Vec3 {
+        let values = spectrum.values;
+        match *self {
+            Channels::Rgb => Vec3::new(values[0], values[1], values[2]),
+            Channels::Spectral(ref wavelengths) => wavelengths.to_rgb(spectrum),
+        }
+    }
+
+    /// Brightness of `spectrum`, for Russian roulette
+    fn luminance(&self, spectrum: &SampledSpectrum) -> f32 {
+        match *self {
+            Channels::Rgb => self.rgb(spectrum).luminance(),
+            Channels::Spectral(ref wavelengths) if wavelengths.secondary_terminated() => {
+                spectrum.values[0]
+            }
+            Channels::Spectral(_) => spectrum.average(),
+        }
+    }
+
+    /// `ray` carrying the hero wavelength, which dispersive materials refract
+    /// it at
+    fn tag(&self, ray: Ray) -> Ray {
+        match *self {
+            Channels::Rgb => ray,
+            Channels::Spectral(ref wavelengths) => ray.with_wavelength(wavelengths.hero()),
+        }
+    }
+
+    /// Follows only the wavelength a dispersive material refracted the path
+    /// at from now on
+    fn terminate_secondary(&mut self) {
+        if let Channels::Spectral(ref mut wavelengths) = *self {
+            wavelengths.terminate_secondary();
+        }
+    }
+}
+
+/// Renders an image by splitting it into square tiles which are handed out to
+/// a pool of worker threads. Each worker has its own sampler, which derives
+/// every sample from `seed`, the pixel index and the sample index, and the
+/// tiles are added to the film in order, so the result is the same for any
+/// number of threads.
+#[derive(Debug, Clone)]
+pub struct Renderer {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    /// Bounces every path takes before Russian roulette may end it
+    pub roulette_depth: i32,
+    /// Bounces after which a path ends regardless, as a safety net for
+    /// paths that roulette keeps alive
+    pub max_depth: i32,
+    pub threads: usize,
+    pub tile_size: usize,
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    pub filter: Filter,
+    pub heuristic: Heuristic,
+    pub integrator: Integrator,
+}
+
+impl Renderer {
+    pub fn new(width: usize, height: usize, samples: usize) -> Renderer {
+        Renderer {
+            width,
+            height,
+            samples,
+            roulette_depth: 3,
+            max_depth: 50,
+            threads: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            tile_size: 32,
+            seed: 0,
+            sampler: SamplerKind::default(),
+            filter: Filter::default(),
+            heuristic: Heuristic::default(),
+            integrator: Integrator::default(),
+        }
+    }
+
+    /// Radiance arriving along `ray`. Every bounce off a material that is not
+    /// specular sends a shadow ray to one of `scene.lights`, and the light
+    /// found that way and the light found by the scattered ray are combined
+    /// with multiple importance sampling. Once a path is `roulette_depth`
+    /// bounces long it survives each further bounce with a chance that
+    /// follows the brightness of its throughput.
+    ///
+    /// The spectral integrator traces each path at four wavelengths, upsamples
+    /// the RGB colours of materials and lights to spectra along the way, and
+    /// turns the result back into RGB through CIE XYZ. Past a dispersive
+    /// material, only the first of the wavelengths is followed.
+    pub fn radiance(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler) -> Vec3 {
+        let mut channels = match self.integrator {
+            Integrator::Rgb => Channels::Rgb,
+            Integrator::Spectral => {
+                Channels::Spectral(SampledWavelengths::sample(sampler.get_1d()))
+            }
+        };
+        let radiance = self.trace(ray, scene, &mut channels, sampler);
+        channels.rgb(&radiance)
+    }
+
+    fn trace(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        channels: &mut Channels,
+        sampler: &mut dyn Sampler,
+    ) -> SampledSpectrum {
+        let mut radiance = SampledSpectrum::constant(0.0);
+        let mut throughput = SampledSpectrum::constant(1.0);
+        let mut ray = channels.tag(ray.clone());
+        // Density with which the last bounce picked `ray`, or `None` for
+        // camera rays and rays leaving specular surfaces, which light sampling
+        // cannot reproduce
+        let mut bsdf_pdf = None;
+        let mut hit_record = HitRecord::new();
+        let mut depth = 0;
+        loop {
+            ray = ray.with_medium_sample(sampler.get_1d());
+            if !scene.world.hit(&ray, 0.001, f32::MAX, &mut hit_record) {
+                radiance += throughput * channels.spectrum(&scene.background.radiance(&ray));
+                break;
+            }
+            let mat = hit_record.mat.clone().unwrap();
+            // Lights only shine from the side their normal faces
+            let mut emitted = if hit_record.front_face(&ray) {
+                mat.emitted(hit_record.u, hit_record.v, &hit_record.p)
+            } else {
+                Vec3::origin()
+            };
+            if let Some(bsdf_pdf) = bsdf_pdf {
+                if mat.is_emissive() {
+                    // Only lights up to the one hit could have been found by
+                    // light sampling
+                    let t_max = hit_record.t * (1.0 + 1e-3);
+                    let light_pdf = scene.lights.pdf(&ray.origin(), &ray.direction(), t_max);
+                    emitted = self.heuristic.weight(bsdf_pdf, light_pdf) * emitted;
+                }
+            }
+            radiance += throughput * channels.spectrum(&emitted);
+
+            let mut scattered = Ray::new(Vec3::origin(), Vec3::origin());
+            let mut attenuation = Vec3::origin();
+            if depth >= self.max_depth
+                || !mat.scatter(&ray, &hit_record, &mut attenuation, &mut scattered, sampler)
+            {
+                break;
+            }
+            if mat.is_specular() {
+                bsdf_pdf = None;
+            } else {
+                let direct = self.direct_light(&ray, scene, &hit_record, channels, sampler);
+                radiance += throughput * direct;
+                bsdf_pdf = Some(mat.pdf(&ray, &hit_record, &scattered.direction()));
+            }
+            if mat.is_dispersive() {
+                channels.terminate_secondary();
+            }
+            throughput *= channels.spectrum(&attenuation);
+            depth += 1;
+
+            if depth >= self.roulette_depth {
+                // Dim paths are cut short, and the survivors brightened to
+                // make up for them
+                let survival = channels.luminance(&throughput).min(1.0);
+                if sampler.get_1d() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+            ray = channels.tag(scattered);
+        }
+        radiance
+    }
+
+    /// Light reaching `hit_record` straight from a randomly picked light and
+    /// scattered back along `ray`, weighted against finding the same light by
+    /// scattering
+    fn direct_light(
+        &self,
+        ray: &Ray,
+        scene: &Scene,
+        hit_record: &HitRecord,
+        channels: &Channels,
+        sampler: &mut dyn Sampler,
+    ) -> SampledSpectrum {
+        let black = SampledSpectrum::constant(0.0);
+        // The samples are drawn even without lights so that later bounces use
+        // the same sample dimensions either way
+        let u_light = sampler.get_1d();
+        let u = sampler.get_2d();
+        let u_medium = sampler.get_1d();
+        let sample = match scene.lights.sample(&hit_record.p, u_light, u) {
+            Some(sample) => sample,
+            None => return black,
+        };
+        let mat = hit_record.mat.as_ref().unwrap();
+        let f = mat.eval(ray, hit_record, &sample.direction);
+        if f == Vec3::origin() {
+            return black;
+        }
+        let distance = sample.direction.length();
+        let shadow = Ray::with_time(
+            hit_record.p.clone(),
+            &sample.direction / distance,
+            ray.time(),
+        )
+        .with_medium_sample(u_medium);
+        // Anything hit clearly before the point on the light is in the way
+        let epsilon = 1e-3 * distance.max(1.0);
+        let mut light_record = HitRecord::new();
+        if !scene
+            .world
+            .hit(&shadow, 0.001, distance + epsilon, &mut light_record)
+            || light_record.t < distance - epsilon
+            || !light_record.front_face(&shadow)
+        {
+            return black;
+        }
+        let light = light_record.mat.unwrap();
+        let emitted = light.emitted(light_record.u, light_record.v, &light_record.p);
+        let bsdf_pdf = mat.pdf(ray, hit_record, &sample.direction);
+        let weight = self.heuristic.weight(sample.pdf, bsdf_pdf);
+        weight / sample.pdf * channels.spectrum(&f) * channels.spectrum(&emitted)
+    }
+
+    pub fn render(&self, camera: &Camera, scene: &Scene) -> Framebuffer {
+        let tile_size = self.tile_size.max(1);
+        let tiles_x = self.width.div_ceil(tile_size);
+        let tiles_y = self.height.div_ceil(tile_size);
+        let tile_count = tiles_x * tiles_y;
+
+        // Finished tiles wait here until every tile before them is merged
+        let film = Mutex::new((
+            Film::new(self.width, self.height, self.filter),
+            0,
+            Vec::new(),
+        ));
+        let next_tile = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..self.threads.clamp(1, tile_count.max(1)) {
+                scope.spawn(|| {
+                    let mut sampler = self.sampler.create(self.samples, self.seed);
+                    loop {
+                        let tile = next_tile.fetch_add(1, Ordering::Relaxed);
+                        if tile >= tile_count {
+                            break;
+                        }
+                        let x0 = (tile % tiles_x) * tile_size;
+                        let y0 = (tile / tiles_x) * tile_size;
+                        let x1 = (x0 + tile_size).min(self.width);
+                        let y1 = (y0 + tile_size).min(self.height);
+
+                        let mut film_tile = film.lock().unwrap().0.tile(x0, y0, x1, y1);
+                        for y in y0..y1 {
+                            for x in x0..x1 {
+                                self.render_pixel(
+                                    camera,
+                                    scene,
+                                    &mut *sampler,
+                                    &mut film_tile,
+                                    x,
+                                    y,
+                                );
+                            }
+                        }
+
+                        let mut guard = film.lock().unwrap();
+                        let (ref mut film, ref mut next_merge, ref mut pending) = *guard;
+                        pending.push((tile, film_tile));
+                        while let Some(i) = pending.iter().position(|&(t, _)| t == *next_merge) {
+                            film.merge(&pending.swap_remove(i).1);
+                            *next_merge += 1;
+                        }
+                    }
+                });
+            }
+        });
+        film.into_inner().unwrap().0.framebuffer()
+    }
+
+    fn render_pixel(
+        &self,
+        camera: &Camera,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        film_tile: &mut FilmTile,
+        x: usize,
+        y: usize,
+    ) {
+        let pixel = (y * self.width + x) as u64;
+        for sample in 0..self.samples {
+            sampler.start_pixel_sample(pixel, sample);
+            let (dx, dy) = sampler.get_2d();
+            let (film_x, film_y) = (x as f32 + dx, y as f32 + dy);
+            // Rows are stored top first, but the camera's v axis points up
+            let u = film_x / self.width as f32;
+            let v = 1.0 - film_y / self.height as f32;
+            let ray = camera.ray(u, v, sampler);
+            let col = self.radiance(&ray, scene, sampler);
+            film_tile.add_sample(film_x, film_y, &col);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::light::LightList;
+    use super::super::materials::{
+        Dielectric, DiffuseLight, Dispersion, DispersiveDielectric, Isotropic, Lambertian, Metal,
+    };
+    use super::super::medium::ConstantMedium;
+    use super::super::sampler::IndependentSampler;
+    use super::super::scene::Background;
+    use super::super::shapes::{Plane, Quad};
+    use super::super::{Hit, HitList, Sphere};
+    use super::*;
+
+    #[test]
+    fn render_is_independent_of_thread_count() {
+        let mut world = HitList::new(3);
+        world.list.push(Sphere::new(
+            Vec3::new(0.0, -100.5, -1.0),
+            100.0,
+            Box::new(Lambertian::new(Vec3::new(0.8, 0.8, 0.0))),
+        ));
+        world.list.push(Sphere::new(
+            Vec3::new(0.0, 0.0, -1.0),
+            0.5,
+            Box::new(Metal::new(Vec3::new(0.8, 0.6, 0.2), 0.3)),
+        ));
+        world.list.push(Sphere::new(
+            Vec3::new(1.0, 0.0, -1.0),
+            0.5,
+            Box::new(Dielectric::new(1.5)),
+        ));
+        let camera = Camera::new(
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            60.0,
+            2.0,
+            0.1,
+            2.0,
+        );
+
+        let scene = Scene::new(Box::new(world), Background::sky());
+
+        let mut renderer = Renderer::new(24, 12, 4);
+        renderer.tile_size = 5;
+        renderer.threads = 1;
+        let single = renderer.render(&camera, &scene);
+        renderer.threads = 4;
+        let multi = renderer.render(&camera, &scene);
+        assert_eq!(single.pixels, multi.pixels);
+
+        // Tiles of a different size visit pixels in another order
+        renderer.tile_size = 7;
+        assert_eq!(renderer.render(&camera, &scene).pixels, single.pixels);
+        renderer.seed = 1;
+        assert_ne!(renderer.render(&camera, &scene).pixels, single.pixels);
+
+        renderer.sampler = SamplerKind::Sobol;
+        renderer.filter = Filter::Mitchell {
+            radius: 2.0,
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        };
+        renderer.threads = 1;
+        let single = renderer.render(&camera, &scene);
+        renderer.threads = 3;
+        assert_eq!(renderer.render(&camera, &scene).pixels, single.pixels);
+    }
+
+    #[test]
+    fn emitters_light_a_dark_scene() {
+        let mut world = HitList::new(2);
+        world.list.push(Sphere::new(
+            Vec3::new(0.0, 3.0, 0.0),
+            2.0,
+            Box::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0))),
+        ));
+        world.list.push(Sphere::new(
+            Vec3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+        ));
+        let scene = Scene::new(Box::new(world), Background::Solid(Vec3::origin()));
+
+        let renderer = Renderer::new(1, 1, 1);
+        let light = Ray::new(Vec3::new(0.0, 3.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut sampler = IndependentSampler::new(1);
+        assert_eq!(
+            renderer.radiance(&light, &scene, &mut sampler),
+            Vec3::new(4.0, 4.0, 4.0)
+        );
+        let floor = Ray::new(Vec3::new(0.0, 1.0, 5.0), Vec3::new(0.0, -1.0, -2.0));
+        let mut total = Vec3::origin();
+        for _ in 0..256 {
+            total += renderer.radiance(&floor, &scene, &mut sampler);
+        }
+        assert!(total.r() > 0.0);
+    }
+
+    #[test]
+    fn direct_lighting_matches_the_analytic_irradiance() {
+        // A sphere of radiance 4 seen at a half angle of asin(1/4) from
+        // straight above gives a white floor the irradiance pi 4 / 16, which
+        // an albedo of 0.5 reflects as a radiance of 0.125
+        let lamp = Sphere::new(
+            Vec3::new(0.0, 2.0, 0.0),
+            0.5,
+            Box::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0))),
+        );
+        let mut world = HitList::new(2);
+        world.list.push(Box::new(lamp.clone()) as Box<dyn Hit>);
+        world.list.push(Box::new(Plane::new(
+            Vec3::origin(),
+            &Vec3::new(0.0, 1.0, 0.0),
+            Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+        )));
+        let mut lights = LightList::new();
+        lights.push(lamp);
+        let scene =
+            Scene::new(Box::new(world), Background::Solid(Vec3::origin())).with_lights(lights);
+
+        let mut renderer = Renderer::new(1, 1, 1);
+        renderer.max_depth = 1;
+        let ray = Ray::new(Vec3::new(0.0, 0.01, 1.0), Vec3::new(0.0, -0.01, -1.0));
+        for &heuristic in &[Heuristic::Balance, Heuristic::Power] {
+            renderer.heuristic = heuristic;
+            let mean = mean_radiance(&renderer, &scene, &ray, 2000);
+            assert!((mean - 0.125).abs() < 0.002, "{}: {}", heuristic, mean);
+        }
+    }
+
+    #[test]
+    fn lights_hidden_behind_lights_do_not_bias_mis() {
+        // A second lamp straight behind the first, which it hides from the
+        // floor entirely, so the floor sees just the first lamp's 0.125
+        let near = Sphere::new(
+            Vec3::new(0.0, 2.0, 0.0),
+            0.5,
+            Box::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0))),
+        );
+        let far = Sphere::new(
+            Vec3::new(0.0, 4.0, 0.0),
+            0.5,
+            Box::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0))),
+        );
+        let mut world = HitList::new(3);
+        world.list.push(Box::new(near.clone()) as Box<dyn Hit>);
+        world.list.push(Box::new(far.clone()));
+        world.list.push(Box::new(Plane::new(
+            Vec3::origin(),
+            &Vec3::new(0.0, 1.0, 0.0),
+            Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+        )));
+        let mut lights = LightList::new();
+        lights.push(near);
+        lights.push(far);
+        let scene =
+            Scene::new(Box::new(world), Background::Solid(Vec3::origin())).with_lights(lights);
+
+        let mut renderer = Renderer::new(1, 1, 1);
+        renderer.max_depth = 1;
+        let ray = Ray::new(Vec3::new(0.0, 0.01, 1.0), Vec3::new(0.0, -0.01, -1.0));
+        for &heuristic in &[Heuristic::Balance, Heuristic::Power] {
+            renderer.heuristic = heuristic;
+            let mean = mean_radiance(&renderer, &scene, &ray, 20000);
+            assert!((mean - 0.125).abs() < 0.002, "{}: {}", heuristic, mean);
+        }
+    }
+
+    /// Green channel of the radiance along `ray`, averaged over `n` paths
+    fn mean_radiance(renderer: &Renderer, scene: &Scene, ray: &Ray, n: usize) -> f32 {
+        let mut sampler = IndependentSampler::new(2);
+        let total: f32 = (0..n)
+            .map(|_| renderer.radiance(ray, scene, &mut sampler).g())
+            .sum();
+        total / n as f32
+    }
+
+    #[test]
+    fn white_furnace_reflects_its_albedo() {
+        // A diffuse sphere inside a cube whose walls glow inwards with
+        // radiance 1 sees radiance 1 from every direction
+        let (x, y, z) = (
+            Vec3::new(20.0, 0.0, 0.0),
+            Vec3::new(0.0, 20.0, 0.0),
+            Vec3::new(0.0, 0.0, 20.0),
+        );
+        let low = Vec3::new(-10.0, -10.0, -10.0);
+        let walls = vec![
+            (low.clone(), y.clone(), z.clone()),
+            (&low + &x, z.clone(), y.clone()),
+            (low.clone(), z.clone(), x.clone()),
+            (&low + &y, x.clone(), z.clone()),
+            (low.clone(), x.clone(), y.clone()),
+            (&low + &z, y, x),
+        ];
+        let mut world = HitList::new(7);
+        let mut lights = LightList::new();
+        for (q, u, v) in walls {
+            let wall = Quad::new(
+                q,
+                u,
+                v,
+                Box::new(DiffuseLight::new(Vec3::new(1.0, 1.0, 1.0))),
+            );
+            world.list.push(Box::new(wall.clone()) as Box<dyn Hit>);
+            lights.push(wall);
+        }
+        world.list.push(Box::new(Sphere::new(
+            Vec3::origin(),
+            1.0,
+            Box::new(Lambertian::new(Vec3::new(0.8, 0.8, 0.8))),
+        )));
+        let scene =
+            Scene::new(Box::new(world), Background::Solid(Vec3::origin())).with_lights(lights);
+
+        let mut renderer = Renderer::new(1, 1, 1);
+        let ray = Ray::new(Vec3::new(0.3, 0.2, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        for &heuristic in &[Heuristic::Balance, Heuristic::Power] {
+            renderer.heuristic = heuristic;
+            let mean = mean_radiance(&renderer, &scene, &ray, 4000);
+            assert!((mean - 0.8).abs() < 0.01, "{}: {}", heuristic, mean);
+        }
+    }
+
+    #[test]
+    fn quad_lights_are_dark_from_behind() {
+        // The same lamp over a floor, facing it or facing away
+        let lit = |u: Vec3, v: Vec3| {
+            let lamp = Quad::new(
+                Vec3::new(-0.5, 2.0, -0.5),
+                u,
+                v,
+                Box::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0))),
+            );
+            let mut world = HitList::new(2);
+            world.list.push(Box::new(lamp.clone()) as Box<dyn Hit>);
+            world.list.push(Box::new(Plane::new(
+                Vec3::origin(),
+                &Vec3::new(0.0, 1.0, 0.0),
+                Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+            )));
+            let mut lights = LightList::new();
+            lights.push(lamp);
+            let scene =
+                Scene::new(Box::new(world), Background::Solid(Vec3::origin())).with_lights(lights);
+            let mut renderer = Renderer::new(1, 1, 1);
+            renderer.max_depth = 1;
+            let ray = Ray::new(Vec3::new(0.0, 0.01, 1.0), Vec3::new(0.0, -0.01, -1.0));
+            mean_radiance(&renderer, &scene, &ray, 1000)
+        };
+        let (x, z) = (Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        assert!(lit(x.clone(), z.clone()) > 0.0);
+        assert_eq!(lit(z, x), 0.0);
+    }
+
+    #[test]
+    fn mis_agrees_with_bsdf_sampling_on_glossy_metal() {
+        // A small lamp reflected in slightly rough metal, which is where
+        // light sampling alone is noisiest and scattering alone is still
+        // good enough to compare against
+        let lamp = Sphere::new(
+            Vec3::new(2.0, 2.0, 0.0),
+            0.5,
+            Box::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0))),
+        );
+        let world = || {
+            let mut world = HitList::new(2);
+            world.list.push(Box::new(lamp.clone()) as Box<dyn Hit>);
+            world.list.push(Box::new(Plane::new(
+                Vec3::origin(),
+                &Vec3::new(0.0, 1.0, 0.0),
+                Box::new(Metal::new(Vec3::new(0.9, 0.9, 0.9), 0.3)),
+            )));
+            Box::new(world)
+        };
+        let unlit = Scene::new(world(), Background::Solid(Vec3::origin()));
+        let mut lights = LightList::new();
+        lights.push(lamp.clone());
+        let lit = Scene::new(world(), Background::Solid(Vec3::origin())).with_lights(lights);
+
+        let mut renderer = Renderer::new(1, 1, 1);
+        renderer.max_depth = 1;
+        let ray = Ray::new(Vec3::new(-2.0, 2.0, 0.1), Vec3::new(1.0, -1.0, 0.0));
+        let reference = mean_radiance(&renderer, &unlit, &ray, 40000);
+        assert!(reference > 0.5, "{}", reference);
+        for &heuristic in &[Heuristic::Balance, Heuristic::Power] {
+            renderer.heuristic = heuristic;
+            let mean = mean_radiance(&renderer, &lit, &ray, 10000);
+            assert!(
+                (mean - reference).abs() < 0.03 * reference,
+                "{}: {} != {}",
+                heuristic,
+                mean,
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn russian_roulette_keeps_deep_paths_unbiased() {
+        // Light leaves a dense cloud after dozens of scattering events, most
+        // of them on paths that have grown dim
+        let cloud = ConstantMedium::new(
+            Sphere::new(
+                Vec3::origin(),
+                1.0,
+                Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5))),
+            ),
+            4.0,
+            Box::new(Isotropic::new(Vec3::new(0.9, 0.9, 0.9))),
+        );
+        let scene = Scene::new(Box::new(cloud), Background::Solid(Vec3::new(1.0, 1.0, 1.0)));
+
+        let mut renderer = Renderer::new(1, 1, 1);
+        renderer.max_depth = 1000;
+        renderer.roulette_depth = renderer.max_depth;
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let reference = mean_radiance(&renderer, &scene, &ray, 20000);
+        renderer.roulette_depth = 2;
+        let mean = mean_radiance(&renderer, &scene, &ray, 20000);
+        assert!(
+            (mean - reference).abs() < 0.02 * reference,
+            "{} != {}",
+            mean,
+            reference
+        );
+
+        // Cutting every path at a fixed depth instead loses the light that
+        // takes many bounces
+        renderer.max_depth = 5;
+        renderer.roulette_depth = renderer.max_depth;
+        let capped = mean_radiance(&renderer, &scene, &ray, 20000);
+        assert!(capped < 0.9 * reference, "{} vs {}", capped, reference);
+    }
+
+    #[test]
+    fn glass_keeps_the_energy_it_does_not_absorb() {
+        // Straight through the middle of a glass ball in a white furnace,
+        // light bounces back and forth along the axis: 4% is reflected at
+        // each crossing and a share `a` kept over each diameter, which adds
+        // up to 0.04 + 0.96^2 a / (1 - 0.04 a)
+        let ball = Sphere::new(
+            Vec3::origin(),
+            1.0,
+            Box::new(Dielectric::new(1.5).tinted(Vec3::new(0.5, 1.0, 0.0), 2.0)),
+        );
+        let scene = Scene::new(Box::new(ball), Background::Solid(Vec3::new(1.0, 1.0, 1.0)));
+        // Without roulette, so that every path's weight is exact
+        let mut renderer = Renderer::new(1, 1, 1);
+        renderer.roulette_depth = renderer.max_depth;
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let mut sampler = IndependentSampler::new(3);
+        let n = 20000;
+        let mut total = Vec3::origin();
+        for _ in 0..n {
+            total += renderer.radiance(&ray, &scene, &mut sampler);
+        }
+        let mean = total / n as f32;
+        let expected = |a: f32| 0.04 + 0.96 * 0.96 * a / (1.0 - 0.04 * a);
+        assert!((mean.r() - expected(0.5)).abs() < 0.01, "{:?}", mean);
+        // Clear glass returns everything
+        assert!((mean.g() - 1.0).abs() < 1e-4, "{:?}", mean);
+        assert!((mean.b() - 0.04).abs() < 0.005, "{:?}", mean);
+    }
+
+    /// Colour along `ray`, averaged over `n` paths
+    fn mean_colour(renderer: &Renderer, scene: &Scene, ray: &Ray, n: usize) -> Vec3 {
+        let mut sampler = IndependentSampler::new(4);
+        let mut total = Vec3::origin();
+        for _ in 0..n {
+            total += renderer.radiance(ray, scene, &mut sampler);
+        }
+        total / n as f32
+    }
+
+    #[test]
+    fn spectral_rendering_agrees_with_rgb() {
+        // A coloured ball lit by a white lamp and a blue sky
+        let lamp = Sphere::new(
+            Vec3::new(0.0, 4.0, 2.0),
+            1.0,
+            Box::new(DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0))),
+        );
+        let mut world = HitList::new(2);
+        world.list.push(Box::new(lamp.clone()) as Box<dyn Hit>);
+        world.list.push(Box::new(Sphere::new(
+            Vec3::origin(),
+            1.0,
+            Box::new(Lambertian::new(Vec3::new(0.7, 0.4, 0.2))),
+        )));
+        let mut lights = LightList::new();
+        lights.push(lamp);
+        let background = Background::Solid(Vec3::new(0.2, 0.3, 0.6));
+        let scene = Scene::new(Box::new(world), background).with_lights(lights);
+
+        let mut renderer = Renderer::new(1, 1, 1);
+        let ray = Ray::new(Vec3::new(0.0, 0.5, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        let rgb = mean_colour(&renderer, &scene, &ray, 20000);
+        renderer.integrator = Integrator::Spectral;
+        let spectral = mean_colour(&renderer, &scene, &ray, 20000);
+        assert!(
+            (&spectral - &rgb).length() < 0.03 * rgb.length(),
+            "{:?} != {:?}",
+            spectral,
+            rgb
+        );
+    }
+
+    #[test]
+    fn dispersive_glass_keeps_white_light_white() {
+        // Diamond splits the light of a white furnace by wavelength, but
+        // every wavelength still finds its way out
+        let ball = Sphere::new(
+            Vec3::origin(),
+            1.0,
+            Box::new(DispersiveDielectric::new(Dispersion::diamond())),
+        );
+        let scene = Scene::new(Box::new(ball), Background::Solid(Vec3::new(1.0, 1.0, 1.0)));
+        let mut renderer = Renderer::new(1, 1, 1);
+        renderer.roulette_depth = renderer.max_depth;
+        renderer.integrator = Integrator::Spectral;
+        let ray = Ray::new(Vec3::new(0.3, 0.2, 5.0), Vec3::new(0.0, 0.0, -1.0));
+        // Paths that carry one wavelength are noisy in colour
+        let mean = mean_colour(&renderer, &scene, &ray, 100000);
+        assert!(
+            (&mean - &Vec3::new(1.0, 1.0, 1.0)).length() < 0.05,
+            "{:?}",
+            mean
+        );
+        assert_eq!("spectral".parse(), Ok(Integrator::Spectral));
+        assert_eq!(Integrator::Rgb.to_string(), "rgb");
+    }
+}
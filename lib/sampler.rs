@@ -0,0 +1,558 @@
+//! Sample generators for rendering.
+//!
+//! A sampler hands out the random dimensions of one camera sample: the
+//! position in the pixel, the position on the lens, then the dimensions each
+//! bounce uses to pick a scattering direction. Every sampler is positioned
+//! with `start_pixel_sample` and derives everything from the render seed, the
+//! pixel and the sample index, so an image does not depend on how the work was
+//! split between threads.
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::rng::mix;
+
+const PCG_MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+const PCG_DEFAULT_STREAM: u64 = 0xda3e_39cb_94b9_5bdb;
+
+/// Largest `f32` below 1
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+/// Bases of the Halton dimensions; later dimensions fall back to independent
+/// random samples
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+pub trait Sampler {
+    /// Positions the sampler at sample `index` of the pixel with flat index
+    /// `pixel`, starting again from its first dimension
+    fn start_pixel_sample(&mut self, pixel: u64, index: usize);
+
+    /// Next dimension, uniform in `[0, 1)`
+    fn get_1d(&mut self) -> f32;
+
+    /// Next two dimensions, uniform in `[0, 1)^2`
+    fn get_2d(&mut self) -> (f32, f32);
+}
+
+/// Seed for the scrambling of one dimension of one pixel
+#[inline(always)]
+fn dimension_hash(seed: u64, pixel: u64, dimension: usize) -> u64 {
+    mix(mix(seed ^ mix(pixel)) ^ dimension as u64)
+}
+
+#[inline(always)]
+fn bits_to_unit(bits: u32) -> f32 {
+    // The top 24 bits fill an f32 mantissa exactly, so 1 is never reached
+    (bits >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+/// Element `i` of a pseudo-random permutation of `0..n` chosen by `seed`,
+/// after Kensler's "Correlated Multi-Jittered Sampling"
+pub fn permutation_element(i: u32, n: u32, seed: u32) -> u32 {
+    let p = seed;
+    let mut w = n.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    let mut i = i;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    i.wrapping_add(p) % n
+}
+
+/// Radical inverse of `a` in the given base with Owen scrambling: every digit
+/// is permuted by a permutation that depends on the digits before it
+pub fn owen_scrambled_radical_inverse(base: u32, mut a: u64, hash: u64) -> f32 {
+    let base = base as u64;
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0f64;
+    let mut reversed_digits = 0u64;
+    // Keep going past the last non-zero digit, as the scrambled zeroes still
+    // contribute until the digits drop below f32 precision
+    while inv_base_m > 1e-8 {
+        let next = a / base;
+        let digit = (a - next * base) as u32;
+        let digit_hash = mix(hash ^ reversed_digits) as u32;
+        let digit = permutation_element(digit, base as u32, digit_hash) as u64;
+        reversed_digits = reversed_digits * base + digit;
+        inv_base_m *= inv_base;
+        a = next;
+    }
+    ((reversed_digits as f64 * inv_base_m) as f32).min(ONE_MINUS_EPSILON)
+}
+
+/// Owen scrambling of a base 2 sample stored in the high bits of `v`, using
+/// the Laine-Karras style hash
+#[inline(always)]
+fn fast_owen_scramble(mut v: u32, seed: u32) -> u32 {
+    v = v.reverse_bits();
+    v ^= v.wrapping_mul(0x3d20_adea);
+    v = v.wrapping_add(seed);
+    v = v.wrapping_mul((seed >> 16) | 1);
+    v ^= v.wrapping_mul(0x0552_6c56);
+    v ^= v.wrapping_mul(0x53a2_2864);
+    v.reverse_bits()
+}
+
+/// Second dimension of the Sobol sequence, which together with the van der
+/// Corput sequence forms a (0,2)-sequence in base 2
+#[inline(always)]
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+/// Uniform random samples from a PCG32 (XSH RR) generator
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndependentSampler {
+    seed: u64,
+    state: u64,
+    /// Odd increment selecting one of 2^63 independent sequences
+    inc: u64,
+}
+
+impl IndependentSampler {
+    /// A generator for `seed` on the default stream
+    pub fn new(seed: u64) -> IndependentSampler {
+        IndependentSampler::with_stream(seed, PCG_DEFAULT_STREAM)
+    }
+
+    /// A generator for `seed` on sequence `stream`; different streams give
+    /// unrelated sequences even for the same seed
+    pub fn with_stream(seed: u64, stream: u64) -> IndependentSampler {
+        let mut sampler = IndependentSampler {
+            seed,
+            state: 0,
+            inc: 0,
+        };
+        sampler.set_sequence(seed, stream);
+        sampler
+    }
+
+    fn set_sequence(&mut self, seed: u64, stream: u64) {
+        self.state = 0;
+        self.inc = (stream << 1) | 1;
+        self.next_u32();
+        self.state = self.state.wrapping_add(seed);
+        self.next_u32();
+    }
+
+    #[inline(always)]
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(PCG_MULTIPLIER).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, pixel: u64, index: usize) {
+        let seed = mix(self.seed ^ mix(pixel));
+        self.set_sequence(seed, mix(index as u64));
+    }
+
+    #[inline(always)]
+    fn get_1d(&mut self) -> f32 {
+        bits_to_unit(self.next_u32())
+    }
+
+    #[inline(always)]
+    fn get_2d(&mut self) -> (f32, f32) {
+        let u = self.get_1d();
+        (u, self.get_1d())
+    }
+}
+
+/// Jittered samples: each dimension of a pixel's samples is split into
+/// `samples_per_pixel` strata (a grid for 2D dimensions) with one sample in
+/// each, and the strata are shuffled independently per dimension
+#[derive(Debug, Clone)]
+pub struct StratifiedSampler {
+    x_strata: usize,
+    y_strata: usize,
+    seed: u64,
+    pixel: u64,
+    index: usize,
+    dimension: usize,
+    jitter: IndependentSampler,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: usize, seed: u64) -> StratifiedSampler {
+        let samples = samples_per_pixel.max(1);
+        // The squarest grid with exactly one stratum per sample
+        let mut x_strata = (samples as f64).sqrt() as usize;
+        while !samples.is_multiple_of(x_strata) {
+            x_strata -= 1;
+        }
+        StratifiedSampler {
+            x_strata,
+            y_strata: samples / x_strata,
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+            jitter: IndependentSampler::new(seed),
+        }
+    }
+
+    fn samples(&self) -> usize {
+        self.x_strata * self.y_strata
+    }
+
+    fn next_stratum(&mut self, dimensions: usize) -> usize {
+        let hash = dimension_hash(self.seed, self.pixel, self.dimension) as u32;
+        self.dimension += dimensions;
+        let samples = self.samples();
+        permutation_element((self.index % samples) as u32, samples as u32, hash) as usize
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, pixel: u64, index: usize) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+        self.jitter.start_pixel_sample(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let stratum = self.next_stratum(1);
+        let u = (stratum as f32 + self.jitter.get_1d()) / self.samples() as f32;
+        u.min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let stratum = self.next_stratum(2);
+        let (x, y) = (stratum % self.x_strata, stratum / self.x_strata);
+        let (dx, dy) = self.jitter.get_2d();
+        (
+            ((x as f32 + dx) / self.x_strata as f32).min(ONE_MINUS_EPSILON),
+            ((y as f32 + dy) / self.y_strata as f32).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+/// The Halton sequence, with dimension `i` the radical inverse in the `i`th
+/// prime base. Each pixel gets its own Owen scrambling of the sequence.
+#[derive(Debug, Clone)]
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    index: usize,
+    dimension: usize,
+    fallback: IndependentSampler,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+            fallback: IndependentSampler::new(seed),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, pixel: u64, index: usize) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+        self.fallback.start_pixel_sample(pixel, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match PRIMES.get(dimension) {
+            Some(&base) => owen_scrambled_radical_inverse(
+                base,
+                self.index as u64,
+                dimension_hash(self.seed, self.pixel, dimension),
+            ),
+            None => self.fallback.get_1d(),
+        }
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let u = self.get_1d();
+        (u, self.get_1d())
+    }
+}
+
+/// The first two dimensions of the Sobol sequence, a (0,2)-sequence, for
+/// every 2D dimension and van der Corput for 1D ones. Each dimension of each
+/// pixel gets its own Owen scrambling and its own shuffle of the sample
+/// indices, which keeps the dimensions from correlating with each other.
+#[derive(Debug, Clone)]
+pub struct SobolSampler {
+    samples_per_pixel: usize,
+    seed: u64,
+    pixel: u64,
+    index: usize,
+    dimension: usize,
+}
+
+impl SobolSampler {
+    pub fn new(samples_per_pixel: usize, seed: u64) -> SobolSampler {
+        SobolSampler {
+            samples_per_pixel: samples_per_pixel.max(1),
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    /// The scrambling hash and shuffled sample index for the next dimension
+    fn next_dimension(&mut self, dimensions: usize) -> (u64, u32) {
+        let hash = dimension_hash(self.seed, self.pixel, self.dimension);
+        self.dimension += dimensions;
+        let samples = self.samples_per_pixel as u32;
+        let index = permutation_element(
+            (self.index % self.samples_per_pixel) as u32,
+            samples,
+            (hash >> 32) as u32,
+        );
+        // Indices past the pixel's sample count continue the sequence
+        let round = (self.index / self.samples_per_pixel) as u32;
+        (hash, round.wrapping_mul(samples).wrapping_add(index))
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, pixel: u64, index: usize) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let (hash, index) = self.next_dimension(1);
+        bits_to_unit(fast_owen_scramble(index.reverse_bits(), hash as u32))
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let (hash, index) = self.next_dimension(2);
+        let x = fast_owen_scramble(index.reverse_bits(), hash as u32);
+        let y = fast_owen_scramble(sobol_second_dimension(index), mix(hash) as u32);
+        (bits_to_unit(x), bits_to_unit(y))
+    }
+}
+
+/// The sample generators a render can use
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SamplerKind {
+    #[default]
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerKind {
+    /// A sampler of this kind for `samples_per_pixel` samples per pixel
+    pub fn create(self, samples_per_pixel: usize, seed: u64) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(samples_per_pixel, seed)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(samples_per_pixel, seed)),
+        }
+    }
+}
+
+impl fmt::Display for SamplerKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            SamplerKind::Independent => "independent",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SamplerKind, String> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("unknown sampler '{}'", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [SamplerKind; 4] = [
+        SamplerKind::Independent,
+        SamplerKind::Stratified,
+        SamplerKind::Halton,
+        SamplerKind::Sobol,
+    ];
+
+    /// L2-star discrepancy of a 2D point set, by Warnock's formula
+    fn l2_star_discrepancy(points: &[(f32, f32)]) -> f64 {
+        let n = points.len() as f64;
+        let mut single = 0.0;
+        let mut pairs = 0.0;
+        for &(xi, yi) in points.iter() {
+            let (xi, yi) = (xi as f64, yi as f64);
+            single += (1.0 - xi * xi) * (1.0 - yi * yi) / 4.0;
+            for &(xj, yj) in points.iter() {
+                pairs += (1.0 - xi.max(xj as f64)) * (1.0 - yi.max(yj as f64));
+            }
+        }
+        (1.0 / 9.0 - 2.0 * single / n + pairs / (n * n)).sqrt()
+    }
+
+    /// Mean discrepancy of the 2D dimension `dimension` over a few pixels
+    fn mean_discrepancy(kind: SamplerKind, samples: usize, dimension: usize) -> f64 {
+        let pixels = 8;
+        let mut sampler = kind.create(samples, 3);
+        let mut total = 0.0;
+        for pixel in 0..pixels {
+            let points: Vec<(f32, f32)> = (0..samples)
+                .map(|index| {
+                    sampler.start_pixel_sample(pixel, index);
+                    for _ in 0..dimension {
+                        sampler.get_2d();
+                    }
+                    sampler.get_2d()
+                })
+                .collect();
+            total += l2_star_discrepancy(&points);
+        }
+        total / pixels as f64
+    }
+
+    #[test]
+    fn pcg32_reference_sequence() {
+        // First outputs of the PCG reference implementation's pcg32-demo
+        let mut sampler = IndependentSampler::with_stream(42, 54);
+        let expected = [
+            0xa15c_02b7,
+            0x7b47_f409,
+            0xba1d_3330,
+            0x83d2_f293,
+            0xbfa4_784b,
+            0xcbed_606e,
+        ];
+        for &value in expected.iter() {
+            assert_eq!(sampler.next_u32(), value);
+        }
+    }
+
+    #[test]
+    fn samples_are_in_range_and_repeatable() {
+        for &kind in KINDS.iter() {
+            let mut sampler = kind.create(16, 7);
+            let mut sum = 0.0;
+            for index in 0..4096 {
+                sampler.start_pixel_sample(index as u64 / 16, index % 16);
+                for _ in 0..40 {
+                    let u = sampler.get_1d();
+                    assert!((0.0..1.0).contains(&u), "{}", kind);
+                    sum += u;
+                }
+            }
+            assert!((sum / (4096.0 * 40.0) - 0.5).abs() < 0.01, "{}", kind);
+
+            let mut first = Vec::new();
+            for &(pixel, index) in [(3, 0), (3, 0), (3, 1), (4, 0)].iter() {
+                sampler.start_pixel_sample(pixel, index);
+                first.push((sampler.get_2d(), sampler.get_1d()));
+            }
+            assert_eq!(first[0], first[1], "{}", kind);
+            assert_ne!(first[0], first[2], "{}", kind);
+            assert_ne!(first[0], first[3], "{}", kind);
+        }
+    }
+
+    #[test]
+    fn permutations_cover_every_element() {
+        for &n in [1, 5, 16, 17].iter() {
+            let mut seen: Vec<u32> = (0..n)
+                .map(|i| permutation_element(i, n, 0xdead_beef))
+                .collect();
+            seen.sort();
+            assert_eq!(seen, (0..n).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn low_discrepancy_samplers_beat_random() {
+        let samples = 64;
+        // Expected L2-star discrepancy of uniform random points in 2D
+        let random = ((1.0 / 4.0 - 1.0 / 9.0) / samples as f64).sqrt();
+        let independent = mean_discrepancy(SamplerKind::Independent, samples, 0);
+        assert!((independent - random).abs() < 0.5 * random, "{}", independent);
+        // The pixel and lens dimensions
+        for &kind in KINDS[1..].iter() {
+            for dimension in 0..2 {
+                let discrepancy = mean_discrepancy(kind, samples, dimension);
+                assert!(
+                    discrepancy < 0.5 * random,
+                    "{} dimension {}: {} vs {}",
+                    kind,
+                    dimension,
+                    discrepancy,
+                    random
+                );
+            }
+        }
+        // Padding keeps every 2D dimension of the Sobol sampler a (0,2) set,
+        // better than a jittered grid even deep into a path
+        let stratified = mean_discrepancy(SamplerKind::Stratified, samples, 5);
+        let sobol = mean_discrepancy(SamplerKind::Sobol, samples, 5);
+        assert!(sobol < 0.75 * stratified, "{} vs {}", sobol, stratified);
+    }
+}
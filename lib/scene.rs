@@ -0,0 +1,57 @@
+use super::light::LightList;
+use super::{unit_vector, Hit, Ray, Vec3};
+
+/// Radiance seen by rays that leave the scene without hitting anything
+pub enum Background {
+    Solid(Vec3),
+    /// Blend from `bottom` to `top` by the ray direction's height
+    Gradient { bottom: Vec3, top: Vec3 },
+    Function(Box<dyn Fn(&Ray) -> Vec3 + Send + Sync>),
+}
+
+impl Background {
+    /// The white to light blue sky of the original renderer
+    pub fn sky() -> Background {
+        Background::Gradient {
+            bottom: Vec3::new(1.0, 1.0, 1.0),
+            top: Vec3::new(0.5, 0.7, 1.0),
+        }
+    }
+
+    pub fn radiance(&self, ray: &Ray) -> Vec3 {
+        match *self {
+            Background::Solid(ref col) => col.clone(),
+            Background::Gradient {
+                ref bottom,
+                ref top,
+            } => {
+                let unit_direction = unit_vector(&ray.direction());
+                let t: f32 = 0.5 * (unit_direction.y() + 1.0);
+                (1.0 - t) * bottom + t * top
+            }
+            Background::Function(ref f) => f(ray),
+        }
+    }
+}
+
+/// Everything the integrator needs to know about the world being rendered
+pub struct Scene {
+    pub world: Box<dyn Hit>,
+    pub background: Background,
+    /// Emitters to aim shadow rays at. They must also be part of `world`.
+    pub lights: LightList,
+}
+
+impl Scene {
+    pub fn new(world: Box<dyn Hit>, background: Background) -> Scene {
+        Scene {
+            world,
+            background,
+            lights: LightList::new(),
+        }
+    }
+
+    pub fn with_lights(self, lights: LightList) -> Scene {
+        Scene { lights, ..self }
+    }
+}
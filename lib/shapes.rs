@@ -0,0 +1,500 @@
+//! Flat shapes for building rooms: axis-aligned rectangles, parallelograms,
+//! infinite planes and boxes.
+//!
+//! Like spheres, every shape reports the same geometric normal whichever side
+//! a ray arrives from. Rectangles face the positive end of their axis unless
+//! `flipped`, quads face along `u × v`, and boxes face outwards. Materials
+//! that look the same from both sides turn it towards the ray with
+//! `HitRecord::facing_normal`.
+
+use super::aabb::Aabb;
+use super::materials::Material;
+use super::{cross, dot, unit_vector, Hit, HitList, HitRecord, Ray, Vec3};
+
+/// Half the thickness given to flat shapes' bounding boxes, so that boxes
+/// lying in an axis plane still have a volume to hit
+const PAD: f32 = 1e-4;
+
+/// Intersection with the rectangle `a0..a1` by `b0..b1` in the plane where
+/// coordinate `axis` is `k`. The texture coordinates run from 0 to 1 along
+/// `a` and `b`.
+#[allow(clippy::too_many_arguments)]
+fn hit_axis_rect(
+    (axis, a, b): (usize, usize, usize),
+    (a0, a1, b0, b1, k): (f32, f32, f32, f32, f32),
+    flip: bool,
+    material: &dyn Material,
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+    hit_record: &mut HitRecord,
+) -> bool {
+    let origin = ray.origin();
+    let direction = ray.direction();
+    let t = (k - origin[axis]) / direction[axis];
+    // Also false for NaN, when the ray runs inside the plane
+    if !(t > t_min && t < t_max) {
+        return false;
+    }
+    let pa = origin[a] + t * direction[a];
+    let pb = origin[b] + t * direction[b];
+    if pa < a0 || pa > a1 || pb < b0 || pb > b1 {
+        return false;
+    }
+    let mut normal = [0.0; 3];
+    normal[axis] = if flip { -1.0 } else { 1.0 };
+    hit_record.t = t;
+    hit_record.p = ray.point_at_param(t);
+    hit_record.normal = Vec3::new(normal[0], normal[1], normal[2]);
+    hit_record.u = (pa - a0) / (a1 - a0);
+    hit_record.v = (pb - b0) / (b1 - b0);
+    hit_record.mat = Some(material.clone_box());
+    true
+}
+
+fn axis_rect_bounds(
+    (axis, a, b): (usize, usize, usize),
+    (a0, a1, b0, b1, k): (f32, f32, f32, f32, f32),
+) -> Aabb {
+    let mut min = [0.0; 3];
+    let mut max = [0.0; 3];
+    min[axis] = k - PAD;
+    max[axis] = k + PAD;
+    min[a] = a0;
+    max[a] = a1;
+    min[b] = b0;
+    max[b] = b1;
+    Aabb::new(
+        Vec3::new(min[0], min[1], min[2]),
+        Vec3::new(max[0], max[1], max[2]),
+    )
+}
+
+/// Rectangle `x0..x1` by `y0..y1` in the plane `z = k`, facing +z
+pub struct XyRect {
+    pub x0: f32,
+    pub x1: f32,
+    pub y0: f32,
+    pub y1: f32,
+    pub k: f32,
+    /// Faces -z instead
+    pub flip: bool,
+    material: Box<dyn Material>,
+}
+
+impl XyRect {
+    pub fn new(x0: f32, x1: f32, y0: f32, y1: f32, k: f32, mat: Box<dyn Material>) -> XyRect {
+        XyRect {
+            x0,
+            x1,
+            y0,
+            y1,
+            k,
+            flip: false,
+            material: mat,
+        }
+    }
+
+    pub fn flipped(self) -> XyRect {
+        XyRect {
+            flip: !self.flip,
+            ..self
+        }
+    }
+}
+
+impl Hit for XyRect {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let extent = (self.x0, self.x1, self.y0, self.y1, self.k);
+        hit_axis_rect(
+            (2, 0, 1),
+            extent,
+            self.flip,
+            &*self.material,
+            ray,
+            t_min,
+            t_max,
+            hit_record,
+        )
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(axis_rect_bounds(
+            (2, 0, 1),
+            (self.x0, self.x1, self.y0, self.y1, self.k),
+        ))
+    }
+}
+
+/// Rectangle `x0..x1` by `z0..z1` in the plane `y = k`, facing +y
+pub struct XzRect {
+    pub x0: f32,
+    pub x1: f32,
+    pub z0: f32,
+    pub z1: f32,
+    pub k: f32,
+    /// Faces -y instead
+    pub flip: bool,
+    material: Box<dyn Material>,
+}
+
+impl XzRect {
+    pub fn new(x0: f32, x1: f32, z0: f32, z1: f32, k: f32, mat: Box<dyn Material>) -> XzRect {
+        XzRect {
+            x0,
+            x1,
+            z0,
+            z1,
+            k,
+            flip: false,
+            material: mat,
+        }
+    }
+
+    pub fn flipped(self) -> XzRect {
+        XzRect {
+            flip: !self.flip,
+            ..self
+        }
+    }
+}
+
+impl Hit for XzRect {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let extent = (self.x0, self.x1, self.z0, self.z1, self.k);
+        hit_axis_rect(
+            (1, 0, 2),
+            extent,
+            self.flip,
+            &*self.material,
+            ray,
+            t_min,
+            t_max,
+            hit_record,
+        )
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(axis_rect_bounds(
+            (1, 0, 2),
+            (self.x0, self.x1, self.z0, self.z1, self.k),
+        ))
+    }
+}
+
+/// Rectangle `y0..y1` by `z0..z1` in the plane `x = k`, facing +x
+pub struct YzRect {
+    pub y0: f32,
+    pub y1: f32,
+    pub z0: f32,
+    pub z1: f32,
+    pub k: f32,
+    /// Faces -x instead
+    pub flip: bool,
+    material: Box<dyn Material>,
+}
+
+impl YzRect {
+    pub fn new(y0: f32, y1: f32, z0: f32, z1: f32, k: f32, mat: Box<dyn Material>) -> YzRect {
+        YzRect {
+            y0,
+            y1,
+            z0,
+            z1,
+            k,
+            flip: false,
+            material: mat,
+        }
+    }
+
+    pub fn flipped(self) -> YzRect {
+        YzRect {
+            flip: !self.flip,
+            ..self
+        }
+    }
+}
+
+impl Hit for YzRect {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let extent = (self.y0, self.y1, self.z0, self.z1, self.k);
+        hit_axis_rect(
+            (0, 1, 2),
+            extent,
+            self.flip,
+            &*self.material,
+            ray,
+            t_min,
+            t_max,
+            hit_record,
+        )
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(axis_rect_bounds(
+            (0, 1, 2),
+            (self.y0, self.y1, self.z0, self.z1, self.k),
+        ))
+    }
+}
+
+/// Parallelogram with corner `q` and edges `u` and `v`, facing along `u × v`.
+/// The texture coordinates run from 0 to 1 along each edge.
+#[derive(Clone)]
+pub struct Quad {
+    pub q: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    normal: Vec3,
+    /// `u × v` divided by its squared length, for the planar coordinates
+    w: Vec3,
+    material: Box<dyn Material>,
+}
+
+impl Quad {
+    pub fn new(q: Vec3, u: Vec3, v: Vec3, mat: Box<dyn Material>) -> Quad {
+        let n = cross(&u, &v);
+        Quad {
+            normal: unit_vector(&n),
+            w: &n / dot(&n, &n),
+            q,
+            u,
+            v,
+            material: mat,
+        }
+    }
+
+    pub fn material(&self) -> &dyn Material {
+        &*self.material
+    }
+
+    /// Unit normal, along `u × v`
+    pub fn normal(&self) -> Vec3 {
+        self.normal.clone()
+    }
+
+    /// Area of the parallelogram
+    pub fn area(&self) -> f32 {
+        cross(&self.u, &self.v).length()
+    }
+}
+
+impl Hit for Quad {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let direction = ray.direction();
+        let denominator = dot(&self.normal, &direction);
+        if denominator.abs() < 1e-8 {
+            return false;
+        }
+        let t = dot(&self.normal, &(&self.q - &ray.origin())) / denominator;
+        if !(t > t_min && t < t_max) {
+            return false;
+        }
+        let p = ray.point_at_param(t);
+        let planar = &p - &self.q;
+        let alpha = dot(&self.w, &cross(&planar, &self.v));
+        let beta = dot(&self.w, &cross(&self.u, &planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return false;
+        }
+        hit_record.t = t;
+        hit_record.p = p;
+        hit_record.normal = self.normal.clone();
+        hit_record.u = alpha;
+        hit_record.v = beta;
+        hit_record.mat = Some(self.material.clone_box());
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let pad = Vec3::new(PAD, PAD, PAD);
+        let far = &(&self.q + &self.u) + &self.v;
+        let bounds = Aabb::new(self.q.clone(), self.q.clone())
+            .grow(&(&self.q + &self.u))
+            .grow(&(&self.q + &self.v))
+            .grow(&far);
+        Some(Aabb::new(bounds.min - pad.clone(), bounds.max + pad))
+    }
+}
+
+/// Infinite plane through `point`, facing along `normal`. The texture
+/// coordinates are distances along two fixed directions in the plane, so
+/// textures repeat with a period of one unit.
+pub struct Plane {
+    pub point: Vec3,
+    pub normal: Vec3,
+    tangent: Vec3,
+    bitangent: Vec3,
+    material: Box<dyn Material>,
+}
+
+impl Plane {
+    pub fn new(point: Vec3, normal: &Vec3, mat: Box<dyn Material>) -> Plane {
+        let normal = unit_vector(normal);
+        // Any axis not too close to the normal gives a tangent
+        let axis = if normal.x.abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let tangent = unit_vector(&cross(&axis, &normal));
+        let bitangent = cross(&normal, &tangent);
+        Plane {
+            point,
+            normal,
+            tangent,
+            bitangent,
+            material: mat,
+        }
+    }
+}
+
+impl Hit for Plane {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let denominator = dot(&self.normal, &ray.direction());
+        if denominator.abs() < 1e-8 {
+            return false;
+        }
+        let t = dot(&self.normal, &(&self.point - &ray.origin())) / denominator;
+        if !(t > t_min && t < t_max) {
+            return false;
+        }
+        hit_record.t = t;
+        hit_record.p = ray.point_at_param(t);
+        let planar = &hit_record.p - &self.point;
+        hit_record.normal = self.normal.clone();
+        hit_record.u = dot(&planar, &self.tangent);
+        hit_record.v = dot(&planar, &self.bitangent);
+        hit_record.mat = Some(self.material.clone_box());
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+}
+
+/// Axis-aligned box between the corners `min` and `max`, made of six quads
+/// facing outwards
+pub struct Cuboid {
+    pub min: Vec3,
+    pub max: Vec3,
+    sides: HitList<Quad>,
+}
+
+impl Cuboid {
+    pub fn new(a: &Vec3, b: &Vec3, mat: Box<dyn Material>) -> Cuboid {
+        let min = a.min(b);
+        let max = a.max(b);
+        let d = &max - &min;
+        let dx = Vec3::new(d.x, 0.0, 0.0);
+        let dy = Vec3::new(0.0, d.y, 0.0);
+        let dz = Vec3::new(0.0, 0.0, d.z);
+        let mut sides = HitList::new(6);
+        let mut side = |q: Vec3, u: Vec3, v: Vec3| sides.list.push(Quad::new(q, u, v, mat.clone()));
+        // Front, right, back, left, top and bottom
+        side(Vec3::new(min.x, min.y, max.z), dx.clone(), dy.clone());
+        side(Vec3::new(max.x, min.y, max.z), -&dz, dy.clone());
+        side(Vec3::new(max.x, min.y, min.z), -&dx, dy.clone());
+        side(min.clone(), dz.clone(), dy.clone());
+        side(Vec3::new(min.x, max.y, max.z), dx.clone(), -&dz);
+        side(min.clone(), dx, dz);
+        Cuboid { min, max, sides }
+    }
+}
+
+impl Hit for Cuboid {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        self.sides.hit(ray, t_min, t_max, hit_record)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.sides.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::materials::Lambertian;
+    use super::*;
+
+    fn grey() -> Box<dyn Material> {
+        Box::new(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)))
+    }
+
+    fn down(x: f32, z: f32) -> Ray {
+        Ray::new(Vec3::new(x, 5.0, z), Vec3::new(0.0, -1.0, 0.0))
+    }
+
+    #[test]
+    fn rectangles_and_quads_agree() {
+        let rect = XzRect::new(1.0, 3.0, -1.0, 1.0, 2.0, grey());
+        let quad = Quad::new(
+            Vec3::new(1.0, 2.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+            grey(),
+        );
+        let mut a = HitRecord::new();
+        let mut b = HitRecord::new();
+        assert!(rect.hit(&down(1.5, 0.5), 0.001, 100.0, &mut a));
+        assert!(quad.hit(&down(1.5, 0.5), 0.001, 100.0, &mut b));
+        assert_eq!(a.t, 3.0);
+        assert!((a.t - b.t).abs() < 1e-6);
+        assert_eq!((a.u, a.v), (0.25, 0.75));
+        assert!((a.u - b.u).abs() < 1e-6 && (a.v - b.v).abs() < 1e-6);
+        // x × z points down
+        assert_eq!(a.normal, Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(b.normal, Vec3::new(0.0, -1.0, 0.0));
+
+        assert!(!rect.hit(&down(0.5, 0.0), 0.001, 100.0, &mut a));
+        assert!(!quad.hit(&down(0.5, 0.0), 0.001, 100.0, &mut b));
+        let rect = rect.flipped();
+        assert!(rect.hit(&down(1.5, 0.5), 0.001, 100.0, &mut a));
+        assert_eq!(a.normal, Vec3::new(0.0, -1.0, 0.0));
+        assert_eq!(quad.area(), 4.0);
+        let bounds = quad.bounding_box().unwrap();
+        assert!(bounds.hit(&down(2.0, 0.0), 0.001, 100.0));
+    }
+
+    #[test]
+    fn planes_are_unbounded() {
+        let plane = Plane::new(Vec3::origin(), &Vec3::new(0.0, 2.0, 0.0), grey());
+        let mut hit_record = HitRecord::new();
+        assert!(plane.hit(&down(1e4, -3.5), 0.001, 100.0, &mut hit_record));
+        assert_eq!(hit_record.t, 5.0);
+        assert_eq!(hit_record.normal, Vec3::new(0.0, 1.0, 0.0));
+        assert!((hit_record.u.abs() - 3.5).abs() < 1e-3 || (hit_record.v.abs() - 3.5).abs() < 1e-3);
+        assert!(plane.bounding_box().is_none());
+        let sideways = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(!plane.hit(&sideways, 0.001, 100.0, &mut hit_record));
+    }
+
+    #[test]
+    fn cuboid_faces_point_outwards() {
+        let cuboid = Cuboid::new(
+            &Vec3::new(1.0, 1.0, 1.0),
+            &Vec3::new(-1.0, 0.0, -2.0),
+            grey(),
+        );
+        let bounds = cuboid.bounding_box().unwrap();
+        assert!((&bounds.min - &Vec3::new(-1.0, 0.0, -2.0)).length() < 1e-3);
+        assert!((&bounds.max - &Vec3::new(1.0, 1.0, 1.0)).length() < 1e-3);
+        let centre = Vec3::new(0.0, 0.5, -0.5);
+        let directions = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        ];
+        let mut hit_record = HitRecord::new();
+        for direction in directions.iter() {
+            let ray = Ray::new(&centre + &(10.0 * direction), -direction);
+            assert!(cuboid.hit(&ray, 0.001, 100.0, &mut hit_record));
+            assert_eq!(hit_record.normal, *direction);
+            assert!((0.0..=1.0).contains(&hit_record.u) && (0.0..=1.0).contains(&hit_record.v));
+        }
+    }
+}
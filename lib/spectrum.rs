@@ -0,0 +1,389 @@
+//! Light as a spectrum rather than an RGB triple, for the spectral
+//! integrator: radiance sampled at a few wavelengths per path, RGB colours
+//! turned into spectra (Smits 1999), and spectra turned back into RGB
+//! through the CIE XYZ colour space.
+//!
+//! Upsampled colours are reflectances lit by light with the same energy at
+//! every wavelength, so the way back to RGB is white balanced for that
+//! light: a flat spectrum comes out as RGB white.
+
+use std::ops::{Add, AddAssign, DivAssign, Mul, MulAssign};
+use std::sync::OnceLock;
+
+use super::Vec3;
+
+/// Shortest wavelength sampled, in nanometres
+pub const WAVELENGTH_MIN: f32 = 360.0;
+/// Longest wavelength sampled, in nanometres
+pub const WAVELENGTH_MAX: f32 = 830.0;
+/// Number of wavelengths each path carries
+pub const SPECTRUM_SAMPLES: usize = 4;
+/// Wavelength at which dispersive materials refract light that has no
+/// wavelength of its own, as in RGB rendering: the sodium D line
+pub const REFERENCE_WAVELENGTH: f32 = 589.3;
+
+/// A spectral quantity at each of the wavelengths of a path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledSpectrum {
+    pub values: [f32; SPECTRUM_SAMPLES],
+}
+
+impl SampledSpectrum {
+    pub fn new(values: [f32; SPECTRUM_SAMPLES]) -> SampledSpectrum {
+        SampledSpectrum { values }
+    }
+
+    pub fn constant(value: f32) -> SampledSpectrum {
+        SampledSpectrum::new([value; SPECTRUM_SAMPLES])
+    }
+
+    /// The reflectance spectrum of `rgb` at each of `wavelengths`
+    pub fn from_rgb(rgb: &Vec3, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+        SampledSpectrum::new(
+            wavelengths
+                .lambda
+                .map(|lambda| rgb_to_spectrum(rgb, lambda)),
+        )
+    }
+
+    pub fn average(&self) -> f32 {
+        self.values.iter().sum::<f32>() / SPECTRUM_SAMPLES as f32
+    }
+}
+
+impl Add for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn add(self, other: SampledSpectrum) -> SampledSpectrum {
+        let mut values = self.values;
+        for (value, other) in values.iter_mut().zip(other.values.iter()) {
+            *value += other;
+        }
+        SampledSpectrum::new(values)
+    }
+}
+
+impl AddAssign for SampledSpectrum {
+    fn add_assign(&mut self, other: SampledSpectrum) {
+        *self = *self + other;
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn mul(self, other: SampledSpectrum) -> SampledSpectrum {
+        let mut values = self.values;
+        for (value, other) in values.iter_mut().zip(other.values.iter()) {
+            *value *= other;
+        }
+        SampledSpectrum::new(values)
+    }
+}
+
+impl Mul<SampledSpectrum> for f32 {
+    type Output = SampledSpectrum;
+
+    fn mul(self, spectrum: SampledSpectrum) -> SampledSpectrum {
+        SampledSpectrum::new(spectrum.values.map(|value| self * value))
+    }
+}
+
+impl MulAssign for SampledSpectrum {
+    fn mul_assign(&mut self, other: SampledSpectrum) {
+        *self = *self * other;
+    }
+}
+
+impl DivAssign<f32> for SampledSpectrum {
+    fn div_assign(&mut self, divisor: f32) {
+        for value in self.values.iter_mut() {
+            *value /= divisor;
+        }
+    }
+}
+
+/// The wavelengths a path carries, in nanometres, with the density each was
+/// picked with. The first is the hero wavelength, which alone decides the
+/// path's direction where materials disperse light.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledWavelengths {
+    pub lambda: [f32; SPECTRUM_SAMPLES],
+    pub pdf: [f32; SPECTRUM_SAMPLES],
+}
+
+impl SampledWavelengths {
+    /// A hero wavelength picked with `u`, and the others spread evenly after
+    /// it, wrapping around (Wilkie et al. 2014). The wavelengths follow
+    /// `visible_pdf`, so few are spent where the eye sees little.
+    pub fn sample(u: f32) -> SampledWavelengths {
+        let mut lambda = [0.0; SPECTRUM_SAMPLES];
+        let mut pdf = [0.0; SPECTRUM_SAMPLES];
+        for i in 0..SPECTRUM_SAMPLES {
+            let u = (u + i as f32 / SPECTRUM_SAMPLES as f32).fract();
+            lambda[i] = sample_visible(u);
+            pdf[i] = visible_pdf(lambda[i]);
+        }
+        SampledWavelengths { lambda, pdf }
+    }
+
+    #[inline(always)]
+    pub fn hero(&self) -> f32 {
+        self.lambda[0]
+    }
+
+    /// Drops every wavelength but the hero, once the path has taken a
+    /// direction that only suits the hero. The hero then stands in for the
+    /// others, so its density is shared between them.
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+        for pdf in self.pdf[1..].iter_mut() {
+            *pdf = 0.0;
+        }
+        self.pdf[0] /= SPECTRUM_SAMPLES as f32;
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|&pdf| pdf == 0.0)
+    }
+
+    /// CIE XYZ colour estimated from `spectrum` sampled at these wavelengths
+    pub fn to_xyz(&self, spectrum: &SampledSpectrum) -> Vec3 {
+        let mut xyz = Vec3::origin();
+        for i in 0..SPECTRUM_SAMPLES {
+            if self.pdf[i] > 0.0 {
+                xyz += (spectrum.values[i] / self.pdf[i]) * cie_xyz(self.lambda[i]);
+            }
+        }
+        xyz / SPECTRUM_SAMPLES as f32
+    }
+
+    /// Linear RGB colour estimated from `spectrum`, white balanced so that a
+    /// flat spectrum of 1 comes out as white
+    pub fn to_rgb(&self, spectrum: &SampledSpectrum) -> Vec3 {
+        let rgb = xyz_to_rgb(&self.to_xyz(spectrum));
+        let white = flat_white();
+        Vec3::new(
+            rgb.r() / white.r(),
+            rgb.g() / white.g(),
+            rgb.b() / white.b(),
+        )
+    }
+}
+
+/// Density of wavelengths, between `WAVELENGTH_MIN` and `WAVELENGTH_MAX`,
+/// that roughly follows the eye's sensitivity (Pharr et al. 2023)
+pub fn visible_pdf(lambda: f32) -> f32 {
+    if !(WAVELENGTH_MIN..=WAVELENGTH_MAX).contains(&lambda) {
+        return 0.0;
+    }
+    let cosh = (0.0072 * (lambda - 538.0)).cosh();
+    0.003_939_804 / (cosh * cosh)
+}
+
+/// Wavelength drawn from `visible_pdf` by inverting its distribution
+pub fn sample_visible(u: f32) -> f32 {
+    let lambda = 538.0 - 138.888_89 * (0.856_910_6 - 1.827_502 * u).atanh();
+    lambda.clamp(WAVELENGTH_MIN, WAVELENGTH_MAX)
+}
+
+/// RGB colour of a flat spectrum of 1 over the sampled range
+fn flat_white() -> &'static Vec3 {
+    static WHITE: OnceLock<Vec3> = OnceLock::new();
+    WHITE.get_or_init(|| {
+        let steps = 4 * (WAVELENGTH_MAX - WAVELENGTH_MIN) as usize;
+        let step = (WAVELENGTH_MAX - WAVELENGTH_MIN) / steps as f32;
+        let mut xyz = Vec3::origin();
+        for i in 0..steps {
+            xyz += step * cie_xyz(WAVELENGTH_MIN + (i as f32 + 0.5) * step);
+        }
+        xyz_to_rgb(&xyz)
+    })
+}
+
+/// Gaussian with different widths either side of its peak
+#[inline(always)]
+fn lobe(lambda: f32, mean: f32, below: f32, above: f32) -> f32 {
+    let t = (lambda - mean) / if lambda < mean { below } else { above };
+    (-0.5 * t * t).exp()
+}
+
+/// The CIE 1931 standard observer's colour matching functions at `lambda`
+/// nanometres, fitted with sums of Gaussians (Wyman et al. 2013)
+pub fn cie_xyz(lambda: f32) -> Vec3 {
+    Vec3::new(
+        1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(lambda, 501.1, 20.4, 26.2),
+        0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1),
+        1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8),
+    )
+}
+
+/// Linear sRGB colour of a CIE XYZ colour
+pub fn xyz_to_rgb(xyz: &Vec3) -> Vec3 {
+    let (x, y, z) = (xyz.x(), xyz.y(), xyz.z());
+    Vec3::new(
+        3.240_454 * x - 1.537_139 * y - 0.498_531 * z,
+        -0.969_266 * x + 1.876_011 * y + 0.041_556 * z,
+        0.055_643 * x - 0.204_026 * y + 1.057_225 * z,
+    )
+}
+
+/// Smits' spectra, in ten even bins from 380 to 720 nm, that the upsampled
+/// spectrum of a colour is made from
+const SMITS_WHITE: [f32; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f32; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f32; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f32; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f32; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f32; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f32; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// One of Smits' spectra at `lambda`, interpolated between the centres of
+/// its bins and held constant beyond them
+fn smits(spectrum: &[f32; 10], lambda: f32) -> f32 {
+    let width = (720.0 - 380.0) / spectrum.len() as f32;
+    let x = ((lambda - 380.0) / width - 0.5).clamp(0.0, (spectrum.len() - 1) as f32);
+    let i = (x as usize).min(spectrum.len() - 2);
+    let t = x - i as f32;
+    (1.0 - t) * spectrum[i] + t * spectrum[i + 1]
+}
+
+/// Value at `lambda` nanometres of a smooth spectrum with the colour `rgb`:
+/// white for the smallest component, then the secondary colour and the
+/// primary that make up the rest. Greys come out flat, and colours in
+/// [0, 1] stay in [0, 1] but for Smits' slight overshoots.
+pub fn rgb_to_spectrum(rgb: &Vec3, lambda: f32) -> f32 {
+    let (r, g, b) = (rgb.r().max(0.0), rgb.g().max(0.0), rgb.b().max(0.0));
+    let spectrum = |s: &[f32; 10]| smits(s, lambda);
+    if r <= g && r <= b {
+        r * spectrum(&SMITS_WHITE)
+            + if g <= b {
+                (g - r) * spectrum(&SMITS_CYAN) + (b - g) * spectrum(&SMITS_BLUE)
+            } else {
+                (b - r) * spectrum(&SMITS_CYAN) + (g - b) * spectrum(&SMITS_GREEN)
+            }
+    } else if g <= r && g <= b {
+        g * spectrum(&SMITS_WHITE)
+            + if r <= b {
+                (r - g) * spectrum(&SMITS_MAGENTA) + (b - r) * spectrum(&SMITS_BLUE)
+            } else {
+                (b - g) * spectrum(&SMITS_MAGENTA) + (r - b) * spectrum(&SMITS_RED)
+            }
+    } else {
+        b * spectrum(&SMITS_WHITE)
+            + if r <= g {
+                (r - b) * spectrum(&SMITS_YELLOW) + (g - r) * spectrum(&SMITS_GREEN)
+            } else {
+                (g - b) * spectrum(&SMITS_YELLOW) + (r - g) * spectrum(&SMITS_RED)
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RGB colour of `rgb` upsampled to a spectrum, estimated from `n` sets
+    /// of wavelengths
+    fn round_trip(rgb: &Vec3, n: usize) -> Vec3 {
+        let mut total = Vec3::origin();
+        for i in 0..n {
+            let wavelengths = SampledWavelengths::sample((i as f32 + 0.5) / n as f32);
+            let spectrum = SampledSpectrum::from_rgb(rgb, &wavelengths);
+            total += wavelengths.to_rgb(&spectrum);
+        }
+        total / n as f32
+    }
+
+    #[test]
+    fn colour_matching_functions_peak_where_expected() {
+        // The CIE 1931 tables have y = 1 at 555 nm and x = 1.06 at 600 nm
+        assert!((cie_xyz(555.0).y() - 1.0).abs() < 0.01);
+        assert!((cie_xyz(600.0).x() - 1.06).abs() < 0.02);
+        assert!(
+            (cie_xyz(445.0).z() - 1.78).abs() < 0.03,
+            "{:?}",
+            cie_xyz(445.0)
+        );
+        assert!(cie_xyz(WAVELENGTH_MAX).length() < 1e-3);
+    }
+
+    #[test]
+    fn visible_wavelengths_follow_their_density() {
+        let steps = 4700;
+        let step = (WAVELENGTH_MAX - WAVELENGTH_MIN) / steps as f32;
+        let integral: f32 = (0..steps)
+            .map(|i| visible_pdf(WAVELENGTH_MIN + (i as f32 + 0.5) * step) * step)
+            .sum();
+        assert!((integral - 1.0).abs() < 1e-3, "{}", integral);
+        assert!((sample_visible(0.0) - WAVELENGTH_MIN).abs() < 0.5);
+        assert!((sample_visible(1.0) - WAVELENGTH_MAX).abs() < 0.5);
+        // Half of the density lies below the median drawn from u = 0.5
+        let median = sample_visible(0.5);
+        let below: f32 = (0..steps)
+            .map(|i| WAVELENGTH_MIN + (i as f32 + 0.5) * step)
+            .filter(|&lambda| lambda < median)
+            .map(|lambda| visible_pdf(lambda) * step)
+            .sum();
+        assert!((below - 0.5).abs() < 1e-3, "{}", below);
+    }
+
+    #[test]
+    fn colours_survive_the_round_trip() {
+        let white = round_trip(&Vec3::new(1.0, 1.0, 1.0), 1000);
+        assert!(
+            (&white - &Vec3::new(1.0, 1.0, 1.0)).length() < 1e-3,
+            "{:?}",
+            white
+        );
+        for rgb in [
+            Vec3::new(0.8, 0.2, 0.1),
+            Vec3::new(0.2, 0.7, 0.3),
+            Vec3::new(0.1, 0.3, 0.9),
+            Vec3::new(0.9, 0.8, 0.2),
+            Vec3::new(0.5, 0.5, 0.5),
+        ]
+        .iter()
+        {
+            let back = round_trip(rgb, 1000);
+            assert!((&back - rgb).length() < 0.05, "{:?} -> {:?}", rgb, back);
+        }
+    }
+
+    #[test]
+    fn terminating_secondaries_keeps_the_estimate_unbiased() {
+        // A flat spectrum carried by the hero alone averages out to the same
+        // colour as one carried by all four wavelengths
+        let n = 1000;
+        let mut total = Vec3::origin();
+        for i in 0..n {
+            let mut wavelengths = SampledWavelengths::sample((i as f32 + 0.5) / n as f32);
+            wavelengths.terminate_secondary();
+            wavelengths.terminate_secondary();
+            total += wavelengths.to_rgb(&SampledSpectrum::constant(1.0));
+        }
+        let white = total / n as f32;
+        assert!(
+            (&white - &Vec3::new(1.0, 1.0, 1.0)).length() < 0.01,
+            "{:?}",
+            white
+        );
+    }
+}
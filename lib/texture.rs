@@ -0,0 +1,347 @@
+use std::f32;
+use std::sync::Arc;
+
+use super::render::Framebuffer;
+use super::sampler::{permutation_element, IndependentSampler, Sampler};
+use super::{dot, unit_vector, Vec3};
+
+pub trait Texture: TextureClone + Send + Sync {
+    /// Colour at surface coordinates `(u, v)` and world position `p`
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3;
+}
+
+pub trait TextureClone {
+    fn clone_box(&self) -> Box<dyn Texture>;
+}
+
+impl<T> TextureClone for T
+where
+    T: 'static + Texture + Clone,
+{
+    fn clone_box(&self) -> Box<dyn Texture> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Texture> {
+    fn clone(&self) -> Box<dyn Texture> {
+        self.clone_box()
+    }
+}
+
+#[derive(Clone)]
+pub struct SolidColor {
+    pub color: Vec3,
+}
+
+impl SolidColor {
+    pub fn new(color: Vec3) -> SolidColor {
+        SolidColor { color }
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f32, _v: f32, _p: &Vec3) -> Vec3 {
+        self.color.clone()
+    }
+}
+
+/// Alternates between two textures in a 3D grid of cells that are
+/// `PI / scale` wide
+#[derive(Clone)]
+pub struct Checker {
+    pub odd: Box<dyn Texture>,
+    pub even: Box<dyn Texture>,
+    pub scale: f32,
+}
+
+impl Checker {
+    pub fn new(odd: Box<dyn Texture>, even: Box<dyn Texture>, scale: f32) -> Checker {
+        Checker { odd, even, scale }
+    }
+}
+
+impl Texture for Checker {
+    fn value(&self, u: f32, v: f32, p: &Vec3) -> Vec3 {
+        let sines = (self.scale * p.x).sin() * (self.scale * p.y).sin() * (self.scale * p.z).sin();
+        if sines < 0.0 {
+            self.odd.value(u, v, p)
+        } else {
+            self.even.value(u, v, p)
+        }
+    }
+}
+
+const PERLIN_POINTS: usize = 256;
+
+/// Gradient noise over a lattice of random unit vectors
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    perm: [Vec<usize>; 3],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut sampler = IndependentSampler::new(seed);
+        let gradients = (0..PERLIN_POINTS)
+            .map(|_| {
+                let mut coordinate = || 2.0 * sampler.get_1d() - 1.0;
+                unit_vector(&Vec3::new(coordinate(), coordinate(), coordinate()))
+            })
+            .collect();
+        let mut permutation = || {
+            let scramble = sampler.next_u32();
+            (0..PERLIN_POINTS as u32)
+                .map(|i| permutation_element(i, PERLIN_POINTS as u32, scramble) as usize)
+                .collect()
+        };
+        let perm = [permutation(), permutation(), permutation()];
+        Perlin { gradients, perm }
+    }
+
+    /// Noise value in roughly `[-1, 1]`
+    pub fn noise(&self, p: &Vec3) -> f32 {
+        let u = p.x - p.x.floor();
+        let v = p.y - p.y.floor();
+        let w = p.z - p.z.floor();
+        let i = p.x.floor() as i64;
+        let j = p.y.floor() as i64;
+        let k = p.z.floor() as i64;
+        let mask = (PERLIN_POINTS - 1) as i64;
+
+        // Hermite smoothing hides the lattice
+        let uu = u * u * (3.0 - 2.0 * u);
+        let vv = v * v * (3.0 - 2.0 * v);
+        let ww = w * w * (3.0 - 2.0 * w);
+        let mut accum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.perm[0][((i + di) & mask) as usize]
+                        ^ self.perm[1][((j + dj) & mask) as usize]
+                        ^ self.perm[2][((k + dk) & mask) as usize];
+                    let (fi, fj, fk) = (di as f32, dj as f32, dk as f32);
+                    let weight = Vec3::new(u - fi, v - fj, w - fk);
+                    accum += (fi * uu + (1.0 - fi) * (1.0 - uu))
+                        * (fj * vv + (1.0 - fj) * (1.0 - vv))
+                        * (fk * ww + (1.0 - fk) * (1.0 - ww))
+                        * dot(&self.gradients[index], &weight);
+                }
+            }
+        }
+        accum
+    }
+
+    /// Sum of `depth` octaves of noise, each at twice the frequency and half
+    /// the amplitude of the last
+    pub fn turbulence(&self, p: &Vec3, depth: usize) -> f32 {
+        let mut accum = 0.0;
+        let mut temp = p.clone();
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            accum += weight * self.noise(&temp);
+            weight *= 0.5;
+            temp *= 2.0;
+        }
+        accum.abs()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseKind {
+    /// Plain noise remapped to `[0, 1]`
+    Smooth,
+    Turbulence,
+    /// Sine bands along z, phase-shifted by turbulence
+    Marble,
+}
+
+#[derive(Clone)]
+pub struct Noise {
+    perlin: Arc<Perlin>,
+    pub scale: f32,
+    pub kind: NoiseKind,
+    pub color: Vec3,
+}
+
+impl Noise {
+    pub fn new(scale: f32, kind: NoiseKind, seed: u64) -> Noise {
+        Noise {
+            perlin: Arc::new(Perlin::new(seed)),
+            scale,
+            kind,
+            color: Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Texture for Noise {
+    fn value(&self, _u: f32, _v: f32, p: &Vec3) -> Vec3 {
+        let scaled = self.scale * p;
+        let value = match self.kind {
+            NoiseKind::Smooth => 0.5 * (1.0 + self.perlin.noise(&scaled)),
+            NoiseKind::Turbulence => self.perlin.turbulence(&scaled, 7),
+            NoiseKind::Marble => {
+                0.5 * (1.0 + (scaled.z + 10.0 * self.perlin.turbulence(p, 7)).sin())
+            }
+        };
+        value * &self.color
+    }
+}
+
+/// How texture coordinates outside `[0, 1]` are brought back into the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl Wrap {
+    fn apply(self, i: i64, size: usize) -> usize {
+        let n = size as i64;
+        let wrapped = match self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Clamp => i.max(0).min(n - 1),
+            Wrap::Mirror => {
+                let period = i.rem_euclid(2 * n);
+                if period < n {
+                    period
+                } else {
+                    2 * n - 1 - period
+                }
+            }
+        };
+        wrapped as usize
+    }
+}
+
+/// Bilinearly filtered image lookup, with `v` running from the bottom of
+/// the image to the top
+#[derive(Clone)]
+pub struct ImageTexture {
+    image: Arc<Framebuffer>,
+    pub wrap: Wrap,
+}
+
+impl ImageTexture {
+    pub fn new(image: Framebuffer, wrap: Wrap) -> ImageTexture {
+        ImageTexture {
+            image: Arc::new(image),
+            wrap,
+        }
+    }
+
+    #[inline(always)]
+    fn texel(&self, x: i64, y: i64) -> &Vec3 {
+        let image = &self.image;
+        image.get(
+            self.wrap.apply(x, image.width),
+            self.wrap.apply(y, image.height),
+        )
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f32, v: f32, _p: &Vec3) -> Vec3 {
+        let image = &self.image;
+        if image.width == 0 || image.height == 0 {
+            return Vec3::new(0.0, 1.0, 1.0);
+        }
+        // Texel centres sit at half-integer coordinates
+        let x = u * image.width as f32 - 0.5;
+        let y = (1.0 - v) * image.height as f32 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = (1.0 - fx) * self.texel(x0, y0) + fx * self.texel(x0 + 1, y0);
+        let bottom = (1.0 - fx) * self.texel(x0, y0 + 1) + fx * self.texel(x0 + 1, y0 + 1);
+        (1.0 - fy) * top + fy * bottom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_texture_wrap_modes() {
+        let mut image = Framebuffer::new(2, 1);
+        image.set(0, 0, Vec3::new(0.0, 0.0, 0.0));
+        image.set(1, 0, Vec3::new(1.0, 1.0, 1.0));
+        let p = Vec3::origin();
+
+        let clamp = ImageTexture::new(image.clone(), Wrap::Clamp);
+        assert_eq!(clamp.value(0.25, 0.5, &p).r(), 0.0);
+        assert_eq!(clamp.value(0.5, 0.5, &p).r(), 0.5);
+        assert_eq!(clamp.value(1.5, 0.5, &p).r(), 1.0);
+
+        let repeat = ImageTexture::new(image.clone(), Wrap::Repeat);
+        assert_eq!(repeat.value(0.0, 0.5, &p).r(), 0.5);
+        assert_eq!(repeat.value(1.25, 0.5, &p).r(), 0.0);
+
+        let mirror = ImageTexture::new(image, Wrap::Mirror);
+        assert_eq!(mirror.value(1.25, 0.5, &p).r(), 1.0);
+    }
+
+    #[test]
+    fn checker_cells_alternate_with_parity() {
+        let checker = Checker::new(
+            Box::new(SolidColor::new(Vec3::new(1.0, 0.0, 0.0))),
+            Box::new(SolidColor::new(Vec3::new(0.0, 1.0, 0.0))),
+            f32::consts::PI,
+        );
+        // Cells are one unit wide, and those whose coordinates sum to an odd
+        // number of steps from the one around (0.5, 0.5, 0.5) are odd
+        for x in -3..3 {
+            for y in -3..3 {
+                for z in -3..3 {
+                    let p = Vec3::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5);
+                    let odd = (x + y + z) % 2 != 0;
+                    assert_eq!(checker.value(0.0, 0.0, &p).r() == 1.0, odd);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn perlin_tables_are_permutations() {
+        let perlin = Perlin::new(7);
+        for perm in &perlin.perm {
+            let mut sorted = perm.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, (0..PERLIN_POINTS).collect::<Vec<_>>());
+        }
+        assert!(perlin.perm[0] != perlin.perm[1]);
+        // The same seed gives the same tables
+        assert_eq!(Perlin::new(7).gradients, perlin.gradients);
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        let perlin = Perlin::new(7);
+        let textures = [
+            Noise::new(4.0, NoiseKind::Smooth, 7),
+            Noise::new(4.0, NoiseKind::Marble, 7),
+        ];
+        let turbulence = Noise::new(4.0, NoiseKind::Turbulence, 7);
+        for i in 0..2000 {
+            let i = i as f32;
+            let p = Vec3::new(
+                (i * 0.37).sin() * 5.0,
+                (i * 0.71).cos() * 5.0,
+                i * 0.013 - 13.0,
+            );
+            assert!(perlin.noise(&p).abs() <= 1.0);
+            for texture in &textures {
+                let value = texture.value(0.0, 0.0, &p).r();
+                assert!((0.0..=1.0).contains(&value));
+            }
+            // Octaves of halving amplitude add up to less than twice the first
+            let value = turbulence.value(0.0, 0.0, &p).r();
+            assert!((0.0..2.0).contains(&value));
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Tone mapping: compresses the linear radiance of a render into the `[0, 1]`
+//! range that 8-bit images can show.
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::render::Framebuffer;
+use super::Vec3;
+
+/// Curves mapping scene radiance to display values, applied per channel
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Operator {
+    /// Cuts everything above 1 off
+    #[default]
+    Clamp,
+    /// `x / (1 + x)`, which never quite reaches white
+    Reinhard,
+    /// Reinhard rescaled so that `white` maps to 1
+    ExtendedReinhard { white: f32 },
+    /// John Hable's Uncharted 2 filmic curve. The curve doubles its input
+    /// first, so `white / 2` is what maps to 1.
+    Hable { white: f32 },
+    /// Stephen Hill's fit of the ACES reference rendering and output
+    /// transforms
+    Aces,
+}
+
+fn hable_partial(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F
+}
+
+fn aces_fitted(col: &Vec3) -> Vec3 {
+    // sRGB => XYZ => D65_2_D60 => AP1 => RRT_SAT
+    const INPUT: [[f32; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    // ODT_SAT => XYZ => D60_2_D65 => sRGB
+    const OUTPUT: [[f32; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let transform = |m: &[[f32; 3]; 3], v: &Vec3| {
+        Vec3::new(
+            m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+            m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+            m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+        )
+    };
+    let v = transform(&INPUT, col);
+    let rrt_and_odt = |x: f32| {
+        let a = x * (x + 0.024_578_6) - 0.000_090_537;
+        let b = x * (0.983_729 * x + 0.432_951) + 0.238_081;
+        a / b
+    };
+    let v = Vec3::new(rrt_and_odt(v[0]), rrt_and_odt(v[1]), rrt_and_odt(v[2]));
+    transform(&OUTPUT, &v)
+}
+
+impl Operator {
+    /// Maps a linear colour to display values in `[0, 1]`
+    pub fn apply(&self, col: &Vec3) -> Vec3 {
+        let col = col.max(&Vec3::origin());
+        let per_channel = |f: &dyn Fn(f32) -> f32| Vec3::new(f(col[0]), f(col[1]), f(col[2]));
+        let mapped = match *self {
+            Operator::Clamp => col,
+            Operator::Reinhard => per_channel(&|x| x / (1.0 + x)),
+            Operator::ExtendedReinhard { white } => {
+                per_channel(&|x| x * (1.0 + x / (white * white)) / (1.0 + x))
+            }
+            Operator::Hable { white } => {
+                // The exposure bias of 2 is part of Hable's published curve
+                let scale = 1.0 / hable_partial(white);
+                per_channel(&|x| hable_partial(2.0 * x) * scale)
+            }
+            Operator::Aces => aces_fitted(&col),
+        };
+        mapped.max(&Vec3::origin()).min(&Vec3::new(1.0, 1.0, 1.0))
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operator::Clamp => write!(f, "clamp"),
+            Operator::Reinhard => write!(f, "reinhard"),
+            Operator::ExtendedReinhard { white } => write!(f, "reinhard-extended:{}", white),
+            Operator::Hable { white } => write!(f, "hable:{}", white),
+            Operator::Aces => write!(f, "aces"),
+        }
+    }
+}
+
+impl FromStr for Operator {
+    type Err = String;
+
+    /// Parses an operator name, optionally followed by `:white` for the
+    /// operators that take a white point, e.g. `reinhard-extended:4`
+    fn from_str(s: &str) -> Result<Operator, String> {
+        let (name, white) = match s.find(':') {
+            Some(colon) => {
+                let white = &s[colon + 1..];
+                match white.parse::<f32>() {
+                    Ok(white) if white > 0.0 => (&s[..colon], Some(white)),
+                    _ => return Err(format!("invalid white point '{}'", white)),
+                }
+            }
+            None => (s, None),
+        };
+        let operator = match name {
+            "clamp" => Operator::Clamp,
+            "reinhard" => Operator::Reinhard,
+            "reinhard-extended" => Operator::ExtendedReinhard {
+                white: white.unwrap_or(4.0),
+            },
+            "hable" | "filmic" => Operator::Hable {
+                white: white.unwrap_or(11.2),
+            },
+            "aces" => Operator::Aces,
+            _ => return Err(format!("unknown tone mapping operator '{}'", name)),
+        };
+        match operator {
+            Operator::ExtendedReinhard { .. } | Operator::Hable { .. } => {}
+            _ if white.is_some() => return Err(format!("'{}' does not take a white point", name)),
+            _ => {}
+        }
+        Ok(operator)
+    }
+}
+
+/// Exposure followed by a tone mapping operator
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ToneMap {
+    pub operator: Operator,
+    /// Exposure compensation in stops; each step doubles the brightness
+    pub exposure: f32,
+}
+
+impl ToneMap {
+    pub fn new(operator: Operator, exposure: f32) -> ToneMap {
+        ToneMap { operator, exposure }
+    }
+
+    #[inline(always)]
+    pub fn map(&self, col: &Vec3) -> Vec3 {
+        self.operator.apply(&(col * self.exposure.exp2()))
+    }
+
+    /// A copy of `image` with every pixel mapped to display values
+    pub fn apply(&self, image: &Framebuffer) -> Framebuffer {
+        Framebuffer {
+            width: image.width,
+            height: image.height,
+            pixels: image.pixels.iter().map(|col| self.map(col)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [&str; 5] = ["clamp", "reinhard", "reinhard-extended:4", "hable", "aces"];
+
+    #[test]
+    fn operators_are_monotonic_and_bounded() {
+        for name in OPERATORS.iter() {
+            let operator = name.parse::<Operator>().unwrap();
+            assert!(operator.apply(&Vec3::origin()).length() < 1e-6, "{}", name);
+            let mut previous = 0.0;
+            for i in 1..200 {
+                let x = i as f32 * 0.1;
+                let y = operator.apply(&Vec3::new(x, x, x)).g();
+                assert!(y >= previous && y <= 1.0, "{} at {}", name, x);
+                previous = y;
+            }
+        }
+    }
+
+    #[test]
+    fn white_points_and_exposure() {
+        let white = Vec3::new(4.0, 4.0, 4.0);
+        let extended = Operator::ExtendedReinhard { white: 4.0 };
+        assert!((extended.apply(&white).r() - 1.0).abs() < 1e-6);
+        let hable = Operator::Hable { white: 11.2 };
+        assert!((hable.apply(&Vec3::new(5.6, 5.6, 5.6)).r() - 1.0).abs() < 1e-5);
+        assert!(hable.apply(&Vec3::new(5.0, 5.0, 5.0)).r() < 0.999);
+
+        // One stop up doubles the input
+        let tone = ToneMap::new(Operator::Reinhard, 1.0);
+        assert_eq!(
+            tone.map(&Vec3::new(0.5, 0.5, 0.5)),
+            Vec3::new(0.5, 0.5, 0.5)
+        );
+
+        assert_eq!(hable.to_string().parse::<Operator>(), Ok(hable));
+        assert!("aces:2".parse::<Operator>().is_err());
+        assert!("reinhard-extended:-1".parse::<Operator>().is_err());
+    }
+}
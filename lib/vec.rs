@@ -1,4 +1,4 @@
-use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign};
+use std::ops::{Add, AddAssign, Div, DivAssign, Index, Mul, MulAssign, Neg, Sub, SubAssign};
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Vec3 {
@@ -19,7 +19,7 @@ impl Vec3 {
 
     #[inline(always)]
     pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
-        Vec3 { x: x, y: y, z: z }
+        Vec3 { x, y, z }
     }
 
     pub fn x(&self) -> f32 {
@@ -48,6 +48,29 @@ impl Vec3 {
     pub fn squared_length(&self) -> f32 {
         (self.x() * self.x()) + (self.y() * self.y()) + (self.z() * self.z())
     }
+    /// Component-wise minimum of two vectors
+    #[inline(always)]
+    pub fn min(&self, other: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.x.min(other.x),
+            self.y.min(other.y),
+            self.z.min(other.z),
+        )
+    }
+    /// Component-wise maximum of two vectors
+    #[inline(always)]
+    pub fn max(&self, other: &Vec3) -> Vec3 {
+        Vec3::new(
+            self.x.max(other.x),
+            self.y.max(other.y),
+            self.z.max(other.z),
+        )
+    }
+    /// Brightness of a linear Rec. 709 colour
+    #[inline(always)]
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
 }
 
 impl Add for Vec3 {
@@ -55,9 +78,9 @@ impl Add for Vec3 {
     #[inline(always)]
     fn add(self, other: Vec3) -> Vec3 {
         Vec3 {
-            x: &self.x + other.x,
-            y: &self.y + other.y,
-            z: &self.z + other.z,
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
         }
     }
 }
@@ -95,7 +118,7 @@ impl Sub for Vec3 {
     }
 }
 
-impl<'a> Sub for &'a Vec3 {
+impl Sub for &Vec3 {
     type Output = Vec3;
     #[inline(always)]
     fn sub(self, other: &Vec3) -> Vec3 {
@@ -173,7 +196,7 @@ impl Div<f32> for Vec3 {
     }
 }
 
-impl<'a> Div<f32> for &'a Vec3 {
+impl Div<f32> for &Vec3 {
     type Output = Vec3;
     #[inline(always)]
     fn div(self, other: f32) -> Vec3 {
@@ -239,7 +262,7 @@ impl Mul<f32> for Vec3 {
     }
 }
 
-impl<'a> Mul<f32> for &'a Vec3 {
+impl Mul<f32> for &Vec3 {
     type Output = Vec3;
     #[inline(always)]
     fn mul(self, other: f32) -> Vec3 {
@@ -256,21 +279,21 @@ impl Mul<Vec3> for f32 {
     #[inline(always)]
     fn mul(self, other: Vec3) -> Vec3 {
         Vec3 {
-            x: &self * other.x,
-            y: &self * other.y,
-            z: &self * other.z,
+            x: self * other.x,
+            y: self * other.y,
+            z: self * other.z,
         }
     }
 }
 
-impl<'a> Mul<&'a Vec3> for f32 {
+impl Mul<&Vec3> for f32 {
     type Output = Vec3;
     #[inline(always)]
     fn mul(self, other: &Vec3) -> Vec3 {
         Vec3 {
-            x: &self * other.x,
-            y: &self * other.y,
-            z: &self * other.z,
+            x: self * other.x,
+            y: self * other.y,
+            z: self * other.z,
         }
     }
 }
@@ -283,3 +306,322 @@ impl MulAssign<f32> for Vec3 {
         self.z *= other;
     }
 }
+
+impl Index<usize> for Vec3 {
+    type Output = f32;
+    #[inline(always)]
+    fn index(&self, axis: usize) -> &f32 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 axis out of range: {}", axis),
+        }
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+    #[inline(always)]
+    fn neg(self) -> Vec3 {
+        Vec3 {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+}
+
+impl Neg for &Vec3 {
+    type Output = Vec3;
+    #[inline(always)]
+    fn neg(self) -> Vec3 {
+        Vec3 {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+}
+
+/// Row-major 4x4 matrix acting on column vectors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Matrix4 {
+    pub m: [[f32; 4]; 4],
+}
+
+impl Matrix4 {
+    pub fn new(m: [[f32; 4]; 4]) -> Matrix4 {
+        Matrix4 { m }
+    }
+
+    pub fn identity() -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        Matrix4 { m }
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.m[j][i];
+            }
+        }
+        Matrix4 { m }
+    }
+
+    /// Inverse by Gauss-Jordan elimination with partial pivoting, or `None`
+    /// if the matrix is singular
+    pub fn inverse(&self) -> Option<Matrix4> {
+        // Elimination runs in f64 so that chains of transforms stay accurate
+        let mut a = self.m.map(|row| row.map(|value| value as f64));
+        let mut inv = [[0f64; 4]; 4];
+        for (i, row) in inv.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        for col in 0..4 {
+            let pivot = (col..4)
+                .max_by(|&r, &s| a[r][col].abs().total_cmp(&a[s][col].abs()))
+                .unwrap();
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+            let scale = 1.0 / a[col][col];
+            for j in 0..4 {
+                a[col][j] *= scale;
+                inv[col][j] *= scale;
+            }
+            for row in 0..4 {
+                if row != col {
+                    let factor = a[row][col];
+                    for j in 0..4 {
+                        a[row][j] -= factor * a[col][j];
+                        inv[row][j] -= factor * inv[col][j];
+                    }
+                }
+            }
+        }
+        Some(Matrix4 {
+            m: inv.map(|row| row.map(|value| value as f32)),
+        })
+    }
+}
+
+impl<'a> Mul<&'a Matrix4> for &'a Matrix4 {
+    type Output = Matrix4;
+    fn mul(self, other: &Matrix4) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[i][k] * other.m[k][j]).sum();
+            }
+        }
+        Matrix4 { m }
+    }
+}
+
+/// Affine transform, kept together with its inverse. `a * b` applies `b`
+/// first, then `a`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub matrix: Matrix4,
+    pub inverse: Matrix4,
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform::identity()
+    }
+}
+
+impl Transform {
+    /// A transform from `matrix`, or `None` if it cannot be inverted
+    pub fn new(matrix: Matrix4) -> Option<Transform> {
+        matrix
+            .inverse()
+            .map(|inverse| Transform { matrix, inverse })
+    }
+
+    pub fn identity() -> Transform {
+        Transform {
+            matrix: Matrix4::identity(),
+            inverse: Matrix4::identity(),
+        }
+    }
+
+    pub fn translate(delta: &Vec3) -> Transform {
+        let matrix = Matrix4::new([
+            [1.0, 0.0, 0.0, delta.x],
+            [0.0, 1.0, 0.0, delta.y],
+            [0.0, 0.0, 1.0, delta.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let inverse = Matrix4::new([
+            [1.0, 0.0, 0.0, -delta.x],
+            [0.0, 1.0, 0.0, -delta.y],
+            [0.0, 0.0, 1.0, -delta.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        Transform { matrix, inverse }
+    }
+
+    /// Scales each axis by the matching component of `factors`, which must
+    /// all be non-zero
+    pub fn scale(factors: &Vec3) -> Transform {
+        let matrix = Matrix4::new([
+            [factors.x, 0.0, 0.0, 0.0],
+            [0.0, factors.y, 0.0, 0.0],
+            [0.0, 0.0, factors.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let inverse = Matrix4::new([
+            [1.0 / factors.x, 0.0, 0.0, 0.0],
+            [0.0, 1.0 / factors.y, 0.0, 0.0],
+            [0.0, 0.0, 1.0 / factors.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        Transform { matrix, inverse }
+    }
+
+    /// Rotates by `degrees` counter-clockwise about `axis`, looking down the
+    /// axis towards the origin
+    pub fn rotate(degrees: f32, axis: &Vec3) -> Transform {
+        let a = axis / axis.length();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let t = 1.0 - cos;
+        let matrix = Matrix4::new([
+            [
+                t * a.x * a.x + cos,
+                t * a.x * a.y - sin * a.z,
+                t * a.x * a.z + sin * a.y,
+                0.0,
+            ],
+            [
+                t * a.x * a.y + sin * a.z,
+                t * a.y * a.y + cos,
+                t * a.y * a.z - sin * a.x,
+                0.0,
+            ],
+            [
+                t * a.x * a.z - sin * a.y,
+                t * a.y * a.z + sin * a.x,
+                t * a.z * a.z + cos,
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        // Rotations are orthogonal
+        Transform {
+            matrix,
+            inverse: matrix.transpose(),
+        }
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    #[inline(always)]
+    pub fn point(&self, p: &Vec3) -> Vec3 {
+        let m = &self.matrix.m;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        if w == 1.0 {
+            Vec3::new(x, y, z)
+        } else {
+            Vec3::new(x / w, y / w, z / w)
+        }
+    }
+
+    /// Transforms a direction, which unlike a point is not translated
+    #[inline(always)]
+    pub fn vector(&self, v: &Vec3) -> Vec3 {
+        let m = &self.matrix.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    /// Transforms a surface normal with the inverse transpose, which keeps it
+    /// perpendicular to the transformed surface. The result is not normalised.
+    #[inline(always)]
+    pub fn normal(&self, n: &Vec3) -> Vec3 {
+        let m = &self.inverse.m;
+        Vec3::new(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        )
+    }
+}
+
+impl Mul for Transform {
+    type Output = Transform;
+    fn mul(self, other: Transform) -> Transform {
+        Transform {
+            matrix: &self.matrix * &other.matrix,
+            inverse: &other.inverse * &self.inverse,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Vec3, b: &Vec3) {
+        assert!((a - b).length() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn transforms_compose_and_invert() {
+        let t = Transform::translate(&Vec3::new(1.0, 2.0, 3.0))
+            * Transform::rotate(90.0, &Vec3::new(0.0, 0.0, 1.0))
+            * Transform::scale(&Vec3::new(2.0, 1.0, 1.0));
+        let p = Vec3::new(1.0, 1.0, 0.0);
+        // Scaled to (2, 1, 0), rotated to (-1, 2, 0), then translated
+        assert_close(&t.point(&p), &Vec3::new(0.0, 4.0, 3.0));
+        assert_close(&t.inverse().point(&t.point(&p)), &p);
+        assert_close(
+            &t.vector(&Vec3::new(1.0, 0.0, 0.0)),
+            &Vec3::new(0.0, 2.0, 0.0),
+        );
+
+        let general = Matrix4::new([
+            [2.0, 0.5, 0.0, 1.0],
+            [0.0, 1.0, 3.0, -2.0],
+            [1.0, 0.0, 1.0, 0.5],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let product = &general * &general.inverse().unwrap();
+        for i in 0..4 {
+            for j in 0..4 {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((product.m[i][j] - expected).abs() < 1e-5);
+            }
+        }
+        assert_eq!(Matrix4::new([[0.0; 4]; 4]).inverse(), None);
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scale() {
+        let t = Transform::scale(&Vec3::new(4.0, 1.0, 1.0));
+        // The surface x + y = 0 has normal (1, 1, 0) and contains (1, -1, 0)
+        let tangent = t.vector(&Vec3::new(1.0, -1.0, 0.0));
+        let normal = t.normal(&Vec3::new(1.0, 1.0, 0.0));
+        let dot = tangent.x * normal.x + tangent.y * normal.y + tangent.z * normal.z;
+        assert!(dot.abs() < 1e-6);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use tracer::filter::Filter;
+use tracer::render::{Heuristic, Integrator};
+use tracer::sampler::SamplerKind;
+use tracer::tonemap::Operator;
+
+pub const USAGE: &str = "\
+Usage: raytracer-weekend [OPTIONS] [SCENE]
+
+Renders SCENE, a scene description file, or the random spheres demo scene
+when no file is given. Without -o the image is written to stdout as binary
+PPM. Output files may be .png or .ppm, or .hdr or .pfm to keep the
+unclamped linear radiance.
+
+Options:
+  -w, --width <PIXELS>     Image width, overriding the scene file
+  -h, --height <PIXELS>    Image height, overriding the scene file
+      --spp <COUNT>        Samples per pixel
+      --sampler <KIND>     independent, stratified, halton or sobol
+      --filter <FILTER>    box, triangle, gaussian, mitchell or lanczos,
+                           with an optional :RADIUS in pixels
+      --roulette-depth <COUNT>
+                           Bounces before Russian roulette may end a path
+      --max-depth <COUNT>  Maximum number of bounces per path
+      --mis <HEURISTIC>    balance or power, to weigh light and BSDF samples
+      --integrator <KIND>  rgb, or spectral to show dispersion
+      --seed <NUMBER>      Seed for the random number streams
+      --exposure <STOPS>   Exposure compensation for 8-bit output
+      --tonemap <OPERATOR> clamp, reinhard, reinhard-extended[:WHITE],
+                           hable[:WHITE] or aces
+      --threads <COUNT>    Worker threads (default: one per core)
+  -o, --output <FILE>      Output file; its extension picks the format
+      --help               Print this message and exit";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageError(pub String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Options given on the command line. Unset values fall back to the scene.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    pub scene: Option<PathBuf>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub samples: Option<usize>,
+    pub sampler: Option<SamplerKind>,
+    pub filter: Option<Filter>,
+    pub roulette_depth: Option<i32>,
+    pub max_depth: Option<i32>,
+    pub heuristic: Option<Heuristic>,
+    pub integrator: Option<Integrator>,
+    pub seed: Option<u64>,
+    pub exposure: Option<f32>,
+    pub tonemap: Option<Operator>,
+    pub threads: Option<usize>,
+    pub output: Option<PathBuf>,
+    pub help: bool,
+}
+
+fn value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, UsageError> {
+    let value = value.ok_or_else(|| UsageError(format!("{} needs a value", flag)))?;
+    value
+        .parse::<T>()
+        .map_err(|_| UsageError(format!("invalid value '{}' for {}", value, flag)))
+}
+
+fn positive(flag: &str, value: usize) -> Result<usize, UsageError> {
+    if value == 0 {
+        return Err(UsageError(format!("{} must be at least 1", flag)));
+    }
+    Ok(value)
+}
+
+fn non_negative(flag: &str, value: i32) -> Result<i32, UsageError> {
+    if value < 0 {
+        return Err(UsageError(format!("{} must not be negative", flag)));
+    }
+    Ok(value)
+}
+
+impl Options {
+    /// Parses the arguments that follow the program name
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, UsageError> {
+        let mut options = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Accept both "--flag value" and "--flag=value"
+            let (flag, inline) = match arg.find('=') {
+                Some(eq) if arg.starts_with("--") => {
+                    (arg[..eq].to_string(), Some(arg[eq + 1..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut next = || inline.clone().or_else(|| args.next());
+            match flag.as_str() {
+                "--help" => options.help = true,
+                "-w" | "--width" => options.width = Some(positive(&flag, value(&flag, next())?)?),
+                "-h" | "--height" => options.height = Some(positive(&flag, value(&flag, next())?)?),
+                "--spp" => options.samples = Some(positive(&flag, value(&flag, next())?)?),
+                "--sampler" => {
+                    let name = value::<String>(&flag, next())?;
+                    options.sampler = Some(name.parse().map_err(UsageError)?)
+                }
+                "--filter" => {
+                    let name = value::<String>(&flag, next())?;
+                    options.filter = Some(name.parse().map_err(UsageError)?)
+                }
+                "--roulette-depth" => {
+                    options.roulette_depth = Some(non_negative(&flag, value(&flag, next())?)?)
+                }
+                "--max-depth" => {
+                    options.max_depth = Some(non_negative(&flag, value(&flag, next())?)?)
+                }
+                "--mis" => {
+                    let name = value::<String>(&flag, next())?;
+                    options.heuristic = Some(name.parse().map_err(UsageError)?)
+                }
+                "--integrator" => {
+                    let name = value::<String>(&flag, next())?;
+                    options.integrator = Some(name.parse().map_err(UsageError)?)
+                }
+                "--seed" => options.seed = Some(value(&flag, next())?),
+                "--exposure" => options.exposure = Some(value(&flag, next())?),
+                "--tonemap" => {
+                    let name = value::<String>(&flag, next())?;
+                    options.tonemap = Some(name.parse().map_err(UsageError)?)
+                }
+                "--threads" => options.threads = Some(positive(&flag, value(&flag, next())?)?),
+                "-o" | "--output" => {
+                    options.output = Some(PathBuf::from(value::<String>(&flag, next())?))
+                }
+                _ if flag.starts_with('-') && flag.len() > 1 => {
+                    return Err(UsageError(format!("unknown option '{}'", flag)))
+                }
+                _ => {
+                    if options.scene.is_some() {
+                        return Err(UsageError(format!("unexpected argument '{}'", arg)));
+                    }
+                    options.scene = Some(PathBuf::from(arg));
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, UsageError> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parse_flags() {
+        let options = parse(&[
+            "scene.txt",
+            "-w",
+            "640",
+            "-h",
+            "480",
+            "--spp",
+            "32",
+            "--sampler=sobol",
+            "--mis",
+            "balance",
+            "--roulette-depth=2",
+            "--integrator=spectral",
+            "--seed=7",
+            "--tonemap=reinhard-extended:2",
+            "--exposure",
+            "-0.5",
+            "-o",
+            "out.ppm",
+        ])
+        .unwrap();
+        assert_eq!(options.scene, Some(PathBuf::from("scene.txt")));
+        assert_eq!(options.width, Some(640));
+        assert_eq!(options.height, Some(480));
+        assert_eq!(options.samples, Some(32));
+        assert_eq!(options.sampler, Some(SamplerKind::Sobol));
+        assert_eq!(options.heuristic, Some(Heuristic::Balance));
+        assert_eq!(options.roulette_depth, Some(2));
+        assert_eq!(options.integrator, Some(Integrator::Spectral));
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(
+            options.tonemap,
+            Some(Operator::ExtendedReinhard { white: 2.0 })
+        );
+        assert_eq!(options.exposure, Some(-0.5));
+        assert_eq!(options.output, Some(PathBuf::from("out.ppm")));
+        // Only long options take the "=value" form
+        assert!(parse(&["-h=480"]).is_err());
+    }
+
+    #[test]
+    fn reject_bad_values() {
+        assert_eq!(
+            parse(&["--spp", "lots"]),
+            Err(UsageError("invalid value 'lots' for --spp".to_string()))
+        );
+        assert_eq!(
+            parse(&["--threads", "0"]),
+            Err(UsageError("--threads must be at least 1".to_string()))
+        );
+        assert_eq!(
+            parse(&["--max-depth", "-1"]),
+            Err(UsageError("--max-depth must not be negative".to_string()))
+        );
+        assert_eq!(
+            parse(&["--roulette-depth=-2"]),
+            Err(UsageError(
+                "--roulette-depth must not be negative".to_string()
+            ))
+        );
+        assert_eq!(
+            parse(&["-o"]),
+            Err(UsageError("-o needs a value".to_string()))
+        );
+        assert!(parse(&["--frobnicate"]).is_err());
+        assert_eq!(
+            parse(&["--filter", "gaussian:-1"]),
+            Err(UsageError("invalid filter radius '-1'".to_string()))
+        );
+    }
+}
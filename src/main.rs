@@ -1,64 +1,49 @@
-extern crate rand;
 extern crate tracer;
 
-use tracer::{unit_vector, Hit, HitList, HitRecord, Ray, Sphere, RAND_END};
-use tracer::vec::Vec3;
-use tracer::materials::*;
-use tracer::camera::*;
-use std::f32;
-use rand::{thread_rng, Rng};
+mod cli;
 
-fn color<T: Hit>(ray: &Ray, world_hit_record: &T, depth: i32) -> Vec3 {
-    let mut hit_record = HitRecord::new();
-    if world_hit_record.hit(ray, 0.001, f32::MAX, &mut hit_record) {
-        let mut scattered = Ray::new(Vec3::new(0f32, 0f32, 0f32), Vec3::new(0f32, 0f32, 0f32));
-        let mut attenuation = Vec3::new(0f32, 0f32, 0f32);
-        if depth < 50
-            && hit_record.mat.clone().unwrap().scatter(
-                ray,
-                &hit_record,
-                &mut attenuation,
-                &mut scattered,
-            ) {
-            return attenuation * color::<T>(&scattered, world_hit_record, depth + 1);
-        } else {
-            return Vec3::new(0f32, 0f32, 0f32);
-        }
-    } else {
-        let unit_direction = unit_vector(&ray.direction());
-        let t: f32 = 0.5 * (unit_direction.y() + 1.0);
-        return (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0);
-    }
-}
+use std::env;
+use std::io;
+use std::process;
 
-fn main() {
-    let nx: i32 = 2000;
-    let ny: i32 = 1000;
-    let ns: i32 = 100;
-    let mut rng = thread_rng();
+use cli::{Options, USAGE};
+use tracer::bvh::Bvh;
+use tracer::camera::*;
+use tracer::filter::Filter;
+use tracer::image::{save, write_ppm, ImageFormat};
+use tracer::materials::*;
+use tracer::parser::{load_scene, SceneDescription};
+use tracer::render::{Heuristic, Integrator, Renderer};
+use tracer::sampler::{IndependentSampler, Sampler, SamplerKind};
+use tracer::scene::{Background, Scene};
+use tracer::tonemap::ToneMap;
+use tracer::vec::Vec3;
+use tracer::{HitList, Sphere};
 
-    let look_from = Vec3::new(13.0, 2.0, 3.0);
-    let look_at = Vec3::new(0.0, 0.0, 0.0);
-    let dist_to_focus = 10.0;
+/// The final scene of the book: a field of small random spheres around three
+/// large ones
+fn random_scene() -> SceneDescription {
+    let mut sampler = IndependentSampler::new(0);
 
-    let camera = Camera::new(
-        look_from,
-        look_at,
-        Vec3::new(0.0, 1.0, 0.0),
-        20.0,
-        (nx / ny) as f32,
-        0.1,
-        dist_to_focus,
-    );
+    let camera = CameraSettings {
+        look_from: Vec3::new(13.0, 2.0, 3.0),
+        look_at: Vec3::new(0.0, 0.0, 0.0),
+        vup: Vec3::new(0.0, 1.0, 0.0),
+        vfov: 20.0,
+        aperture: 0.1,
+        focus: 10.0,
+        shutter_open: 0.0,
+        shutter_close: 0.0,
+    };
 
     let mut hit_list = HitList::new(0);
     for i in (-11..11).rev() {
         for j in (-11..11).rev() {
-            let mat_choice = rng.gen_range::<f32>(0.0, RAND_END);
+            let mat_choice = sampler.get_1d();
             let center = Vec3::new(
-                i as f32 + 0.9 + rng.gen_range::<f32>(0.0, RAND_END),
+                i as f32 + 0.9 + sampler.get_1d(),
                 0.2,
-                j as f32 + 0.9 + rng.gen_range::<f32>(0.0, RAND_END),
+                j as f32 + 0.9 + sampler.get_1d(),
             );
             if (center.clone() - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
                 if mat_choice < 0.8 {
@@ -66,12 +51,9 @@ fn main() {
                         center.clone(),
                         0.2,
                         Box::new(Lambertian::new(Vec3::new(
-                            rng.gen_range::<f32>(0.0, RAND_END)
-                                * rng.gen_range::<f32>(0.0, RAND_END),
-                            rng.gen_range::<f32>(0.0, RAND_END)
-                                * rng.gen_range::<f32>(0.0, RAND_END),
-                            rng.gen_range::<f32>(0.0, RAND_END)
-                                * rng.gen_range::<f32>(0.0, RAND_END),
+                            sampler.get_1d() * sampler.get_1d(),
+                            sampler.get_1d() * sampler.get_1d(),
+                            sampler.get_1d() * sampler.get_1d(),
                         ))),
                     ));
                 } else if mat_choice < 0.95 {
@@ -80,11 +62,11 @@ fn main() {
                         0.2,
                         Box::new(Metal::new(
                             Vec3::new(
-                                0.5 * (1.0 + rng.gen_range::<f32>(0.0, RAND_END)),
-                                0.5 * (1.0 + rng.gen_range::<f32>(0.0, RAND_END)),
-                                0.5 * (1.0 + rng.gen_range::<f32>(0.0, RAND_END)),
+                                0.5 * (1.0 + sampler.get_1d()),
+                                0.5 * (1.0 + sampler.get_1d()),
+                                0.5 * (1.0 + sampler.get_1d()),
                             ),
-                            0.5 * rng.gen_range::<f32>(0.0, RAND_END),
+                            0.5 * sampler.get_1d(),
                         )),
                     ));
                 } else {
@@ -118,21 +100,78 @@ fn main() {
         Box::new(Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0)),
     ));
 
-    println!("P3\n{} {}\n255\n", nx, ny);
-    for j in (0..ny).rev() {
-        for i in 0..nx {
-            let mut col = Vec3::new(0f32, 0f32, 0f32);
-            for _ in 0..ns {
-                let u: f32 = (i as f32 + rng.gen_range::<f32>(0.0, RAND_END)) / nx as f32;
-                let v: f32 = (j as f32 + rng.gen_range::<f32>(0.0, RAND_END)) / ny as f32;
-                let ray = camera.ray(u, v);
-                col += color(&ray, &hit_list, 0);
-            }
-            col /= ns as f32;
-            let ir: i32 = (255.99 * col.r().sqrt()) as i32;
-            let ig: i32 = (255.99 * col.g().sqrt()) as i32;
-            let ib: i32 = (255.99 * col.b().sqrt()) as i32;
-            println!("{} {} {}\n", ir, ig, ib);
+    SceneDescription {
+        width: 2000,
+        height: 1000,
+        samples: 100,
+        sampler: SamplerKind::default(),
+        filter: Filter::default(),
+        roulette_depth: 3,
+        max_depth: 50,
+        heuristic: Heuristic::default(),
+        integrator: Integrator::default(),
+        tone_map: ToneMap::default(),
+        camera,
+        scene: Scene::new(Box::new(Bvh::new(hit_list.list)), Background::sky()),
+    }
+}
+
+fn run(options: Options) -> Result<(), String> {
+    // Catch a bad output name before spending time on the render
+    if let Some(ref path) = options.output {
+        ImageFormat::from_path(path).map_err(|err| err.to_string())?;
+    }
+    let mut description = match options.scene {
+        Some(ref path) => load_scene(path).map_err(|err| match err.line {
+            0 => err.to_string(),
+            _ => format!("{}:{}", path.display(), err),
+        })?,
+        None => random_scene(),
+    };
+    description.width = options.width.unwrap_or(description.width);
+    description.height = options.height.unwrap_or(description.height);
+
+    let mut renderer = Renderer::new(
+        description.width,
+        description.height,
+        options.samples.unwrap_or(description.samples),
+    );
+    renderer.sampler = options.sampler.unwrap_or(description.sampler);
+    renderer.filter = options.filter.unwrap_or(description.filter);
+    renderer.roulette_depth = options.roulette_depth.unwrap_or(description.roulette_depth);
+    renderer.max_depth = options.max_depth.unwrap_or(description.max_depth);
+    renderer.heuristic = options.heuristic.unwrap_or(description.heuristic);
+    renderer.integrator = options.integrator.unwrap_or(description.integrator);
+    renderer.seed = options.seed.unwrap_or(renderer.seed);
+    renderer.threads = options.threads.unwrap_or(renderer.threads);
+
+    let mut tone_map = description.tone_map;
+    tone_map.operator = options.tonemap.unwrap_or(tone_map.operator);
+    tone_map.exposure = options.exposure.unwrap_or(tone_map.exposure);
+
+    let framebuffer = renderer.render(&description.camera(), &description.scene);
+    match options.output {
+        Some(ref path) => save(path, &framebuffer, &tone_map)
+            .map_err(|err| format!("{}: {}", path.display(), err)),
+        None => write_ppm(io::stdout().lock(), &tone_map.apply(&framebuffer))
+            .map_err(|err| err.to_string()),
+    }
+}
+
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\nRun with --help for usage.", err);
+            process::exit(2);
         }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+    if let Err(err) = run(options) {
+        eprintln!("error: {}", err);
+        process::exit(1);
     }
 }